@@ -11,6 +11,8 @@ WITH replication = {'class': 'NetworkTopologyStrategy', 'datacenter1': 3};
 CREATE TABLE IF NOT EXISTS twitter_clone.users (
     user_id uuid PRIMARY KEY,
     username text,
+    display_name text,
+    pinned_tweet_id uuid,
     email text,
     password_hash text,
     created_at timestamp,
@@ -20,14 +22,77 @@ CREATE TABLE IF NOT EXISTS twitter_clone.users (
 CREATE INDEX IF NOT EXISTS users_username_idx ON twitter_clone.users (username);
 CREATE INDEX IF NOT EXISTS users_email_idx ON twitter_clone.users (email);
 
+-- Claims each username, case-insensitively, and resolves @mentions
+CREATE TABLE IF NOT EXISTS twitter_clone.users_by_username (
+    username_lower text PRIMARY KEY,
+    user_id uuid
+);
+
+-- For keyspaces created before display names were stored
+-- ALTER TABLE twitter_clone.users ADD display_name text;
+-- For keyspaces created before pinned tweets
+-- ALTER TABLE twitter_clone.users ADD pinned_tweet_id uuid;
+
+CREATE TABLE IF NOT EXISTS twitter_clone.user_stats (
+    user_id uuid PRIMARY KEY,
+    follower_count counter
+);
+
 CREATE TABLE IF NOT EXISTS twitter_clone.tweets (
     tweet_id uuid,
     user_id uuid,
     content text,
     created_at timestamp,
+    mentions map<text, uuid>,
+    media text,
+    poll text,
+    in_reply_to_tweet_id uuid,
     PRIMARY KEY (tweet_id)
 );
 
+-- For keyspaces created before mentions were stored
+-- ALTER TABLE twitter_clone.tweets ADD mentions map<text, uuid>;
+-- For keyspaces created before media attachments (JSON list of media metadata)
+-- ALTER TABLE twitter_clone.tweets ADD media text;
+-- For keyspaces created before polls (JSON poll definition)
+-- ALTER TABLE twitter_clone.tweets ADD poll text;
+-- For keyspaces created before replies
+-- ALTER TABLE twitter_clone.tweets ADD in_reply_to_tweet_id uuid;
+
+-- Replies to each tweet, newest first
+CREATE TABLE IF NOT EXISTS twitter_clone.replies_by_tweet (
+    tweet_id uuid,
+    created_at timestamp,
+    reply_id uuid,
+    PRIMARY KEY (tweet_id, created_at, reply_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.media (
+    media_id uuid PRIMARY KEY,
+    user_id uuid,
+    content_hash text,
+    content_type text,
+    size_bytes bigint,
+    width int,
+    height int,
+    alt_text text,
+    blurhash text,
+    variants text,
+    created_at timestamp
+);
+
+-- Processed files by SHA-256 of the upload, shared by identical uploads
+CREATE TABLE IF NOT EXISTS twitter_clone.media_blobs (
+    content_hash text PRIMARY KEY,
+    content_type text,
+    size_bytes bigint,
+    width int,
+    height int,
+    blurhash text,
+    variants text,
+    created_at timestamp
+);
+
 CREATE TABLE IF NOT EXISTS twitter_clone.user_timeline (
     user_id uuid,
     tweet_id uuid,
@@ -40,4 +105,253 @@ CREATE TABLE IF NOT EXISTS twitter_clone.likes (
     user_id uuid,
     created_at timestamp,
     PRIMARY KEY (tweet_id, user_id)
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.retweets (
+    tweet_id uuid,
+    user_id uuid,
+    created_at timestamp,
+    PRIMARY KEY (tweet_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.tweets_by_hashtag (
+    hashtag text,
+    bucket int,
+    created_at timestamp,
+    tweet_id uuid,
+    user_id uuid,
+    PRIMARY KEY ((hashtag, bucket), created_at, tweet_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.mentions_by_user (
+    user_id uuid,
+    created_at timestamp,
+    tweet_id uuid,
+    author_id uuid,
+    PRIMARY KEY (user_id, created_at, tweet_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+-- Bookmarks, newest first for each user
+CREATE TABLE IF NOT EXISTS twitter_clone.bookmarks_by_user (
+    user_id uuid,
+    created_at timestamp,
+    tweet_id uuid,
+    PRIMARY KEY (user_id, created_at, tweet_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+-- Who bookmarked each tweet, for removing bookmarks and cleaning up deleted tweets
+CREATE TABLE IF NOT EXISTS twitter_clone.bookmarks_by_tweet (
+    tweet_id uuid,
+    user_id uuid,
+    created_at timestamp,
+    PRIMARY KEY (tweet_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.notifications (
+    user_id uuid,
+    created_at timestamp,
+    notification_id uuid,
+    kind text,
+    actor_id uuid,
+    tweet_id uuid,
+    PRIMARY KEY (user_id, created_at, notification_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+-- Notifications about each tweet, for deleting them with it
+CREATE TABLE IF NOT EXISTS twitter_clone.notifications_by_tweet (
+    tweet_id uuid,
+    user_id uuid,
+    created_at timestamp,
+    notification_id uuid,
+    PRIMARY KEY (tweet_id, user_id, created_at, notification_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.notification_read_markers (
+    user_id uuid PRIMARY KEY,
+    last_read_at timestamp
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.followers (
+    user_id uuid,
+    follower_id uuid,
+    created_at timestamp,
+    PRIMARY KEY (user_id, follower_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.following (
+    user_id uuid,
+    followed_id uuid,
+    created_at timestamp,
+    PRIMARY KEY (user_id, followed_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.dm_settings (
+    user_id uuid PRIMARY KEY,
+    allow_from text
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.conversations (
+    conversation_id uuid PRIMARY KEY,
+    participant_ids set<uuid>,
+    is_group boolean,
+    created_by uuid,
+    created_at timestamp
+);
+
+-- One-to-one conversations, keyed by the two participants in ascending order
+CREATE TABLE IF NOT EXISTS twitter_clone.direct_conversations (
+    user_a uuid,
+    user_b uuid,
+    conversation_id uuid,
+    PRIMARY KEY ((user_a, user_b))
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.user_conversations (
+    user_id uuid,
+    conversation_id uuid,
+    joined_at timestamp,
+    PRIMARY KEY (user_id, conversation_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.direct_messages (
+    conversation_id uuid,
+    created_at timestamp,
+    message_id uuid,
+    sender_id uuid,
+    content text,
+    PRIMARY KEY (conversation_id, created_at, message_id)
+) WITH CLUSTERING ORDER BY (created_at DESC);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.conversation_read_markers (
+    conversation_id uuid,
+    user_id uuid,
+    last_read_at timestamp,
+    PRIMARY KEY (conversation_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.lists (
+    list_id uuid PRIMARY KEY,
+    owner_id uuid,
+    name text,
+    description text,
+    private boolean,
+    created_at timestamp
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.lists_by_owner (
+    owner_id uuid,
+    list_id uuid,
+    PRIMARY KEY (owner_id, list_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.list_members (
+    list_id uuid,
+    member_id uuid,
+    added_at timestamp,
+    PRIMARY KEY (list_id, member_id)
+);
+
+-- Subscriptions, stored both ways: by user for listing them, by list for
+-- cleaning up when the list is deleted
+CREATE TABLE IF NOT EXISTS twitter_clone.list_subscriptions (
+    user_id uuid,
+    list_id uuid,
+    PRIMARY KEY (user_id, list_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.list_subscribers (
+    list_id uuid,
+    user_id uuid,
+    PRIMARY KEY (list_id, user_id)
+);
+
+-- One row per voter, written conditionally so each user votes once
+CREATE TABLE IF NOT EXISTS twitter_clone.poll_votes (
+    tweet_id uuid,
+    user_id uuid,
+    option_index int,
+    created_at timestamp,
+    PRIMARY KEY (tweet_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.poll_tallies (
+    tweet_id uuid,
+    option_index int,
+    votes counter,
+    PRIMARY KEY (tweet_id, option_index)
+);
+
+-- Polls waiting to be closed, bucketed by the day they end
+CREATE TABLE IF NOT EXISTS twitter_clone.open_polls (
+    bucket int,
+    ends_at timestamp,
+    tweet_id uuid,
+    author_id uuid,
+    PRIMARY KEY (bucket, ends_at, tweet_id)
+);
+
+-- Tweets waiting to be published. status is scheduled, publishing while a
+-- server holds the lease on it, or failed
+CREATE TABLE IF NOT EXISTS twitter_clone.scheduled_tweets (
+    scheduled_id uuid PRIMARY KEY,
+    user_id uuid,
+    content text,
+    media_ids list<uuid>,
+    poll text,
+    publish_at timestamp,
+    status text,
+    lease_owner uuid,
+    lease_expires timestamp,
+    created_at timestamp
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.scheduled_by_user (
+    user_id uuid,
+    scheduled_id uuid,
+    PRIMARY KEY (user_id, scheduled_id)
+);
+
+-- Scheduled tweets by publishing time, bucketed by day, for the scheduler
+CREATE TABLE IF NOT EXISTS twitter_clone.due_tweets (
+    bucket int,
+    publish_at timestamp,
+    scheduled_id uuid,
+    PRIMARY KEY (bucket, publish_at, scheduled_id)
+);
+
+CREATE TABLE IF NOT EXISTS twitter_clone.drafts (
+    user_id uuid,
+    draft_id uuid,
+    content text,
+    media_ids list<uuid>,
+    poll text,
+    created_at timestamp,
+    updated_at timestamp,
+    PRIMARY KEY (user_id, draft_id)
+);
+
+-- Request counts for the cluster-wide rate limiter (RATE_LIMIT_STORE=scylla),
+-- per budget (e.g. tweet:user), window and client. Partitions are split into
+-- 16 shards by key and deleted by the server once their window has passed
+CREATE TABLE IF NOT EXISTS twitter_clone.rate_limit_counters (
+    budget text,
+    window_start bigint,
+    shard int,
+    key text,
+    hits counter,
+    PRIMARY KEY ((budget, window_start, shard), key)
+);
+
+-- Responses to requests sent with an Idempotency-Key, per user (or
+-- "ip:<address>") and key, replayed for retries. status is null while the first
+-- request is being handled; rows expire after a day
+CREATE TABLE IF NOT EXISTS twitter_clone.idempotency_keys (
+    scope text,
+    idempotency_key text,
+    request_hash text,
+    status int,
+    content_type text,
+    body blob,
+    created_at timestamp,
+    PRIMARY KEY ((scope, idempotency_key))
+);
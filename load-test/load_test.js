@@ -49,7 +49,7 @@ function createRandomUser() {
     const payload = JSON.stringify({
         username: username,
         email: email,
-        password: 'password123'
+        password: 'load-test-secret'
     });
 
     const params = {
@@ -58,13 +58,13 @@ function createRandomUser() {
         return null;
     }
 
-    const username = `user_${randomString(8)}_${Date.now()}`;
+    const username = `u_${randomString(12)}`;
     const email = `${username}@example.com`;
 
     const payload = JSON.stringify({
         username: username,
         email: email,
-        password: 'password123'
+        password: 'load-test-secret'
     });
 
     const params = {
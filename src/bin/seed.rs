@@ -3,11 +3,24 @@ use chrono::Utc;
 use fake::faker::internet::en::{SafeEmail, Username};
 use fake::faker::lorem::en::Sentence;
 use fake::Fake;
-use scylla::{frame::value::CqlTimestamp, Session, SessionBuilder};
+use scylla::{
+    frame::response::result::CqlValue, frame::value::CqlTimestamp, Session, SessionBuilder,
+};
 use std::error::Error;
 use std::sync::Arc;
 use uuid::Uuid;
 
+// The trends engine is pure, so the `trends` mode runs it directly on a
+// synthetic event stream instead of going through the server.
+#[allow(dead_code)]
+#[path = "../shutdown.rs"]
+mod shutdown;
+#[allow(dead_code)]
+#[path = "../trends.rs"]
+mod trends;
+
+use trends::{TrendTracker, TrendWindow};
+
 // #[tokio::main]
 // async fn main() -> Result<(), Box<dyn Error>> {
 //     println!("Starting data seeding...");
@@ -31,6 +44,11 @@ use uuid::Uuid;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    if std::env::args().nth(1).as_deref() == Some("trends") {
+        simulate_trends();
+        return Ok(());
+    }
+
     println!("Starting data seeding...");
 
     // Connect to ScyllaDB
@@ -108,6 +126,23 @@ async fn seed_users(
                 let password_hash = hash("password123", DEFAULT_COST)?;
                 let now = CqlTimestamp(Utc::now().timestamp_millis());
 
+                // Generated usernames repeat now and then; those users are
+                // skipped, as the server would refuse them.
+                let claimed = session_clone
+                    .query(
+                        "INSERT INTO twitter_clone.users_by_username (username_lower, user_id) VALUES (?, ?) IF NOT EXISTS",
+                        (username.to_ascii_lowercase(), user_id),
+                    )
+                    .await?;
+                let applied = claimed
+                    .rows
+                    .as_ref()
+                    .and_then(|rows| rows.first())
+                    .and_then(|row| row.columns.first());
+                if !matches!(applied, Some(Some(CqlValue::Boolean(true)))) {
+                    continue;
+                }
+
                 session_clone
                     .query(
                         "INSERT INTO twitter_clone.users (user_id, username, email, password_hash, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?)",
@@ -234,3 +269,84 @@ async fn seed_tweets(
 
     Ok(())
 }
+
+/// SplitMix64, so synthetic event streams are the same on every run.
+struct SyntheticRng(u64);
+
+impl SyntheticRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Feeds the trends engine 26 hours of Zipf-distributed hashtag traffic,
+/// with one hashtag bursting in the last 20 minutes, then prints what it
+/// reports. `#breakingnews` should lead the hourly trends and be flagged as
+/// spiking, while the steady `#topicN` tags should not.
+fn simulate_trends() {
+    const TOPICS: usize = 500;
+    const TWEETS_PER_MINUTE: usize = 50;
+    const SIMULATED_MINUTES: i64 = 26 * 60;
+    const SPIKE_MINUTES: i64 = 20;
+    const SPIKE_TWEETS_PER_MINUTE: usize = 15;
+
+    println!(
+        "Simulating {} hours of hashtag traffic...",
+        SIMULATED_MINUTES / 60
+    );
+
+    let mut rng = SyntheticRng(42);
+    let mut cumulative_weights = Vec::with_capacity(TOPICS);
+    let mut total_weight = 0.0;
+    for rank in 1..=TOPICS {
+        total_weight += 1.0 / rank as f64;
+        cumulative_weights.push(total_weight);
+    }
+
+    let mut tracker = TrendTracker::new();
+    let now = Utc::now().timestamp_millis();
+    let start = now - SIMULATED_MINUTES * 60_000;
+    let mut events = 0;
+
+    for minute in 0..SIMULATED_MINUTES {
+        let minute_start = start + minute * 60_000;
+        for _ in 0..TWEETS_PER_MINUTE {
+            let target = rng.next_f64() * total_weight;
+            let topic = cumulative_weights.partition_point(|&weight| weight < target);
+            let at = minute_start + (rng.next_f64() * 60_000.0) as i64;
+            tracker.record(&[format!("topic{}", topic)], at);
+            events += 1;
+        }
+        if minute >= SIMULATED_MINUTES - SPIKE_MINUTES {
+            for _ in 0..SPIKE_TWEETS_PER_MINUTE {
+                let at = minute_start + (rng.next_f64() * 60_000.0) as i64;
+                tracker.record(&["breakingnews".to_string()], at);
+                events += 1;
+            }
+        }
+    }
+    println!("Recorded {} events.", events);
+
+    for (window, label) in [(TrendWindow::Hour, "1h"), (TrendWindow::Day, "24h")] {
+        println!("\nTop trends ({}):", label);
+        for trend in tracker.top(window, now, 10) {
+            println!(
+                "  #{:<14} count {:>6}  score {:>9.1}  velocity {:>6.1}{}",
+                trend.hashtag,
+                trend.count,
+                trend.score,
+                trend.velocity,
+                if trend.spiking { "  SPIKING" } else { "" }
+            );
+        }
+    }
+}
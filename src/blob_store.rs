@@ -0,0 +1,293 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum BlobError {
+    Io(io::Error),
+    Http(reqwest::Error),
+    /// The object store answered with an unexpected status.
+    Status(StatusCode),
+    Config(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Io(e) => write!(f, "blob store I/O error: {}", e),
+            BlobError::Http(e) => write!(f, "blob store request failed: {}", e),
+            BlobError::Status(status) => write!(f, "blob store returned {}", status),
+            BlobError::Config(message) => write!(f, "blob store misconfigured: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+impl From<io::Error> for BlobError {
+    fn from(e: io::Error) -> Self {
+        BlobError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for BlobError {
+    fn from(e: reqwest::Error) -> Self {
+        BlobError::Http(e)
+    }
+}
+
+/// Storage for uploaded media. Keys are `/`-separated relative paths.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<(), BlobError>;
+
+    /// `None` if there is no blob under `key`.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, BlobError>;
+
+    /// Deleting a missing blob is not an error.
+    async fn delete(&self, key: &str) -> Result<(), BlobError>;
+}
+
+/// Picks the store from `MEDIA_STORE` (`local`, the default, or `s3`).
+///
+/// `local` keeps blobs under `MEDIA_DIR` (default `data/media`). `s3` talks
+/// to any S3-compatible service and needs `S3_ENDPOINT`, `S3_BUCKET`,
+/// `S3_ACCESS_KEY` and `S3_SECRET_KEY`; `S3_REGION` defaults to `us-east-1`.
+pub fn from_env() -> Result<Arc<dyn BlobStore>, BlobError> {
+    let env = |name: &str| {
+        std::env::var(name).map_err(|_| BlobError::Config(format!("{} is not set", name)))
+    };
+
+    match std::env::var("MEDIA_STORE").as_deref() {
+        Err(_) | Ok("local") => {
+            let root = std::env::var("MEDIA_DIR").unwrap_or_else(|_| "data/media".to_string());
+            Ok(Arc::new(LocalBlobStore::new(root)))
+        }
+        Ok("s3") => Ok(Arc::new(S3BlobStore::new(
+            &env("S3_ENDPOINT")?,
+            env("S3_BUCKET")?,
+            std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            env("S3_ACCESS_KEY")?,
+            env("S3_SECRET_KEY")?,
+        )?)),
+        Ok(other) => Err(BlobError::Config(format!(
+            "unknown MEDIA_STORE {:?}, expected \"local\" or \"s3\"",
+            other
+        ))),
+    }
+}
+
+/// Blobs as files under a root directory.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalBlobStore { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, BlobError> {
+        // Keys are generated by us, but never let one escape the root.
+        if key
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            return Err(BlobError::Config(format!("invalid blob key {:?}", key)));
+        }
+        Ok(self.root.join(Path::new(key)))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, bytes: Bytes, _content_type: &str) -> Result<(), BlobError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write then rename, so readers never see a partial file.
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        tokio::fs::write(&partial, &bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, BlobError> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Blobs in a bucket of an S3-compatible service (AWS, MinIO, ...), using
+/// path-style URLs and Signature Version 4.
+pub struct S3BlobStore {
+    client: reqwest::Client,
+    endpoint: Url,
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3BlobStore {
+    pub fn new(
+        endpoint: &str,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, BlobError> {
+        let endpoint = Url::parse(endpoint)
+            .map_err(|e| BlobError::Config(format!("invalid S3 endpoint: {}", e)))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(BlobError::Config("S3 endpoint has no host".to_string())),
+        };
+
+        Ok(S3BlobStore {
+            client: reqwest::Client::new(),
+            endpoint,
+            host,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+        })
+    }
+
+    fn object_path(&self, key: &str) -> String {
+        format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key))
+    }
+
+    /// Builds a request for `key` carrying SigV4 authentication headers.
+    fn signed_request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        body: &[u8],
+    ) -> reqwest::RequestBuilder {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let path = self.object_path(key);
+        let payload_hash = hex::encode(Sha256::digest(body));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut signing_key = hmac_sha256(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        self.client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, signed_headers, signature
+                ),
+            )
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<(), BlobError> {
+        let response = self
+            .signed_request(reqwest::Method::PUT, key, &bytes)
+            .header("content-type", content_type)
+            .body(bytes)
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(BlobError::Status(status)),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, BlobError> {
+        let response = self
+            .signed_request(reqwest::Method::GET, key, &[])
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.bytes().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(BlobError::Status(status)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        let response = self
+            .signed_request(reqwest::Method::DELETE, key, &[])
+            .send()
+            .await?;
+        match response.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(BlobError::Status(status)),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but unreserved characters and `/`, as SigV4
+/// expects for object paths.
+fn uri_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
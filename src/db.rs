@@ -1,29 +1,231 @@
 use futures::future::try_join_all;
-use scylla::{Session, SessionBuilder};
+use log::warn;
+use scylla::{
+    frame::response::result::CqlValue,
+    serialize::row::SerializeRow,
+    statement::query::Query,
+    transport::errors::{DbError, NewSessionError, QueryError},
+    transport::iterator::RowIterator,
+    QueryResult, SessionBuilder,
+};
 use std::error::Error;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::telemetry;
+
+/// The node connected to unless `SCYLLA_NODE` says otherwise.
+const DEFAULT_SCYLLA_NODE: &str = "127.0.0.1:9042";
+/// Wait before retrying a failed connection, doubled after each failure up
+/// to `MAX_CONNECT_DELAY`.
+const FIRST_CONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_CONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long to keep retrying. A freshly started cluster takes 1-2 minutes
+/// to come up.
+const CONNECT_DEADLINE: Duration = Duration::from_secs(5 * 60);
+
+/// A database session whose statements are timed into the metrics, by
+/// verb and table, and traced each in their own span.
+pub struct Session(scylla::Session);
+
+impl Session {
+    pub async fn query(
+        &self,
+        query: impl Into<Query>,
+        values: impl SerializeRow,
+    ) -> Result<QueryResult, QueryError> {
+        let query = query.into();
+        let statement = metrics::statement_label(&query.contents);
+        let span = telemetry::query_span(&statement, &query.contents);
+        let started = Instant::now();
+        let result = self.0.query(query, values).instrument(span.clone()).await;
+        metrics::observe_query(&statement, started, result.is_ok());
+        match &result {
+            Ok(result) => {
+                let rows = result.rows.as_ref().map_or(0, Vec::len);
+                span.record("db.response.returned_rows", rows);
+            }
+            Err(e) => telemetry::record_error(&span, e),
+        }
+        result
+    }
+
+    /// Only the first page is timed and traced; later ones are fetched as
+    /// the rows are read.
+    pub async fn query_iter(
+        &self,
+        query: impl Into<Query>,
+        values: impl SerializeRow,
+    ) -> Result<RowIterator, QueryError> {
+        let query = query.into();
+        let statement = metrics::statement_label(&query.contents);
+        let span = telemetry::query_span(&statement, &query.contents);
+        let started = Instant::now();
+        let result = self
+            .0
+            .query_iter(query, values)
+            .instrument(span.clone())
+            .await;
+        metrics::observe_query(&statement, started, result.is_ok());
+        match &result {
+            Ok(_) => {}
+            Err(e) => telemetry::record_error(&span, e),
+        }
+        result
+    }
+
+    /// The schema version every node agrees on, or `None` while they
+    /// disagree, e.g. during a schema change. Reaches every node.
+    pub async fn check_schema_agreement(&self) -> Result<Option<Uuid>, QueryError> {
+        self.0.check_schema_agreement().await
+    }
+}
+
+/// The sessions shared by handlers and background tasks.
+#[derive(Clone)]
+pub struct DbPool(Arc<Mutex<Vec<Arc<Session>>>>);
+
+impl DbPool {
+    /// Waits for the pool, recording how long that took.
+    pub async fn lock(&self) -> MutexGuard<'_, Vec<Arc<Session>>> {
+        let started = Instant::now();
+        let sessions = self.0.lock().await;
+        metrics::observe_pool_wait(started);
+        sessions
+    }
+
+    /// A session to use without holding the pool, for background tasks and
+    /// requests that run many statements and would otherwise hold up every
+    /// other request.
+    pub async fn session(&self) -> Option<Arc<Session>> {
+        self.lock().await.first().cloned()
+    }
+}
+
+/// Sessions connect to `SCYLLA_NODE`, over TLS when `SCYLLA_TLS_CA` is
+/// set, which needs the `scylla-tls` feature.
+fn session_builder() -> Result<SessionBuilder, Box<dyn Error>> {
+    let node = std::env::var("SCYLLA_NODE").unwrap_or_else(|_| DEFAULT_SCYLLA_NODE.to_string());
+    let builder = SessionBuilder::new().known_node(node);
+    #[cfg(feature = "scylla-tls")]
+    let builder = builder.ssl_context(ssl_context()?);
+    #[cfg(not(feature = "scylla-tls"))]
+    if std::env::var_os("SCYLLA_TLS_CA").is_some() {
+        return Err("SCYLLA_TLS_CA is set, but TLS to Scylla needs the scylla-tls feature".into());
+    }
+    Ok(builder)
+}
+
+/// Verifies the cluster against the CA in `SCYLLA_TLS_CA`, and presents the
+/// client certificate in `SCYLLA_TLS_CERT` and `SCYLLA_TLS_KEY` if set, for
+/// clusters that require one.
+#[cfg(feature = "scylla-tls")]
+fn ssl_context() -> Result<Option<openssl::ssl::SslContext>, Box<dyn Error>> {
+    use openssl::ssl::{SslContext, SslFiletype, SslMethod, SslVerifyMode};
+
+    let Some(ca_path) = std::env::var_os("SCYLLA_TLS_CA") else {
+        return Ok(None);
+    };
+    let mut context = SslContext::builder(SslMethod::tls())?;
+    context.set_ca_file(ca_path)?;
+    context.set_verify(SslVerifyMode::PEER);
+    match (
+        std::env::var_os("SCYLLA_TLS_CERT"),
+        std::env::var_os("SCYLLA_TLS_KEY"),
+    ) {
+        (None, None) => {}
+        (Some(cert_path), Some(key_path)) => {
+            context.set_certificate_chain_file(cert_path)?;
+            context.set_private_key_file(key_path, SslFiletype::PEM)?;
+            context.check_private_key()?;
+        }
+        _ => return Err("SCYLLA_TLS_CERT and SCYLLA_TLS_KEY must be set together".into()),
+    }
+    Ok(Some(context.build()))
+}
+
+/// Connects to the cluster, retrying with exponential backoff while it is
+/// unreachable, e.g. still booting.
+async fn connect(builder: &SessionBuilder) -> Result<scylla::Session, NewSessionError> {
+    let started = Instant::now();
+    let mut delay = FIRST_CONNECT_DELAY;
+    loop {
+        match builder.build().await {
+            Ok(session) => return Ok(session),
+            Err(e) if started.elapsed() + delay < CONNECT_DEADLINE => {
+                warn!(
+                    "Failed to connect to Scylla, retrying in {:?}: {}",
+                    delay, e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_CONNECT_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
 
 pub async fn create_session() -> Result<Session, Box<dyn Error>> {
-    let session = SessionBuilder::new()
-        .known_node("127.0.0.1:9042")
-        .build()
-        .await?;
+    let session = connect(&session_builder()?).await?;
 
-    Ok(session)
+    Ok(Session(session))
 }
 
-pub async fn create_connection_pool() -> Result<Arc<Mutex<Vec<Session>>>, Box<dyn Error>> {
+pub async fn create_connection_pool() -> Result<DbPool, Box<dyn Error>> {
+    let builder = session_builder()?;
     let session_futures = (0..num_cpus::get() * 8)
         .map(|_| async {
-            SessionBuilder::new()
-                .known_node("127.0.0.1:9042")
-                .build()
+            connect(&builder)
                 .await
+                .map(|session| Arc::new(Session(session)))
         })
         .collect::<Vec<_>>();
 
     let pool = try_join_all(session_futures).await?;
 
-    Ok(Arc::new(Mutex::new(pool)))
+    Ok(DbPool(Arc::new(Mutex::new(pool))))
+}
+
+/// Whether a conditional (`IF ...`) statement was applied.
+pub fn lwt_applied(result: &QueryResult) -> bool {
+    matches!(
+        result
+            .rows
+            .as_ref()
+            .and_then(|rows| rows.first())
+            .and_then(|row| row.columns.first()),
+        Some(Some(CqlValue::Boolean(true)))
+    )
+}
+
+/// Whether a failed write certainly wasn't applied, because the cluster
+/// refused it up front. After a timeout or a lost connection it may have
+/// been.
+pub fn write_not_applied(e: &QueryError) -> bool {
+    matches!(
+        e,
+        QueryError::UnableToAllocStreamId
+            | QueryError::DbError(
+                DbError::Unavailable { .. } | DbError::Overloaded | DbError::IsBootstrapping,
+                _,
+            )
+    )
+}
+
+/// The uuids of a `list<uuid>` column. A null column is an empty list.
+pub fn uuid_list(value: Option<CqlValue>) -> Vec<Uuid> {
+    match value {
+        Some(CqlValue::List(values)) => values
+            .into_iter()
+            .filter_map(|value| match value {
+                CqlValue::Uuid(id) => Some(id),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
 }
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use unicode_normalization::char::is_combining_mark;
+use uuid::Uuid;
+
+use crate::models::{HashtagEntity, MentionEntity, TweetEntities};
+
+const HASH_SIGNS: [char; 2] = ['#', '\u{FF03}'];
+const AT_SIGNS: [char; 2] = ['@', '\u{FF20}'];
+const MAX_HASHTAG_CHARS: usize = 100;
+pub const MAX_USERNAME_CHARS: usize = 15;
+
+/// Characters that twitter-text allows inside a hashtag besides letters,
+/// marks and digits (joiners and a handful of script-specific punctuation).
+const HASHTAG_SPECIAL_CHARS: [char; 16] = [
+    '_', '\u{200C}', '\u{200D}', '\u{A67E}', '\u{05BE}', '\u{05F3}', '\u{05F4}', '\u{FF5E}',
+    '\u{301C}', '\u{309B}', '\u{309C}', '\u{30A0}', '\u{30FB}', '\u{3003}', '\u{0F0B}', '\u{00B7}',
+];
+
+fn is_hashtag_alpha(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+/// Letters, digits and marks (general categories Mn, Mc and Me, such as
+/// the Devanagari virama or a combining acute accent), plus the special
+/// characters.
+fn is_hashtag_char(c: char) -> bool {
+    c.is_alphanumeric() || is_combining_mark(c) || HASHTAG_SPECIAL_CHARS.contains(&c)
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl TweetEntities {
+    /// Extracts all entities from tweet text. Offsets are in Unicode scalar
+    /// values, with `end` exclusive. `resolved_mentions` maps usernames as
+    /// written in the tweet to user ids; mentions missing from it are left as
+    /// plain text.
+    pub fn parse(content: &str, resolved_mentions: &HashMap<String, Uuid>) -> Self {
+        let mentions = extract_mentions(content)
+            .into_iter()
+            .filter_map(|(username, start, end)| {
+                let user_id = *resolved_mentions.get(&username)?;
+                Some(MentionEntity {
+                    username,
+                    user_id,
+                    start,
+                    end,
+                })
+            })
+            .collect();
+
+        TweetEntities {
+            hashtags: extract_hashtags(content),
+            mentions,
+        }
+    }
+
+    /// Normalized hashtags, each once.
+    pub fn unique_hashtags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self
+            .hashtags
+            .iter()
+            .filter_map(|hashtag| normalize_hashtag(&hashtag.tag))
+            .collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    /// Ids of the mentioned users, each once.
+    pub fn mentioned_user_ids(&self) -> Vec<Uuid> {
+        let mut user_ids: Vec<Uuid> = self
+            .mentions
+            .iter()
+            .map(|mention| mention.user_id)
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        user_ids
+    }
+}
+
+/// Finds `@username` candidates following Twitter's rules: an `@` (or
+/// fullwidth `＠`) not preceded by a username character or one of `!#$%&*@`,
+/// followed by up to 15 ASCII letters, digits or underscores, and not
+/// directly followed by another at sign or `://`. Returns the username
+/// without the at sign together with its offsets.
+pub fn extract_mentions(content: &str) -> Vec<(String, usize, usize)> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut mentions = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if !AT_SIGNS.contains(&chars[i]) {
+            i += 1;
+            continue;
+        }
+
+        let preceded_ok = i == 0
+            || !(is_username_char(chars[i - 1])
+                || "!#$%&*".contains(chars[i - 1])
+                || AT_SIGNS.contains(&chars[i - 1]));
+        let start = i;
+        let mut end = i + 1;
+        while end < chars.len() && is_username_char(chars[end]) {
+            end += 1;
+        }
+
+        let followed_ok = match chars.get(end) {
+            Some(c) if AT_SIGNS.contains(c) => false,
+            Some(':') => !(chars.get(end + 1) == Some(&'/') && chars.get(end + 2) == Some(&'/')),
+            _ => true,
+        };
+        let length = end - start - 1;
+
+        if preceded_ok && followed_ok && (1..=MAX_USERNAME_CHARS).contains(&length) {
+            mentions.push((chars[start + 1..end].iter().collect(), start, end));
+        }
+
+        i = end;
+    }
+
+    mentions
+}
+
+/// Finds hashtags following Twitter's rules: a `#` (or fullwidth `＃`) not
+/// preceded by a hashtag character or `&`, followed by hashtag characters of
+/// which at least one is a letter, and not directly followed by another hash
+/// sign or `://`.
+pub fn extract_hashtags(content: &str) -> Vec<HashtagEntity> {
+    let chars: Vec<char> = content.chars().collect();
+    let mut hashtags = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if !HASH_SIGNS.contains(&chars[i]) {
+            i += 1;
+            continue;
+        }
+
+        let preceded_ok = i == 0 || !(is_hashtag_char(chars[i - 1]) || chars[i - 1] == '&');
+        let start = i;
+        let mut end = i + 1;
+        while end < chars.len() && is_hashtag_char(chars[end]) {
+            end += 1;
+        }
+
+        let body = &chars[start + 1..end];
+        let followed_ok = match chars.get(end) {
+            Some(c) if HASH_SIGNS.contains(c) => false,
+            Some(':') => !(chars.get(end + 1) == Some(&'/') && chars.get(end + 2) == Some(&'/')),
+            _ => true,
+        };
+
+        if preceded_ok
+            && followed_ok
+            && body.len() <= MAX_HASHTAG_CHARS
+            && body.iter().any(|&c| is_hashtag_alpha(c))
+        {
+            hashtags.push(HashtagEntity {
+                tag: body.iter().collect(),
+                start,
+                end,
+            });
+        }
+
+        i = end;
+    }
+
+    hashtags
+}
+
+/// Canonical form of a hashtag used as the partition key of
+/// `tweets_by_hashtag`: without the hash sign and lowercased. Returns `None`
+/// if the input is not a valid hashtag.
+pub fn normalize_hashtag(tag: &str) -> Option<String> {
+    let tag = tag.strip_prefix(HASH_SIGNS).unwrap_or(tag);
+    if tag.is_empty()
+        || tag.chars().count() > MAX_HASHTAG_CHARS
+        || !tag.chars().all(is_hashtag_char)
+        || !tag.chars().any(is_hashtag_alpha)
+    {
+        return None;
+    }
+    Some(tag.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(content: &str) -> Vec<String> {
+        extract_hashtags(content)
+            .into_iter()
+            .map(|hashtag| hashtag.tag)
+            .collect()
+    }
+
+    fn usernames(content: &str) -> Vec<String> {
+        extract_mentions(content)
+            .into_iter()
+            .map(|(username, _, _)| username)
+            .collect()
+    }
+
+    #[test]
+    fn hashtags_keep_combining_marks() {
+        assert_eq!(tags("#नमस्ते दुनिया"), ["नमस्ते"]);
+        assert_eq!(tags("#cafe\u{301} au lait"), ["cafe\u{301}"]);
+        assert_eq!(tags("#日本語 #한국어"), ["日本語", "한국어"]);
+    }
+
+    #[test]
+    fn hashtag_offsets_are_in_chars() {
+        let hashtags = extract_hashtags("héllo #wörld!");
+        assert_eq!(hashtags.len(), 1);
+        assert_eq!((hashtags[0].start, hashtags[0].end), (6, 12));
+    }
+
+    #[test]
+    fn hashtags_follow_twitter_rules() {
+        assert_eq!(tags("#rust_lang, ＃fullwidth"), ["rust_lang", "fullwidth"]);
+        // Digits alone, a preceding word character or `&`, and a following
+        // hash sign or URL scheme all disqualify.
+        assert!(tags("#2024 a#b &#39; #x#y #http://x").is_empty());
+        assert_eq!(
+            tags(&format!("#{}", "a".repeat(MAX_HASHTAG_CHARS))).len(),
+            1
+        );
+        assert!(tags(&format!("#{}", "a".repeat(MAX_HASHTAG_CHARS + 1))).is_empty());
+    }
+
+    #[test]
+    fn mentions_follow_twitter_rules() {
+        let mentions = extract_mentions("hi @alice and ＠Bob_2");
+        assert_eq!(
+            mentions,
+            [("alice".to_string(), 3, 9), ("Bob_2".to_string(), 14, 20)]
+        );
+        assert!(usernames("mail@example.com !@x @a@b @ftp://x @").is_empty());
+        assert_eq!(
+            usernames(&format!("@{}", "a".repeat(MAX_USERNAME_CHARS))).len(),
+            1
+        );
+        assert!(usernames(&format!("@{}", "a".repeat(MAX_USERNAME_CHARS + 1))).is_empty());
+    }
+
+    #[test]
+    fn hashtags_normalize_to_lowercase_without_the_sign() {
+        assert_eq!(normalize_hashtag("#RustLang").as_deref(), Some("rustlang"));
+        assert_eq!(normalize_hashtag("＃Ünïcode").as_deref(), Some("ünïcode"));
+        assert_eq!(normalize_hashtag("नमस्ते").as_deref(), Some("नमस्ते"));
+        assert_eq!(normalize_hashtag("#"), None);
+        assert_eq!(normalize_hashtag("#123"), None);
+        assert_eq!(normalize_hashtag("#two words"), None);
+    }
+}
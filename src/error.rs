@@ -0,0 +1,265 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use log::{error, warn};
+use scylla::transport::errors::{DbError, QueryError};
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longest client-supplied request id that is kept rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request is malformed or breaks a limit.
+    Validation(String),
+    /// Some fields of the request body are invalid, each listed.
+    InvalidFields(Vec<FieldError>),
+    /// Names the thing that wasn't found, e.g. `"tweet"`.
+    NotFound(&'static str),
+    Conflict(&'static str),
+    /// An `Idempotency-Key` was sent again with a different request.
+    IdempotencyKeyReused,
+    /// The request lacks valid credentials.
+    Unauthorized(&'static str),
+    /// The caller may not act on the resource.
+    Forbidden(&'static str),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    /// The server is too busy to take the request now.
+    Overloaded {
+        retry_after: Duration,
+    },
+    Timeout,
+    DatabaseUnavailable,
+    Database(QueryError),
+    Internal(String),
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "<[FieldError]>::is_empty")]
+    pub fields: &'a [FieldError],
+}
+
+/// Why one field of a request body was rejected.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    /// Machine-readable rule that failed, e.g. `"length"`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    /// Stable, machine-readable name for the error, sent as `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) | ApiError::InvalidFields(_) => "invalid_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Overloaded { .. } => "overloaded",
+            ApiError::Timeout => "timeout",
+            ApiError::DatabaseUnavailable => "database_unavailable",
+            ApiError::Database(_) | ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Validation(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::UnsupportedMediaType(message) => write!(f, "{}", message),
+            ApiError::InvalidFields(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| format!("{} {}", field.field, field.message))
+                    .collect();
+                write!(f, "{}", fields.join("; "))
+            }
+            ApiError::NotFound(what) => write!(f, "{} not found", what),
+            ApiError::Conflict(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message) => write!(f, "{}", message),
+            ApiError::IdempotencyKeyReused => {
+                write!(f, "this Idempotency-Key was used for a different request")
+            }
+            ApiError::RateLimited { .. } => write!(f, "too many requests"),
+            ApiError::Overloaded { .. } => write!(f, "the server is busy"),
+            ApiError::Timeout => write!(f, "the request timed out"),
+            ApiError::DatabaseUnavailable => write!(f, "the database is unavailable"),
+            ApiError::Database(_) | ApiError::Internal(_) => write!(f, "internal error"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Validation(_) | ApiError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Overloaded { .. } | ApiError::DatabaseUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let request_id = current_request_id();
+        let status = self.status_code();
+        // The details stay in the log; clients only get the generic message.
+        match self {
+            ApiError::Database(e) => error!("Request {:?} failed: {:?}", request_id, e),
+            ApiError::Internal(detail) => error!("Request {:?} failed: {}", request_id, detail),
+            _ if status.is_server_error() => warn!("Request {:?} failed: {}", request_id, self),
+            _ => {}
+        }
+
+        let mut response = HttpResponse::build(status);
+        let retry_after = match self {
+            ApiError::RateLimited { retry_after } => *retry_after,
+            ApiError::Overloaded { retry_after } => Some(*retry_after),
+            _ => None,
+        };
+        if let Some(retry_after) = retry_after {
+            // Rounded up, so a client waiting this long is let through.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.insert_header((header::RETRY_AFTER, seconds));
+        }
+        if let ApiError::Unauthorized(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            request_id,
+            fields: match self {
+                ApiError::InvalidFields(fields) => fields,
+                _ => &[],
+            },
+        })
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(e: QueryError) -> Self {
+        match e {
+            QueryError::TimeoutError
+            | QueryError::RequestTimeout(_)
+            | QueryError::DbError(DbError::ReadTimeout { .. } | DbError::WriteTimeout { .. }, _) => {
+                ApiError::Timeout
+            }
+            QueryError::IoError(_)
+            | QueryError::UnableToAllocStreamId
+            | QueryError::TooManyOrphanedStreamIds(_)
+            | QueryError::DbError(
+                DbError::Unavailable { .. } | DbError::Overloaded | DbError::IsBootstrapping,
+                _,
+            ) => ApiError::DatabaseUnavailable,
+            QueryError::DbError(DbError::RateLimitReached { .. }, _) => {
+                ApiError::RateLimited { retry_after: None }
+            }
+            e => ApiError::Database(e),
+        }
+    }
+}
+
+impl From<JsonPayloadError> for ApiError {
+    fn from(e: JsonPayloadError) -> Self {
+        match e {
+            JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+                ApiError::PayloadTooLarge(e.to_string())
+            }
+            JsonPayloadError::ContentType => ApiError::UnsupportedMediaType(e.to_string()),
+            e => ApiError::Validation(e.to_string()),
+        }
+    }
+}
+
+/// Error handler for `web::JsonConfig`, so malformed bodies get the same
+/// error schema as handler errors.
+pub fn json_error(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::from(e).into()
+}
+
+pub fn path_error(e: PathError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::Validation(e.to_string()).into()
+}
+
+pub fn query_error(e: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::Validation(e.to_string()).into()
+}
+
+/// Answers requests no route matched.
+pub async fn not_found() -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound("route"))
+}
+
+/// The id of the request being handled, if called while handling one.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+/// The request's id: the client's `X-Request-Id` if it sent a usable one,
+/// otherwise a new one.
+pub fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_REQUEST_ID_LEN
+                && value.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Runs a request with its id available to `current_request_id`, and echoes
+/// the id back in the `X-Request-Id` response header.
+pub async fn with_request_id<B>(
+    request_id: String,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let header_value = HeaderValue::from_str(&request_id);
+    let mut response = REQUEST_ID.scope(request_id, response).await?;
+    if let Ok(value) = header_value {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
@@ -1,27 +1,98 @@
-use actix_web::{get, post, web, HttpResponse};
+use actix_multipart::{Field, Multipart, MultipartError};
+use actix_web::{delete, get, http::header, post, put, web, HttpRequest, HttpResponse};
 use bcrypt::{hash, DEFAULT_COST};
+use bytes::{Bytes, BytesMut};
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use log::{debug, error, info};
 use scylla::{
-    frame::response::result::CqlValue, frame::value::CqlTimestamp, transport::errors::QueryError,
-    QueryResult, Session,
+    frame::response::result::{CqlValue, Row},
+    frame::value::{Counter, CqlTimestamp},
+    transport::errors::QueryError,
+    QueryResult,
 };
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::Infallible;
+use std::time::Duration;
+use utoipa::IntoParams;
 use uuid::Uuid;
-type DbPool = Arc<Mutex<Vec<Session>>>;
 
-use crate::models::{CreateTweetRequest, CreateUserRequest, Tweet, User};
+use crate::blob_store::BlobStore;
+use crate::db::{lwt_applied, uuid_list, write_not_applied, DbPool, Session};
+use crate::entities::{extract_mentions, normalize_hashtag};
+use crate::error::ApiError;
+use crate::media::{
+    blob_key, content_hash, MediaBlob, MediaKind, StoredMedia, StoredVariant, MAX_ALT_TEXT_CHARS,
+    MAX_MEDIA_PER_TWEET, MAX_UPLOAD_BYTES,
+};
+use crate::media_processing::{MediaProcessor, ProcessError};
+use crate::models::{
+    Conversation, CreateConversationRequest, CreateListRequest, CreateTweetRequest,
+    CreateUserRequest, DirectMessage, DmAllowFrom, DmSettings, Draft, List, MarkReadRequest, Media,
+    MessagePage, NotificationPage, PinTweetRequest, Poll, ScheduleStatus, ScheduleTweetRequest,
+    ScheduledTweet, SendMessageRequest, TrendList, Tweet, TweetEntities, TweetPage,
+    TweetSearchResults, UnreadCount, UpdateProfileRequest, User, UserLists, UserSearchResults,
+    UserSummary, VoteRequest,
+};
+use crate::notifications::{
+    delete_tweet_notifications, describe, group_notifications, NotificationEvent, NotificationKind,
+    NotificationSender, StoredNotification,
+};
+use crate::openapi::MediaUpload;
+use crate::polls::{delete_poll_rows, hydrate_poll, hydrate_polls, new_poll, schedule_close};
+use crate::scheduler::{delete_schedule_rows, fetch_scheduled, schedule_bucket};
+use crate::search::{SearchIndex, SearchOrder, SearchQuery};
+use crate::shutdown::Shutdown;
+use crate::stream::Broker;
+use crate::trends::{TrendEvent, TrendSender, TrendWindow, Trends};
+use crate::user_index::UserIndex;
+use crate::validation::{self, Invalid};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+/// Results returned by user search in typeahead mode.
+const TYPEAHEAD_PAGE_SIZE: usize = 8;
+const DEFAULT_TRENDS: usize = 10;
+const MAX_TRENDS: usize = 50;
+/// `tweets_by_hashtag` partitions are bucketed by day so a popular tag does
+/// not grow a single unbounded partition.
+const HASHTAG_BUCKET_MILLIS: i64 = 24 * 60 * 60 * 1000;
+/// How many empty days a hashtag page request walks back before giving up.
+const HASHTAG_MAX_BUCKETS_SCANNED: i32 = 30;
+/// Participants of a group conversation, including its creator.
+const MAX_CONVERSATION_PARTICIPANTS: usize = 50;
+const MAX_MESSAGE_CHARS: usize = 10_000;
+const MAX_SCHEDULED_PER_USER: usize = 100;
+/// How far ahead a tweet can be scheduled.
+const MAX_SCHEDULE_AHEAD_DAYS: i64 = 365;
+const MAX_DRAFTS_PER_USER: usize = 100;
+const MAX_LISTS_PER_USER: usize = 100;
+/// Reading a list fans out to every member's timeline, so lists stay small.
+const MAX_LIST_MEMBERS: usize = 500;
+const MAX_LIST_NAME_CHARS: usize = 25;
+const MAX_LIST_DESCRIPTION_CHARS: usize = 100;
+/// Unread notification rows grouped to compute the unread count.
+const MAX_UNREAD_COUNTED: i32 = 1000;
+/// Member timelines read at once when building a list timeline.
+const LIST_TIMELINE_CONCURRENCY: usize = 16;
+/// Sent on idle streams so proxies and the server's keep-alive don't close them.
+const STREAM_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
 
+#[utoipa::path(
+    tag = "Users",
+    summary = "Create a user",
+    responses((status = 200, description = "The new user", body = User))
+)]
 #[post("/users")]
 pub async fn create_user(
     db_pool: web::Data<DbPool>,
+    user_index: web::Data<UserIndex>,
     user_data: web::Json<CreateUserRequest>,
-) -> HttpResponse {
-    let password_hash = match hash(user_data.password.as_bytes(), DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let new_user = validation::new_user(&user_data)?;
+    let password_hash = hash(new_user.password.as_bytes(), DEFAULT_COST)
+        .map_err(|e| ApiError::Internal(format!("Failed to hash password: {:?}", e)))?;
 
     let user_id = Uuid::new_v4();
     let now = Utc::now();
@@ -29,309 +100,4141 @@ pub async fn create_user(
 
     let pool = db_pool.lock().await;
 
-    if let Some(session) = pool.get(0) {
-        let result = session
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let username_lower = new_user.username.to_ascii_lowercase();
+    let claimed = session
+        .query(
+            "INSERT INTO twitter_clone.users_by_username (username_lower, user_id) VALUES (?, ?) IF NOT EXISTS",
+            (&username_lower, user_id),
+        )
+        .await?;
+    if !lwt_applied(&claimed) {
+        return Err(ApiError::Conflict("username is already taken"));
+    }
+    if let Err(e) = session
+        .query(
+            "INSERT INTO twitter_clone.users (user_id, username, display_name, email, password_hash, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (
+                user_id,
+                &new_user.username,
+                &new_user.display_name,
+                &new_user.email,
+                &password_hash,
+                cql_timestamp,
+                cql_timestamp
+            ),
+        )
+        .await
+    {
+        // Free the username for a retry. Conditional, like the claim.
+        if let Err(e) = session
             .query(
-                "INSERT INTO twitter_clone.users (user_id, username, email, password_hash, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?)",
-                (
-                    user_id,
-                    &user_data.username,
-                    &user_data.email,
-                    &password_hash,
-                    cql_timestamp,
-                    cql_timestamp
-                ),
+                "DELETE FROM twitter_clone.users_by_username WHERE username_lower = ? IF user_id = ?",
+                (&username_lower, user_id),
             )
-            .await;
-        match result {
-            Ok(_) => {
-                let user = User {
-                    user_id,
-                    username: user_data.username.clone(),
-                    email: user_data.email.clone(),
-                    password_hash,
-                    created_at: now,
-                    updated_at: now,
-                };
-                HttpResponse::Ok().json(user)
-            }
-            Err(_) => HttpResponse::InternalServerError().finish(),
+            .await
+        {
+            error!("Failed to release username {:?}: {:?}", username_lower, e);
         }
-    } else {
-        HttpResponse::InternalServerError().body("Internal error")
+        return Err(e.into());
     }
+    user_index.upsert(
+        user_id,
+        &new_user.username,
+        new_user.display_name.as_deref(),
+    );
+    let user = User {
+        user_id,
+        username: new_user.username,
+        display_name: new_user.display_name,
+        email: new_user.email,
+        password_hash,
+        created_at: now,
+        updated_at: now,
+    };
+    Ok(HttpResponse::Ok().json(user))
 }
 
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Post a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "The new tweet", body = Tweet))
+)]
 #[post("/tweets")]
 pub async fn create_tweet(
     db_pool: web::Data<DbPool>,
+    sinks: web::Data<TweetSinks>,
     tweet_data: web::Json<CreateTweetRequest>,
     query: web::Query<UserIdQuery>,
-) -> HttpResponse {
-    let tweet_id = Uuid::new_v4();
-    let user_id = match Uuid::parse_str(&query.user_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
-    let now = Utc::now();
-    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
 
     let pool = db_pool.lock().await;
 
-    if let Some(session) = pool.get(0) {
-        let result = session
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let tweet = publish_tweet(
+        session,
+        &sinks,
+        user_id,
+        Uuid::new_v4(),
+        None,
+        &tweet_data,
+        Utc::now(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(tweet))
+}
+
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Reply to a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "The reply", body = Tweet))
+)]
+#[post("/tweets/{tweet_id}/replies")]
+pub async fn reply_to_tweet(
+    db_pool: web::Data<DbPool>,
+    sinks: web::Data<TweetSinks>,
+    tweet_id: web::Path<String>,
+    tweet_data: web::Json<CreateTweetRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let in_reply_to_tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    if fetch_tweet(session, in_reply_to_tweet_id).await?.is_none() {
+        return Err(ApiError::NotFound("tweet"));
+    }
+    let tweet = publish_tweet(
+        session,
+        &sinks,
+        user_id,
+        Uuid::new_v4(),
+        Some(in_reply_to_tweet_id),
+        &tweet_data,
+        Utc::now(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(tweet))
+}
+
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "List a tweet's replies",
+    params(PageQuery),
+    responses((status = 200, description = "Replies, newest first", body = TweetPage))
+)]
+#[get("/tweets/{tweet_id}/replies")]
+pub async fn get_replies(
+    db_pool: web::Data<DbPool>,
+    tweet_id: web::Path<String>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let limit = page.limit();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let rows = fetch_page_rows(
+        session,
+        "SELECT reply_id, created_at FROM twitter_clone.replies_by_tweet WHERE tweet_id = ?",
+        "reply_id",
+        vec![CqlValue::Uuid(tweet_id)],
+        page.cursor()?,
+        limit,
+    )
+    .await?;
+
+    let entries: Vec<(Uuid, i64)> = rows
+        .into_iter()
+        .filter_map(timeline_entry_from_row)
+        .collect();
+    let next_cursor = next_page_cursor(&entries, limit);
+    let reply_ids: Vec<Uuid> = entries.iter().map(|(reply_id, _)| *reply_id).collect();
+    let tweets = fetch_tweets(session, &reply_ids, None).await;
+    Ok(HttpResponse::Ok().json(TweetPage {
+        tweets,
+        next_cursor,
+    }))
+}
+
+/// Where a newly published tweet is announced, besides the database.
+#[derive(Clone)]
+pub struct TweetSinks {
+    pub notifications: NotificationSender,
+    pub broker: Broker,
+    pub search_index: SearchIndex,
+    pub trend_sender: TrendSender,
+}
+
+/// Writes a tweet and everything derived from it, then announces it. Used
+/// for new tweets, replies, drafts and scheduled tweets alike.
+///
+/// Every write is keyed by `tweet_id` and `created_at`, so publishing the
+/// same tweet again after a failure overwrites rather than duplicates it.
+pub async fn publish_tweet(
+    session: &Session,
+    sinks: &TweetSinks,
+    user_id: Uuid,
+    tweet_id: Uuid,
+    in_reply_to_tweet_id: Option<Uuid>,
+    tweet_data: &CreateTweetRequest,
+    now: DateTime<Utc>,
+) -> Result<Tweet, ApiError> {
+    let content = tweet_content(tweet_data)?;
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+    let poll = match &tweet_data.poll {
+        // A tweet carries either media or a poll, not both.
+        Some(_) if !tweet_data.media_ids.is_empty() => {
+            return Err(ApiError::Validation(
+                "a tweet can't have both media and a poll".to_string(),
+            ))
+        }
+        Some(request) => Some(new_poll(request, now)?),
+        None => None,
+    };
+    let poll_json = poll
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize poll: {:?}", e)))?;
+
+    let media = resolve_media(session, user_id, &tweet_data.media_ids).await?;
+    let media_json = serde_json::to_string(&media)
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize media: {:?}", e)))?;
+
+    let mentions = resolve_mentions(session, &content).await?;
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.tweets (tweet_id, user_id, content, created_at, mentions, media, poll, in_reply_to_tweet_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                tweet_id,
+                user_id,
+                &content,
+                cql_timestamp,
+                &mentions,
+                &media_json,
+                &poll_json,
+                in_reply_to_tweet_id,
+            ),
+        )
+        .await?;
+    if let Some(in_reply_to_tweet_id) = in_reply_to_tweet_id {
+        session
             .query(
-                "INSERT INTO twitter_clone.tweets (tweet_id, user_id, content, created_at) VALUES (?, ?, ?, ?)",
-                (tweet_id, user_id, &tweet_data.content, cql_timestamp),
+                "INSERT INTO twitter_clone.replies_by_tweet (tweet_id, created_at, reply_id) VALUES (?, ?, ?)",
+                (in_reply_to_tweet_id, cql_timestamp, tweet_id),
             )
-            .await;
-
-        match result {
-            Ok(_) => {
-                // Also insert into user_timeline
-                let timeline_result = session
-                        .query(
-                            "INSERT INTO twitter_clone.user_timeline (user_id, tweet_id, created_at) VALUES (?, ?, ?)",
-                            (user_id, tweet_id, cql_timestamp),
-                        )
-                        .await;
-
-                match timeline_result {
-                    Ok(_) => {
-                        let tweet = Tweet {
-                            tweet_id,
-                            user_id,
-                            content: tweet_data.content.clone(),
-                            created_at: now,
-                        };
-                        info!("Tweet created successfully: {}", tweet_id);
-                        HttpResponse::Ok().json(tweet)
-                    }
-                    Err(e) => {
-                        error!("Failed to update timeline: {:?}", e);
-                        HttpResponse::InternalServerError().finish()
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to create tweet: {:?}", e);
-                HttpResponse::InternalServerError().finish()
-            }
+            .await?;
+    }
+
+    // Also insert into user_timeline
+    session
+        .query(
+            "INSERT INTO twitter_clone.user_timeline (user_id, tweet_id, created_at) VALUES (?, ?, ?)",
+            (user_id, tweet_id, cql_timestamp),
+        )
+        .await?;
+
+    let entities = TweetEntities::parse(&content, &mentions);
+    index_hashtags(session, &entities, tweet_id, user_id, cql_timestamp).await?;
+    index_mentions(session, &entities, tweet_id, user_id, cql_timestamp).await?;
+    if let Some(poll) = &poll {
+        schedule_close(session, tweet_id, user_id, poll).await?;
+    }
+
+    if let Err(e) = sinks
+        .search_index
+        .add_tweet(tweet_id, user_id, &content, cql_timestamp.0)
+    {
+        // The index can be rebuilt with `reindex`, so this doesn't fail the
+        // request.
+        error!("Failed to index tweet {}: {:?}", tweet_id, e);
+    }
+
+    let hashtags = entities.unique_hashtags();
+    if !hashtags.is_empty() {
+        sinks.trend_sender.send(TrendEvent {
+            hashtags,
+            created_at: now,
+        });
+    }
+
+    let mentioned_user_ids = entities.mentioned_user_ids();
+    if !mentioned_user_ids.is_empty() {
+        sinks.notifications.send(NotificationEvent::Mentioned {
+            actor_id: user_id,
+            tweet_id,
+            mentioned_user_ids,
+            created_at: now,
+        });
+    }
+    if let Some(in_reply_to_tweet_id) = in_reply_to_tweet_id {
+        sinks.notifications.send(NotificationEvent::Replied {
+            actor_id: user_id,
+            tweet_id,
+            in_reply_to_tweet_id,
+            created_at: now,
+        });
+    }
+
+    let mut tweet = Tweet {
+        tweet_id,
+        user_id,
+        content,
+        created_at: now,
+        entities,
+        media,
+        poll,
+        in_reply_to_tweet_id,
+        pinned: false,
+    };
+    sinks.broker.publish(user_id, "tweet", &tweet);
+    // The author always sees their poll's results.
+    if let Some(poll) = &mut tweet.poll {
+        for option in &mut poll.options {
+            option.votes = Some(0);
         }
-    } else {
-        HttpResponse::InternalServerError().body("Internal error")
+        poll.total_votes = Some(0);
+    }
+    info!("Tweet created successfully: {}", tweet_id);
+    Ok(tweet)
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Schedule a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "The scheduled tweet", body = ScheduledTweet))
+)]
+#[post("/tweets/scheduled")]
+pub async fn schedule_tweet(
+    db_pool: web::Data<DbPool>,
+    request: web::Json<ScheduleTweetRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let now = Utc::now();
+    let publish_at = request.publish_at;
+    if publish_at <= now || publish_at > now + chrono::Duration::days(MAX_SCHEDULE_AHEAD_DAYS) {
+        return Err(ApiError::Validation(format!(
+            "publish_at must be in the next {} days",
+            MAX_SCHEDULE_AHEAD_DAYS
+        )));
+    }
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let content = check_publishable(session, user_id, &request.tweet, publish_at).await?;
+    let count = count_rows(
+        session,
+        "SELECT COUNT(*) FROM twitter_clone.scheduled_by_user WHERE user_id = ?",
+        user_id,
+    )
+    .await?;
+    if count >= MAX_SCHEDULED_PER_USER as i64 {
+        return Err(ApiError::Validation(format!(
+            "at most {} tweets can be scheduled",
+            MAX_SCHEDULED_PER_USER
+        )));
+    }
+
+    let scheduled = ScheduledTweet {
+        scheduled_id: Uuid::new_v4(),
+        user_id,
+        content,
+        media_ids: request.tweet.media_ids.clone(),
+        poll: request.tweet.poll.clone(),
+        publish_at,
+        status: ScheduleStatus::Scheduled,
+        created_at: now,
+    };
+    let publish_at_cql = CqlTimestamp(publish_at.timestamp_millis());
+    session
+        .query(
+            "INSERT INTO twitter_clone.scheduled_tweets (scheduled_id, user_id, content, media_ids, poll, publish_at, status, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                scheduled.scheduled_id,
+                user_id,
+                &scheduled.content,
+                &scheduled.media_ids,
+                scheduled.poll.as_ref().and_then(|poll| serde_json::to_string(poll).ok()),
+                publish_at_cql,
+                scheduled.status.as_str(),
+                CqlTimestamp(now.timestamp_millis()),
+            ),
+        )
+        .await?;
+    session
+        .query(
+            "INSERT INTO twitter_clone.scheduled_by_user (user_id, scheduled_id) VALUES (?, ?)",
+            (user_id, scheduled.scheduled_id),
+        )
+        .await?;
+    // Written last, so the scheduler never finds a half-written tweet.
+    session
+        .query(
+            "INSERT INTO twitter_clone.due_tweets (bucket, publish_at, scheduled_id) VALUES (?, ?, ?)",
+            (schedule_bucket(publish_at), publish_at_cql, scheduled.scheduled_id),
+        )
+        .await?;
+
+    info!(
+        "Tweet {} scheduled for {}",
+        scheduled.scheduled_id, publish_at
+    );
+    Ok(HttpResponse::Ok().json(scheduled))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "List your scheduled tweets",
+    params(UserIdQuery),
+    responses(
+        (status = 200, description = "Scheduled tweets, soonest first", body = Vec<ScheduledTweet>),
+    )
+)]
+#[get("/tweets/scheduled")]
+pub async fn get_scheduled_tweets(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let scheduled_ids = fetch_uuid_column(
+        session,
+        "SELECT scheduled_id FROM twitter_clone.scheduled_by_user WHERE user_id = ?",
+        user_id,
+    )
+    .await?;
+
+    let mut scheduled_tweets = Vec::new();
+    for scheduled_id in scheduled_ids {
+        // Tweets published since are skipped.
+        if let Some(scheduled) = fetch_scheduled(session, scheduled_id).await? {
+            scheduled_tweets.push(scheduled);
+        }
+    }
+    scheduled_tweets.sort_by_key(|scheduled| scheduled.publish_at);
+    Ok(HttpResponse::Ok().json(scheduled_tweets))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Cancel a scheduled tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Cancelled"))
+)]
+#[delete("/tweets/scheduled/{scheduled_id}")]
+pub async fn cancel_scheduled_tweet(
+    db_pool: web::Data<DbPool>,
+    scheduled_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let scheduled_id = parse_id(&scheduled_id, "scheduled_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let scheduled = match fetch_scheduled(session, scheduled_id).await? {
+        Some(scheduled) if scheduled.user_id == user_id => scheduled,
+        _ => return Err(ApiError::NotFound("scheduled tweet")),
+    };
+    if scheduled.status == ScheduleStatus::Publishing {
+        return Err(ApiError::Conflict("the tweet is being published"));
+    }
+
+    // Conditional, so that a tweet the scheduler claims in the meantime
+    // is published rather than half cancelled.
+    let result = session
+        .query(
+            "DELETE FROM twitter_clone.scheduled_tweets WHERE scheduled_id = ? IF status = ?",
+            (scheduled_id, scheduled.status.as_str()),
+        )
+        .await?;
+    if !lwt_applied(&result) {
+        return Err(ApiError::Conflict("the tweet is being published"));
+    }
+
+    delete_schedule_rows(session, &scheduled).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Save a draft",
+    params(UserIdQuery),
+    responses((status = 200, description = "The new draft", body = Draft))
+)]
+#[post("/drafts")]
+pub async fn create_draft(
+    db_pool: web::Data<DbPool>,
+    request: web::Json<CreateTweetRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let content =
+        validation::tweet_content(&request.content, true).map_err(invalid_field("content"))?;
+    let now = Utc::now();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let count = count_rows(
+        session,
+        "SELECT COUNT(*) FROM twitter_clone.drafts WHERE user_id = ?",
+        user_id,
+    )
+    .await?;
+    if count >= MAX_DRAFTS_PER_USER as i64 {
+        return Err(ApiError::Validation(format!(
+            "at most {} drafts can be saved",
+            MAX_DRAFTS_PER_USER
+        )));
+    }
+
+    let draft = Draft {
+        draft_id: Uuid::new_v4(),
+        content,
+        media_ids: request.media_ids.clone(),
+        poll: request.poll.clone(),
+        created_at: now,
+        updated_at: now,
+    };
+    session
+        .query(
+            "INSERT INTO twitter_clone.drafts (user_id, draft_id, content, media_ids, poll, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (
+                user_id,
+                draft.draft_id,
+                &draft.content,
+                &draft.media_ids,
+                draft.poll.as_ref().and_then(|poll| serde_json::to_string(poll).ok()),
+                CqlTimestamp(now.timestamp_millis()),
+                CqlTimestamp(now.timestamp_millis()),
+            ),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "List your drafts",
+    params(UserIdQuery),
+    responses((status = 200, description = "Drafts, most recently edited first", body = Vec<Draft>))
+)]
+#[get("/drafts")]
+pub async fn get_drafts(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result = session
+        .query(
+            "SELECT draft_id, content, media_ids, poll, created_at, updated_at FROM twitter_clone.drafts WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+
+    let mut drafts: Vec<Draft> = result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(draft_from_row)
+        .collect();
+    drafts.sort_by_key(|draft| Reverse(draft.updated_at));
+    Ok(HttpResponse::Ok().json(drafts))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Get a draft",
+    params(UserIdQuery),
+    responses((status = 200, description = "The draft", body = Draft))
+)]
+#[get("/drafts/{draft_id}")]
+pub async fn get_draft(
+    db_pool: web::Data<DbPool>,
+    draft_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let draft_id = parse_id(&draft_id, "draft_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let draft = fetch_draft(session, user_id, draft_id)
+        .await?
+        .ok_or(ApiError::NotFound("draft"))?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Replace a draft",
+    params(UserIdQuery),
+    responses((status = 200, description = "The updated draft", body = Draft))
+)]
+#[put("/drafts/{draft_id}")]
+pub async fn update_draft(
+    db_pool: web::Data<DbPool>,
+    draft_id: web::Path<String>,
+    request: web::Json<CreateTweetRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let draft_id = parse_id(&draft_id, "draft_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let content =
+        validation::tweet_content(&request.content, true).map_err(invalid_field("content"))?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    // Conditional so that updating a deleted draft doesn't recreate it.
+    let result = session
+        .query(
+            "UPDATE twitter_clone.drafts SET content = ?, media_ids = ?, poll = ?, updated_at = ? WHERE user_id = ? AND draft_id = ? IF EXISTS",
+            (
+                &content,
+                &request.media_ids,
+                request.poll.as_ref().and_then(|poll| serde_json::to_string(poll).ok()),
+                CqlTimestamp(Utc::now().timestamp_millis()),
+                user_id,
+                draft_id,
+            ),
+        )
+        .await?;
+    if !lwt_applied(&result) {
+        return Err(ApiError::NotFound("draft"));
+    }
+
+    let draft = fetch_draft(session, user_id, draft_id)
+        .await?
+        .ok_or(ApiError::NotFound("draft"))?;
+    Ok(HttpResponse::Ok().json(draft))
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Delete a draft",
+    params(UserIdQuery),
+    responses((status = 200, description = "Deleted"))
+)]
+#[delete("/drafts/{draft_id}")]
+pub async fn delete_draft(
+    db_pool: web::Data<DbPool>,
+    draft_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let draft_id = parse_id(&draft_id, "draft_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.drafts WHERE user_id = ? AND draft_id = ?",
+            (user_id, draft_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Scheduled Tweets and Drafts",
+    summary = "Publish a draft",
+    params(UserIdQuery),
+    responses((status = 200, description = "The published tweet", body = Tweet))
+)]
+#[post("/drafts/{draft_id}/publish")]
+pub async fn publish_draft(
+    db_pool: web::Data<DbPool>,
+    sinks: web::Data<TweetSinks>,
+    draft_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let draft_id = parse_id(&draft_id, "draft_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let draft = fetch_draft(session, user_id, draft_id)
+        .await?
+        .ok_or(ApiError::NotFound("draft"))?;
+    let request = CreateTweetRequest {
+        content: draft.content,
+        media_ids: draft.media_ids,
+        poll: draft.poll,
+    };
+
+    let tweet = publish_tweet(
+        session,
+        &sinks,
+        user_id,
+        Uuid::new_v4(),
+        None,
+        &request,
+        Utc::now(),
+    )
+    .await?;
+    if let Err(e) = session
+        .query(
+            "DELETE FROM twitter_clone.drafts WHERE user_id = ? AND draft_id = ?",
+            (user_id, draft_id),
+        )
+        .await
+    {
+        // The tweet is out; a leftover draft is only clutter.
+        error!("Failed to delete published draft {}: {:?}", draft_id, e);
+    }
+    Ok(HttpResponse::Ok().json(tweet))
+}
+
+/// Checks what `publish_tweet` would reject, so a scheduled tweet fails
+/// when it is scheduled rather than when it falls due.
+async fn check_publishable(
+    session: &Session,
+    user_id: Uuid,
+    tweet_data: &CreateTweetRequest,
+    publish_at: DateTime<Utc>,
+) -> Result<String, ApiError> {
+    let content = tweet_content(tweet_data)?;
+    if let Some(poll) = &tweet_data.poll {
+        if !tweet_data.media_ids.is_empty() {
+            return Err(ApiError::Validation(
+                "a tweet can't have both media and a poll".to_string(),
+            ));
+        }
+        new_poll(poll, publish_at)?;
+    }
+    resolve_media(session, user_id, &tweet_data.media_ids).await?;
+    Ok(content)
+}
+
+async fn fetch_draft(
+    session: &Session,
+    user_id: Uuid,
+    draft_id: Uuid,
+) -> Result<Option<Draft>, QueryError> {
+    let result = session
+        .query(
+            "SELECT draft_id, content, media_ids, poll, created_at, updated_at FROM twitter_clone.drafts WHERE user_id = ? AND draft_id = ?",
+            (user_id, draft_id),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(draft_from_row))
+}
+
+/// Builds a `Draft` from a row selected as
+/// `draft_id, content, media_ids, poll, created_at, updated_at`.
+fn draft_from_row(row: Row) -> Option<Draft> {
+    let mut columns = row.columns.into_iter();
+    match (
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+    ) {
+        (
+            Some(CqlValue::Uuid(draft_id)),
+            content,
+            media_ids,
+            poll,
+            Some(CqlValue::Timestamp(created_at)),
+            Some(CqlValue::Timestamp(updated_at)),
+        ) => Some(Draft {
+            draft_id,
+            content: match content {
+                Some(CqlValue::Text(content)) => content,
+                _ => String::new(),
+            },
+            media_ids: uuid_list(media_ids),
+            poll: match poll {
+                Some(CqlValue::Text(poll)) => serde_json::from_str(&poll).ok(),
+                _ => None,
+            },
+            created_at: datetime_from_cql(created_at),
+            updated_at: datetime_from_cql(updated_at),
+        }),
+        _ => None,
     }
 }
 
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Like a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Liked"))
+)]
 #[post("/tweets/{tweet_id}/like")]
 pub async fn like_tweet(
     db_pool: web::Data<DbPool>,
+    notifications: web::Data<NotificationSender>,
     tweet_id: web::Path<String>,
     query: web::Query<UserIdQuery>,
-) -> HttpResponse {
-    let tweet_id = match Uuid::parse_str(&tweet_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
-    let user_id = match Uuid::parse_str(&query.user_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
     let now = Utc::now();
     let cql_timestamp = CqlTimestamp(now.timestamp_millis());
 
     let pool = db_pool.lock().await;
 
-    if let Some(session) = pool.get(0) {
-        let result = session
-            .query(
-                "INSERT INTO twitter_clone.likes (tweet_id, user_id, created_at) VALUES (?, ?, ?)",
-                (tweet_id, user_id, cql_timestamp),
-            )
-            .await;
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    // Conditional so that liking a tweet twice only notifies its author once.
+    let result = session
+        .query(
+            "INSERT INTO twitter_clone.likes (tweet_id, user_id, created_at) VALUES (?, ?, ?) IF NOT EXISTS",
+            (tweet_id, user_id, cql_timestamp),
+        )
+        .await?;
 
-        match result {
-            Ok(_) => HttpResponse::Ok().finish(),
-            Err(_) => HttpResponse::InternalServerError().finish(),
-        }
-    } else {
-        HttpResponse::InternalServerError().body("Internal error")
+    if lwt_applied(&result) {
+        notifications.send(NotificationEvent::Liked {
+            actor_id: user_id,
+            tweet_id,
+            created_at: now,
+        });
     }
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[get("/feed")]
-pub async fn get_home_feed(
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Retweet a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Retweeted"))
+)]
+#[post("/tweets/{tweet_id}/retweet")]
+pub async fn retweet_tweet(
     db_pool: web::Data<DbPool>,
+    notifications: web::Data<NotificationSender>,
+    tweet_id: web::Path<String>,
     query: web::Query<UserIdQuery>,
-) -> HttpResponse {
-    let user_id = match Uuid::parse_str(&query.user_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let now = Utc::now();
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
 
     let pool = db_pool.lock().await;
 
-    if let Some(session) = pool.get(0) {
-        let result: Result<QueryResult, QueryError> = session
-            .query(
-                "SELECT tweet_id FROM twitter_clone.user_timeline WHERE user_id = ? LIMIT 20",
-                (user_id,),
-            )
-            .await;
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    if fetch_tweet(session, tweet_id).await?.is_none() {
+        return Err(ApiError::NotFound("tweet"));
+    }
+    // Conditional so that retweeting twice only notifies the author once.
+    let result = session
+        .query(
+            "INSERT INTO twitter_clone.retweets (tweet_id, user_id, created_at) VALUES (?, ?, ?) IF NOT EXISTS",
+            (tweet_id, user_id, cql_timestamp),
+        )
+        .await?;
 
-        match result {
-            Ok(rows) => {
-                let tweet_ids: Vec<Uuid> = rows
-                    .rows
-                    .unwrap_or_default()
-                    .into_iter()
-                    .filter_map(|row| match row.columns[0].as_ref() {
-                        Some(CqlValue::Uuid(uuid)) => Some(*uuid),
-                        _ => None,
-                    })
-                    .collect();
-
-                let mut tweets = Vec::new();
-                for tweet_id in tweet_ids {
-                    if let Ok(result) = session
-                        .query(
-                            "SELECT tweet_id, user_id, content, created_at FROM twitter_clone.tweets WHERE tweet_id = ?",
-                            (tweet_id,),
-                        )
-                        .await
-                    {
-                        if let Some(rows) = result.rows {
-                            for row in rows {
-                                let tweet = match (
-                                    row.columns[0].as_ref().and_then(|v| match v {
-                                        CqlValue::Uuid(uuid) => Some(*uuid),
-                                        _ => None,
-                                    }),
-                                    row.columns[1].as_ref().and_then(|v| match v {
-                                        CqlValue::Uuid(uuid) => Some(*uuid),
-                                        _ => None,
-                                    }),
-                                    row.columns[2].as_ref().and_then(|v| match v {
-                                        CqlValue::Text(text) => Some(text.clone()),
-                                        _ => None,
-                                    }),
-                                    row.columns[3].as_ref().and_then(|v| match v {
-                                        CqlValue::Timestamp(ts) => Some(*ts),
-                                        _ => None,
-                                    }),
-                                ) {
-                                    (Some(tweet_id), Some(user_id), Some(content), Some(timestamp)) => {
-                                        let timestamp_millis = timestamp.0; // Extract the inner i64 value from CqlTimestamp
-                                        let seconds = timestamp_millis / 1000;
-                                        let nanos = ((timestamp_millis % 1000) * 1_000_000) as u32;
-                                        let created_at = DateTime::<Utc>::from_timestamp(
-                                            seconds,
-                                            nanos
-                                        ).unwrap_or_default();
-                                        Some(Tweet {
-                                            tweet_id,
-                                            user_id,
-                                            content,
-                                            created_at,
-                                        })
-                                    }
-                                    _ => None,
-                                };
-                                if let Some(tweet) = tweet {
-                                    tweets.push(tweet);
-                                }
-                            }
-                        }
-                    }
-                }
-                HttpResponse::Ok().json(tweets)
-            }
-            Err(_) => HttpResponse::InternalServerError().finish(),
-        }
-    } else {
-        HttpResponse::InternalServerError().body("Internal error")
+    if lwt_applied(&result) {
+        notifications.send(NotificationEvent::Retweeted {
+            actor_id: user_id,
+            tweet_id,
+            created_at: now,
+        });
     }
+    Ok(HttpResponse::Ok().finish())
 }
 
-#[get("/users/{user_id}/tweets")]
-pub async fn get_user_tweets(
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Undo a retweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "No longer retweeted"))
+)]
+#[delete("/tweets/{tweet_id}/retweet")]
+pub async fn undo_retweet(
     db_pool: web::Data<DbPool>,
-    user_id: web::Path<String>,
-) -> HttpResponse {
-    let user_id = match Uuid::parse_str(&user_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+    tweet_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.retweets WHERE tweet_id = ? AND user_id = ? IF EXISTS",
+            (tweet_id, user_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    info!("Fetching tweets for user: {}", user_id);
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Vote in a tweet's poll",
+    params(UserIdQuery),
+    responses((status = 200, description = "The poll with the vote counted", body = Poll))
+)]
+#[post("/tweets/{tweet_id}/poll/votes")]
+pub async fn vote_in_poll(
+    db_pool: web::Data<DbPool>,
+    tweet_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+    vote: web::Json<VoteRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let now = Utc::now();
 
     let pool = db_pool.lock().await;
 
-    if let Some(session) = pool.get(0) {
-        let result = session
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let (author_id, mut poll) = match fetch_tweet(session, tweet_id).await? {
+        Some(Tweet {
+            user_id: author_id,
+            poll: Some(poll),
+            ..
+        }) => (author_id, poll),
+        _ => return Err(ApiError::NotFound("poll")),
+    };
+    if vote.option >= poll.options.len() {
+        return Err(ApiError::Validation(format!(
+            "option must be less than {}",
+            poll.options.len()
+        )));
+    }
+    if poll.ends_at <= now {
+        return Err(ApiError::Forbidden("the poll has closed"));
+    }
+
+    // Conditional, so that each user's first vote is the only one counted.
+    let result = session
         .query(
-            "SELECT tweet_id, user_id, content, created_at FROM twitter_clone.tweets WHERE user_id = ? ALLOW FILTERING",
-            (user_id,),
+            "INSERT INTO twitter_clone.poll_votes (tweet_id, user_id, option_index, created_at) VALUES (?, ?, ?, ?) IF NOT EXISTS",
+            (tweet_id, user_id, vote.option as i32, CqlTimestamp(now.timestamp_millis())),
         )
-        .await;
-        match result {
-            Ok(rows) => {
-                let mut tweets = Vec::new();
-                if let Some(rows) = rows.rows {
-                    for row in rows {
-                        let tweet = match (
-                            row.columns[0].as_ref().and_then(|v| match v {
-                                CqlValue::Uuid(uuid) => Some(*uuid),
-                                _ => None,
-                            }),
-                            row.columns[1].as_ref().and_then(|v| match v {
-                                CqlValue::Uuid(uuid) => Some(*uuid),
-                                _ => None,
-                            }),
-                            row.columns[2].as_ref().and_then(|v| match v {
-                                CqlValue::Text(text) => Some(text.clone()),
-                                _ => None,
-                            }),
-                            row.columns[3].as_ref().and_then(|v| match v {
-                                CqlValue::Timestamp(ts) => Some(*ts),
-                                _ => None,
-                            }),
-                        ) {
-                            (Some(tweet_id), Some(user_id), Some(content), Some(timestamp)) => {
-                                let timestamp_millis = timestamp.0; // Extract the inner i64 value from CqlTimestamp
-                                let seconds = timestamp_millis / 1000;
-                                let nanos = ((timestamp_millis % 1000) * 1_000_000) as u32;
-                                let created_at = DateTime::<Utc>::from_timestamp(seconds, nanos)
-                                    .unwrap_or_default();
-                                Some(Tweet {
-                                    tweet_id,
-                                    user_id,
-                                    content,
-                                    created_at,
-                                })
-                            }
-                            _ => None,
-                        };
-
-                        if let Some(tweet) = tweet {
-                            tweets.push(tweet);
-                        }
-                    }
-                }
-                debug!("Found {} tweets for user {}", tweets.len(), user_id);
-                HttpResponse::Ok().json(tweets)
-            }
-            Err(e) => {
-                error!("Failed to fetch user tweets: {:?}", e);
-                HttpResponse::InternalServerError().finish()
+        .await?;
+    if !lwt_applied(&result) {
+        return Err(ApiError::Conflict("already voted in this poll"));
+    }
+
+    if let Err(e) = session
+        .query(
+            "UPDATE twitter_clone.poll_tallies SET votes = votes + 1 WHERE tweet_id = ? AND option_index = ?",
+            (tweet_id, vote.option as i32),
+        )
+        .await
+    {
+        // Counters can't be incremented idempotently, so the vote is only
+        // undone for a retry when the increment surely didn't happen.
+        // Otherwise it stands, and a retry is told it already voted.
+        if write_not_applied(&e) {
+            if let Err(e) = session
+                .query(
+                    "DELETE FROM twitter_clone.poll_votes WHERE tweet_id = ? AND user_id = ?",
+                    (tweet_id, user_id),
+                )
+                .await
+            {
+                error!("Failed to roll back vote: {:?}", e);
             }
+        } else {
+            error!(
+                "Vote of {} in poll {} may not have been counted: {:?}",
+                user_id, tweet_id, e
+            );
         }
-    } else {
-        HttpResponse::InternalServerError().body("Internal error")
+        return Err(e.into());
     }
+
+    hydrate_poll(session, tweet_id, author_id, &mut poll, Some(user_id), now).await?;
+    Ok(HttpResponse::Ok().json(poll))
 }
 
-#[derive(serde::Deserialize)]
-pub struct UserIdQuery {
-    user_id: String,
+#[utoipa::path(
+    tag = "Bookmarks",
+    summary = "Bookmark a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Bookmarked"))
+)]
+#[post("/tweets/{tweet_id}/bookmark")]
+pub async fn bookmark_tweet(
+    db_pool: web::Data<DbPool>,
+    tweet_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let cql_timestamp = CqlTimestamp(Utc::now().timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    if fetch_tweet(session, tweet_id).await?.is_none() {
+        return Err(ApiError::NotFound("tweet"));
+    }
+
+    // Conditional so that bookmarking twice keeps the original position.
+    let result = session
+        .query(
+            "INSERT INTO twitter_clone.bookmarks_by_tweet (tweet_id, user_id, created_at) VALUES (?, ?, ?) IF NOT EXISTS",
+            (tweet_id, user_id, cql_timestamp),
+        )
+        .await?;
+    if !lwt_applied(&result) {
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    if let Err(e) = session
+        .query(
+            "INSERT INTO twitter_clone.bookmarks_by_user (user_id, created_at, tweet_id) VALUES (?, ?, ?)",
+            (user_id, cql_timestamp, tweet_id),
+        )
+        .await
+    {
+        // Undo the claim so that a retry can succeed.
+        if let Err(e) = session
+            .query(
+                "DELETE FROM twitter_clone.bookmarks_by_tweet WHERE tweet_id = ? AND user_id = ?",
+                (tweet_id, user_id),
+            )
+            .await
+        {
+            error!("Failed to roll back bookmark: {:?}", e);
+        }
+        return Err(e.into());
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Bookmarks",
+    summary = "Remove a bookmark",
+    params(UserIdQuery),
+    responses((status = 200, description = "Removed"))
+)]
+#[delete("/tweets/{tweet_id}/bookmark")]
+pub async fn remove_bookmark(
+    db_pool: web::Data<DbPool>,
+    tweet_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result = session
+        .query(
+            "SELECT created_at FROM twitter_clone.bookmarks_by_tweet WHERE tweet_id = ? AND user_id = ?",
+            (tweet_id, user_id),
+        )
+        .await?;
+    let created_at = result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        match row.columns.into_iter().next().flatten() {
+            Some(CqlValue::Timestamp(created_at)) => Some(created_at),
+            _ => None,
+        }
+    });
+    // Not bookmarked; removing is idempotent.
+    let created_at = match created_at {
+        Some(created_at) => created_at,
+        None => return Ok(HttpResponse::Ok().finish()),
+    };
+
+    delete_bookmark_rows(session, tweet_id, user_id, created_at).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Feed",
+    summary = "Get your home feed",
+    params(UserIdQuery),
+    responses((status = 200, description = "Tweets in your home feed", body = Vec<Tweet>))
+)]
+#[get("/feed")]
+pub async fn get_home_feed(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result: QueryResult = session
+        .query(
+            "SELECT tweet_id FROM twitter_clone.user_timeline WHERE user_id = ? LIMIT 20",
+            (user_id,),
+        )
+        .await?;
+
+    let tweet_ids: Vec<Uuid> = result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|row| match row.columns[0].as_ref() {
+            Some(CqlValue::Uuid(uuid)) => Some(*uuid),
+            _ => None,
+        })
+        .collect();
+
+    let tweets = fetch_tweets(session, &tweet_ids, Some(user_id)).await;
+    Ok(HttpResponse::Ok().json(tweets))
+}
+
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "List a user's tweets",
+    responses(
+        (status = 200, description = "The user's tweets, pinned tweet first", body = Vec<Tweet>),
+    )
+)]
+#[get("/users/{user_id}/tweets")]
+pub async fn get_user_tweets(
+    db_pool: web::Data<DbPool>,
+    user_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&user_id, "user_id")?;
+
+    debug!("Fetching tweets for user: {}", user_id);
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result = session
+        .query(
+            "SELECT tweet_id, user_id, content, created_at, mentions, media, poll, in_reply_to_tweet_id FROM twitter_clone.tweets WHERE user_id = ? ALLOW FILTERING",
+            (user_id,),
+        )
+        .await?;
+    let mut tweets: Vec<Tweet> = result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(tweet_from_row)
+        .collect();
+    match fetch_pinned_tweet(session, user_id).await {
+        Ok(Some(mut pinned)) => {
+            tweets.retain(|tweet| tweet.tweet_id != pinned.tweet_id);
+            pinned.pinned = true;
+            tweets.insert(0, pinned);
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to fetch pinned tweet: {:?}", e),
+    }
+    if let Err(e) = hydrate_polls(session, &mut tweets, None).await {
+        error!("Failed to fetch poll results: {:?}", e);
+    }
+    debug!("Found {} tweets for user {}", tweets.len(), user_id);
+    Ok(HttpResponse::Ok().json(tweets))
+}
+
+#[utoipa::path(
+    tag = "Hashtags",
+    summary = "List tweets with a hashtag",
+    params(PageQuery),
+    responses(
+        (status = 200, description = "Tweets with the hashtag, newest first", body = TweetPage),
+    )
+)]
+#[get("/hashtags/{tag}/tweets")]
+pub async fn get_hashtag_tweets(
+    db_pool: web::Data<DbPool>,
+    tag: web::Path<String>,
+    query: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tag = normalize_hashtag(&tag)
+        .ok_or_else(|| ApiError::Validation("tag is not a valid hashtag".to_string()))?;
+    let limit = query.limit();
+    let mut cursor = query.cursor()?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let mut entries = Vec::new();
+    let mut bucket = hashtag_bucket(cursor.created_at);
+    let oldest_bucket = bucket - HASHTAG_MAX_BUCKETS_SCANNED;
+
+    while entries.len() < limit && bucket > oldest_bucket {
+        let rows = fetch_page_rows(
+            session,
+            "SELECT tweet_id, created_at FROM twitter_clone.tweets_by_hashtag WHERE hashtag = ? AND bucket = ?",
+            "tweet_id",
+            vec![CqlValue::Text(tag.clone()), CqlValue::Int(bucket)],
+            cursor,
+            limit - entries.len(),
+        )
+        .await?;
+        entries.extend(rows.into_iter().filter_map(timeline_entry_from_row));
+        // Older buckets are read whole, up to the cursor's time.
+        cursor.id = None;
+        bucket -= 1;
+    }
+
+    let next_cursor = next_page_cursor(&entries, limit);
+    let tweet_ids: Vec<Uuid> = entries.iter().map(|(tweet_id, _)| *tweet_id).collect();
+    let tweets = fetch_tweets(session, &tweet_ids, None).await;
+    Ok(HttpResponse::Ok().json(TweetPage {
+        tweets,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "List tweets mentioning you",
+    params(UserIdQuery, PageQuery),
+    responses((status = 200, description = "Tweets mentioning you, newest first", body = TweetPage))
+)]
+#[get("/users/me/mentions")]
+pub async fn get_my_mentions(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let limit = page.limit();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let rows = fetch_page_rows(
+        session,
+        "SELECT tweet_id, created_at FROM twitter_clone.mentions_by_user WHERE user_id = ?",
+        "tweet_id",
+        vec![CqlValue::Uuid(user_id)],
+        page.cursor()?,
+        limit,
+    )
+    .await?;
+
+    let entries: Vec<(Uuid, i64)> = rows
+        .into_iter()
+        .filter_map(timeline_entry_from_row)
+        .collect();
+    let next_cursor = next_page_cursor(&entries, limit);
+    let tweet_ids: Vec<Uuid> = entries.iter().map(|(tweet_id, _)| *tweet_id).collect();
+    let tweets = fetch_tweets(session, &tweet_ids, Some(user_id)).await;
+    Ok(HttpResponse::Ok().json(TweetPage {
+        tweets,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Bookmarks",
+    summary = "List your bookmarks",
+    params(UserIdQuery, PageQuery),
+    responses(
+        (
+            status = 200,
+            description = "Bookmarked tweets, most recently bookmarked first",
+            body = TweetPage
+        ),
+    )
+)]
+#[get("/users/me/bookmarks")]
+pub async fn get_my_bookmarks(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let limit = page.limit();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let rows = fetch_page_rows(
+        session,
+        "SELECT tweet_id, created_at FROM twitter_clone.bookmarks_by_user WHERE user_id = ?",
+        "tweet_id",
+        vec![CqlValue::Uuid(user_id)],
+        page.cursor()?,
+        limit,
+    )
+    .await?;
+
+    let entries: Vec<(Uuid, i64)> = rows
+        .into_iter()
+        .filter_map(timeline_entry_from_row)
+        .collect();
+    let next_cursor = next_page_cursor(&entries, limit);
+    let tweet_ids: Vec<Uuid> = entries.iter().map(|(tweet_id, _)| *tweet_id).collect();
+    let tweets = fetch_tweets(session, &tweet_ids, Some(user_id)).await;
+    Ok(HttpResponse::Ok().json(TweetPage {
+        tweets,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Notifications",
+    summary = "List your notifications",
+    params(UserIdQuery, PageQuery),
+    responses(
+        (
+            status = 200,
+            description = "Notifications, grouped and newest first",
+            body = NotificationPage
+        ),
+    )
+)]
+#[get("/notifications")]
+pub async fn get_notifications(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let limit = page.limit();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let last_read_at = fetch_notifications_read_at(session, user_id).await?;
+    let unread_count = count_unread_notifications(session, user_id, last_read_at).await?;
+
+    let rows = fetch_page_rows(
+        session,
+        "SELECT notification_id, kind, actor_id, tweet_id, created_at FROM twitter_clone.notifications WHERE user_id = ?",
+        "notification_id",
+        vec![CqlValue::Uuid(user_id)],
+        page.cursor()?,
+        limit,
+    )
+    .await?;
+
+    let fetched = rows.len();
+    let stored: Vec<StoredNotification> =
+        rows.into_iter().filter_map(notification_from_row).collect();
+    let next_cursor = match stored.last() {
+        Some(last) if fetched == limit => Some(page_cursor(
+            last.created_at.timestamp_millis(),
+            last.notification_id,
+        )),
+        _ => None,
+    };
+
+    let mut notifications = group_notifications(stored, datetime_from_cql(last_read_at));
+    for group in &mut notifications {
+        let first_actor = match fetch_username(session, group.actor_ids[0]).await {
+            Some(username) => username,
+            None => "Someone".to_string(),
+        };
+        if let Some(kind) = NotificationKind::parse(&group.kind) {
+            group.message = describe(kind, &first_actor, group.actor_ids.len());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(NotificationPage {
+        notifications,
+        unread_count,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Notifications",
+    summary = "Count your unread notifications",
+    params(UserIdQuery),
+    responses((status = 200, description = "The unread count", body = UnreadCount))
+)]
+#[get("/notifications/unread_count")]
+pub async fn get_unread_notification_count(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let last_read_at = fetch_notifications_read_at(session, user_id).await?;
+    let unread_count = count_unread_notifications(session, user_id, last_read_at).await?;
+    Ok(HttpResponse::Ok().json(UnreadCount { unread_count }))
+}
+
+#[utoipa::path(
+    tag = "Notifications",
+    summary = "Mark notifications read",
+    params(UserIdQuery),
+    responses((status = 200, description = "Marked read"))
+)]
+#[post("/notifications/read")]
+pub async fn mark_notifications_read(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    request: Option<web::Json<MarkReadRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let up_to = request
+        .and_then(|request| request.up_to)
+        .unwrap_or_else(|| Utc::now().timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    // The marker only ever moves forward, so a stale client can't
+    // resurrect notifications that were already read.
+    let last_read_at = fetch_notifications_read_at(session, user_id).await?;
+    if up_to <= last_read_at.0 {
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.notification_read_markers (user_id, last_read_at) VALUES (?, ?)",
+            (user_id, CqlTimestamp(up_to)),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn fetch_notifications_read_at(
+    session: &Session,
+    user_id: Uuid,
+) -> Result<CqlTimestamp, QueryError> {
+    let result = session
+        .query(
+            "SELECT last_read_at FROM twitter_clone.notification_read_markers WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| match row.columns.first() {
+            Some(Some(CqlValue::Timestamp(timestamp))) => Some(*timestamp),
+            _ => None,
+        })
+        .unwrap_or(CqlTimestamp(0)))
+}
+
+/// Counts unread notification groups, as listed by `get_notifications`, so the
+/// badge matches what the user sees. Only the newest `MAX_UNREAD_COUNTED`
+/// unread rows are grouped.
+async fn count_unread_notifications(
+    session: &Session,
+    user_id: Uuid,
+    last_read_at: CqlTimestamp,
+) -> Result<i64, QueryError> {
+    let result = session
+        .query(
+            "SELECT notification_id, kind, actor_id, tweet_id, created_at FROM twitter_clone.notifications WHERE user_id = ? AND created_at > ? LIMIT ?",
+            (user_id, last_read_at, MAX_UNREAD_COUNTED),
+        )
+        .await?;
+    let stored: Vec<StoredNotification> = result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(notification_from_row)
+        .collect();
+    Ok(group_notifications(stored, datetime_from_cql(last_read_at)).len() as i64)
+}
+
+/// Reads a `notification_id, kind, actor_id, tweet_id, created_at` row of
+/// `notifications`.
+fn notification_from_row(row: Row) -> Option<StoredNotification> {
+    let mut columns = row.columns.into_iter();
+    match (
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+    ) {
+        (
+            Some(CqlValue::Uuid(notification_id)),
+            Some(CqlValue::Text(kind)),
+            Some(CqlValue::Uuid(actor_id)),
+            tweet_id,
+            Some(CqlValue::Timestamp(timestamp)),
+        ) => Some(StoredNotification {
+            notification_id,
+            kind: NotificationKind::parse(&kind)?,
+            actor_id,
+            tweet_id: match tweet_id {
+                Some(CqlValue::Uuid(tweet_id)) => Some(tweet_id),
+                _ => None,
+            },
+            created_at: datetime_from_cql(timestamp),
+        }),
+        _ => None,
+    }
+}
+
+async fn fetch_username(session: &Session, user_id: Uuid) -> Option<String> {
+    let result = session
+        .query(
+            "SELECT username FROM twitter_clone.users WHERE user_id = ?",
+            (user_id,),
+        )
+        .await;
+    match result {
+        Ok(rows) => rows.rows.unwrap_or_default().into_iter().find_map(|row| {
+            match row.columns.into_iter().next().flatten() {
+                Some(CqlValue::Text(username)) => Some(username),
+                _ => None,
+            }
+        }),
+        Err(e) => {
+            error!("Failed to fetch username for {}: {:?}", user_id, e);
+            None
+        }
+    }
+}
+
+fn clean_display_name(display_name: Option<&str>) -> Result<Option<String>, ApiError> {
+    validation::display_name(display_name).map_err(invalid_field("display_name"))
+}
+
+/// The tweet's text, normalized. Tweets with media may have none.
+fn tweet_content(tweet_data: &CreateTweetRequest) -> Result<String, ApiError> {
+    validation::tweet_content(&tweet_data.content, !tweet_data.media_ids.is_empty())
+        .map_err(invalid_field("content"))
+}
+
+/// Reports a single invalid field of a request body.
+fn invalid_field(field: &'static str) -> impl FnOnce(Invalid) -> ApiError {
+    move |invalid| ApiError::InvalidFields(vec![invalid.on(field)])
+}
+
+async fn adjust_follower_count(
+    session: &Session,
+    user_id: Uuid,
+    delta: i64,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "UPDATE twitter_clone.user_stats SET follower_count = follower_count + ? WHERE user_id = ?",
+            (Counter(delta), user_id),
+        )
+        .await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "Media",
+    summary = "Upload an image or video",
+    params(UserIdQuery),
+    request_body(content = inline(MediaUpload), content_type = "multipart/form-data"),
+    responses((status = 200, description = "The uploaded media", body = Media))
+)]
+#[post("/media")]
+pub async fn upload_media(
+    db_pool: web::Data<DbPool>,
+    blob_store: web::Data<dyn BlobStore>,
+    processor: web::Data<MediaProcessor>,
+    mut payload: Multipart,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let mut file = None;
+    let mut alt_text = None;
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(invalid_multipart)?;
+        let name = field.name().map(str::to_string);
+        let limit = match name.as_deref() {
+            Some("file") => MAX_UPLOAD_BYTES,
+            // UTF-8 takes at most 4 bytes per character.
+            Some("alt_text") => MAX_ALT_TEXT_CHARS * 4,
+            _ => 0,
+        };
+        let data = read_field(&mut field, limit).await?;
+        match name.as_deref() {
+            Some("file") => file = Some(data.freeze()),
+            Some("alt_text") => match String::from_utf8(data.to_vec()) {
+                Ok(text) => alt_text = Some(text),
+                Err(_) => {
+                    return Err(ApiError::Validation(
+                        "alt_text is not valid UTF-8".to_string(),
+                    ))
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let file = file.ok_or_else(|| ApiError::Validation("file is missing".to_string()))?;
+    let kind = MediaKind::sniff(&file).ok_or_else(|| {
+        ApiError::UnsupportedMediaType("file is not a supported image or video".to_string())
+    })?;
+    if file.len() > kind.max_bytes() {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "{} files are at most {} bytes",
+            kind.content_type(),
+            kind.max_bytes()
+        )));
+    }
+    let alt_text = match alt_text.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(text) if text.chars().count() > MAX_ALT_TEXT_CHARS => {
+            return Err(ApiError::Validation(format!(
+                "alt_text is longer than {} characters",
+                MAX_ALT_TEXT_CHARS
+            )))
+        }
+        Some(text) => Some(text.to_string()),
+    };
+
+    let content_hash = content_hash(&file);
+    let existing = {
+        let pool = db_pool.lock().await;
+        let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+        fetch_media_blob(session, &content_hash).await?
+    };
+    let blob = match existing {
+        // Uploaded before; the stored files are reused.
+        Some(blob) => blob,
+        None => {
+            store_media_blob(
+                &db_pool,
+                blob_store.get_ref(),
+                &processor,
+                kind,
+                file,
+                &content_hash,
+            )
+            .await?
+        }
+    };
+
+    let media_id = Uuid::new_v4();
+    let media = blob.to_media(media_id, alt_text);
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    session
+        .query(
+            "INSERT INTO twitter_clone.media (media_id, user_id, content_hash, alt_text, content_type, size_bytes, width, height, blurhash, variants, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                media_id,
+                user_id,
+                &content_hash,
+                &media.alt_text,
+                &blob.content_type,
+                blob.size_bytes as i64,
+                blob.width.map(|width| width as i32),
+                blob.height.map(|height| height as i32),
+                &blob.blurhash,
+                serde_json::to_string(&blob.variants).unwrap_or_default(),
+                CqlTimestamp(Utc::now().timestamp_millis()),
+            ),
+        )
+        .await?;
+
+    info!("Media uploaded: {} ({})", media_id, media.content_type);
+    Ok(HttpResponse::Ok().json(media))
+}
+
+#[utoipa::path(
+    tag = "Media",
+    summary = "Download media",
+    responses(
+        (
+            status = 200,
+            description = "The file, with its content type",
+            content_type = "application/octet-stream"
+        ),
+    )
+)]
+#[get("/media/{media_id}")]
+pub async fn get_media(
+    db_pool: web::Data<DbPool>,
+    blob_store: web::Data<dyn BlobStore>,
+    media_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    serve_media(&db_pool, blob_store.get_ref(), &media_id, None).await
+}
+
+#[utoipa::path(
+    tag = "Media",
+    summary = "Download a smaller copy of an image",
+    responses(
+        (
+            status = 200,
+            description = "The file, with its content type",
+            content_type = "application/octet-stream"
+        ),
+    )
+)]
+#[get("/media/{media_id}/{variant}")]
+pub async fn get_media_variant(
+    db_pool: web::Data<DbPool>,
+    blob_store: web::Data<dyn BlobStore>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (media_id, variant) = path.into_inner();
+    serve_media(&db_pool, blob_store.get_ref(), &media_id, Some(&variant)).await
+}
+
+#[utoipa::path(
+    tag = "Tweets",
+    summary = "Delete a tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Deleted"))
+)]
+#[delete("/tweets/{tweet_id}")]
+pub async fn delete_tweet(
+    db_pool: web::Data<DbPool>,
+    search_index: web::Data<SearchIndex>,
+    tweet_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let tweet_id = parse_id(&tweet_id, "tweet_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let tweet = fetch_tweet(session, tweet_id)
+        .await?
+        .ok_or(ApiError::NotFound("tweet"))?;
+    if tweet.user_id != user_id {
+        return Err(ApiError::Forbidden("only the author can delete a tweet"));
+    }
+
+    delete_tweet_rows(session, &tweet).await?;
+    search_index.remove_tweet(tweet_id);
+    info!("Tweet deleted: {}", tweet_id);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Search",
+    summary = "Search tweets",
+    params(SearchTweetsQuery),
+    responses((status = 200, description = "Matching tweets", body = TweetSearchResults))
+)]
+#[get("/search/tweets")]
+pub async fn search_tweets(
+    db_pool: web::Data<DbPool>,
+    search_index: web::Data<SearchIndex>,
+    query: web::Query<SearchTweetsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let search = SearchQuery::parse(&query.q).map_err(ApiError::Validation)?;
+    if search.is_empty() {
+        return Err(ApiError::Validation("q has no search terms".to_string()));
+    }
+    let order = query.sort.unwrap_or(SearchOrder::Relevance);
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let author_id = match &search.from {
+        Some(username) => match fetch_user_id_by_username(session, username).await? {
+            Some(author_id) => Some(author_id),
+            None => {
+                return Ok(HttpResponse::Ok().json(TweetSearchResults {
+                    tweets: Vec::new(),
+                    next_offset: None,
+                }))
+            }
+        },
+        None => None,
+    };
+
+    let index = search_index.clone();
+    let tweet_ids = web::block(move || index.search(&search, author_id, order, offset, limit))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()))
+        .map_err(|e| ApiError::Internal(format!("Failed to search tweets: {}", e)))?;
+
+    let next_offset = (tweet_ids.len() == limit).then_some(offset + limit);
+    let tweets = fetch_tweets(session, &tweet_ids, None).await;
+    Ok(HttpResponse::Ok().json(TweetSearchResults {
+        tweets,
+        next_offset,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Trends",
+    summary = "Get trending hashtags",
+    params(TrendsQuery),
+    responses(
+        (status = 200, description = "Trending hashtags, highest scoring first", body = TrendList),
+    )
+)]
+#[get("/trends")]
+pub async fn get_trends(
+    trends: web::Data<Trends>,
+    query: web::Query<TrendsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let window = query.window.unwrap_or(TrendWindow::Hour);
+    let limit = query.limit.unwrap_or(DEFAULT_TRENDS).clamp(1, MAX_TRENDS);
+
+    Ok(HttpResponse::Ok().json(TrendList {
+        window,
+        as_of: Utc::now(),
+        trends: trends.top(window, limit),
+    }))
+}
+
+/// Streams new feed items and notifications as Server-Sent Events. Clients
+/// resume after a disconnect by sending the standard `Last-Event-ID` header
+/// (or `last_event_id` for clients that can't set headers). Streams end
+/// when the server starts shutting down, so that it can stop.
+#[utoipa::path(
+    tag = "Streaming",
+    summary = "Stream feed items and notifications",
+    params(StreamQuery),
+    responses(
+        (
+            status = 200,
+            description = "Server-Sent Events",
+            body = String,
+            content_type = "text/event-stream"
+        ),
+    )
+)]
+#[get("/stream")]
+pub async fn stream_events(
+    broker: web::Data<Broker>,
+    shutdown: web::Data<Shutdown>,
+    req: HttpRequest,
+    query: web::Query<StreamQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or(query.last_event_id);
+
+    let receiver = broker.subscribe(user_id, last_event_id);
+    let heartbeat = tokio::time::interval(STREAM_HEARTBEAT_INTERVAL);
+    let shutdown = shutdown.get_ref().clone();
+    let body = futures::stream::unfold(
+        (receiver, heartbeat, shutdown),
+        |(mut receiver, mut heartbeat, shutdown)| async move {
+            let message = tokio::select! {
+                event = receiver.recv() => event?.to_sse(),
+                _ = heartbeat.tick() => ": heartbeat\n\n".to_string(),
+                _ = shutdown.stopping() => return None,
+            };
+            Some((
+                Ok::<_, Infallible>(web::Bytes::from(message)),
+                (receiver, heartbeat, shutdown),
+            ))
+        },
+    );
+
+    debug!("Opened event stream for user {}", user_id);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+#[utoipa::path(
+    tag = "Follows",
+    summary = "Follow a user",
+    params(UserIdQuery),
+    responses((status = 200, description = "Followed"))
+)]
+#[post("/users/{followed_id}/follow")]
+pub async fn follow_user(
+    db_pool: web::Data<DbPool>,
+    notifications: web::Data<NotificationSender>,
+    user_index: web::Data<UserIndex>,
+    followed_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let followed_id = parse_id(&followed_id, "followed_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    if followed_id == user_id {
+        return Err(ApiError::Validation(
+            "users can't follow themselves".to_string(),
+        ));
+    }
+    let now = Utc::now();
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    if !user_exists(session, followed_id).await? {
+        return Err(ApiError::NotFound("user"));
+    }
+
+    // Conditional so that following someone twice only notifies them once.
+    let result = session
+        .query(
+            "INSERT INTO twitter_clone.followers (user_id, follower_id, created_at) VALUES (?, ?, ?) IF NOT EXISTS",
+            (followed_id, user_id, cql_timestamp),
+        )
+        .await?;
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.following (user_id, followed_id, created_at) VALUES (?, ?, ?)",
+            (user_id, followed_id, cql_timestamp),
+        )
+        .await?;
+
+    if lwt_applied(&result) {
+        if let Err(e) = adjust_follower_count(session, followed_id, 1).await {
+            error!("Failed to update follower count: {:?}", e);
+        }
+        user_index.adjust_follower_count(followed_id, 1);
+        notifications.send(NotificationEvent::Followed {
+            actor_id: user_id,
+            followed_id,
+            created_at: now,
+        });
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Follows",
+    summary = "Unfollow a user",
+    params(UserIdQuery),
+    responses((status = 200, description = "Unfollowed"))
+)]
+#[delete("/users/{followed_id}/follow")]
+pub async fn unfollow_user(
+    db_pool: web::Data<DbPool>,
+    user_index: web::Data<UserIndex>,
+    followed_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let followed_id = parse_id(&followed_id, "followed_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result = session
+        .query(
+            "DELETE FROM twitter_clone.followers WHERE user_id = ? AND follower_id = ? IF EXISTS",
+            (followed_id, user_id),
+        )
+        .await?;
+
+    if lwt_applied(&result) {
+        if let Err(e) = adjust_follower_count(session, followed_id, -1).await {
+            error!("Failed to update follower count: {:?}", e);
+        }
+        user_index.adjust_follower_count(followed_id, -1);
+    }
+    session
+        .query(
+            "DELETE FROM twitter_clone.following WHERE user_id = ? AND followed_id = ?",
+            (user_id, followed_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Users",
+    summary = "Update your profile",
+    params(UserIdQuery),
+    responses((status = 200, description = "The updated profile", body = UpdateProfileRequest))
+)]
+#[put("/users/me/profile")]
+pub async fn update_profile(
+    db_pool: web::Data<DbPool>,
+    user_index: web::Data<UserIndex>,
+    profile: web::Json<UpdateProfileRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let display_name = clean_display_name(profile.display_name.as_deref())?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let username = fetch_username(session, user_id)
+        .await
+        .ok_or(ApiError::NotFound("user"))?;
+
+    session
+        .query(
+            "UPDATE twitter_clone.users SET display_name = ?, updated_at = ? WHERE user_id = ?",
+            (
+                &display_name,
+                CqlTimestamp(Utc::now().timestamp_millis()),
+                user_id,
+            ),
+        )
+        .await?;
+
+    user_index.upsert(user_id, &username, display_name.as_deref());
+    Ok(HttpResponse::Ok().json(UpdateProfileRequest { display_name }))
+}
+
+#[utoipa::path(
+    tag = "Users",
+    summary = "Pin one of your tweets to your profile",
+    params(UserIdQuery),
+    responses((status = 200, description = "Pinned"))
+)]
+#[post("/users/me/pinned")]
+pub async fn pin_tweet(
+    db_pool: web::Data<DbPool>,
+    request: web::Json<PinTweetRequest>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_tweet(session, request.tweet_id).await? {
+        Some(tweet) if tweet.user_id == user_id => {}
+        Some(_) => return Err(ApiError::Forbidden("only the author can pin a tweet")),
+        None => return Err(ApiError::NotFound("tweet")),
+    }
+
+    // Replaces any previously pinned tweet.
+    session
+        .query(
+            "UPDATE twitter_clone.users SET pinned_tweet_id = ? WHERE user_id = ?",
+            (request.tweet_id, user_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Users",
+    summary = "Unpin your pinned tweet",
+    params(UserIdQuery),
+    responses((status = 200, description = "Unpinned"))
+)]
+#[delete("/users/me/pinned")]
+pub async fn unpin_tweet(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    // Conditional so that unpinning never creates a user row.
+    session
+        .query(
+            "UPDATE twitter_clone.users SET pinned_tweet_id = null WHERE user_id = ? IF EXISTS",
+            (user_id,),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Finds users by username or display name prefix, most followed first.
+/// With `typeahead=true` only a handful of results are returned and a
+/// leading `@` is ignored, for mention autocompletion.
+#[utoipa::path(
+    tag = "Search",
+    summary = "Search users",
+    params(SearchUsersQuery),
+    responses((status = 200, description = "Matching users", body = UserSearchResults))
+)]
+#[get("/search/users")]
+pub async fn search_users(
+    user_index: web::Data<UserIndex>,
+    query: web::Query<SearchUsersQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (prefix, limit, offset) = if query.typeahead {
+        let prefix = query.q.trim().trim_start_matches(['@', '\u{FF20}']);
+        let limit = query
+            .limit
+            .unwrap_or(TYPEAHEAD_PAGE_SIZE)
+            .clamp(1, TYPEAHEAD_PAGE_SIZE);
+        (prefix, limit, 0)
+    } else {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        (query.q.trim(), limit, query.offset.unwrap_or(0))
+    };
+    if prefix.is_empty() {
+        return Err(ApiError::Validation("q is empty".to_string()));
+    }
+
+    let users = user_index.search(prefix, offset, limit);
+    let next_offset = (!query.typeahead && users.len() == limit).then_some(offset + limit);
+    Ok(HttpResponse::Ok().json(UserSearchResults { users, next_offset }))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "Get who may message you",
+    params(UserIdQuery),
+    responses((status = 200, description = "Your direct message settings", body = DmSettings))
+)]
+#[get("/users/me/dm_settings")]
+pub async fn get_dm_settings(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let allow_from = fetch_dm_allow_from(session, user_id).await?;
+    Ok(HttpResponse::Ok().json(DmSettings { allow_from }))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "Set who may message you",
+    params(UserIdQuery),
+    responses((status = 200, description = "The new settings", body = DmSettings))
+)]
+#[put("/users/me/dm_settings")]
+pub async fn update_dm_settings(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    settings: web::Json<DmSettings>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    session
+        .query(
+            "INSERT INTO twitter_clone.dm_settings (user_id, allow_from) VALUES (?, ?)",
+            (user_id, settings.allow_from.as_str()),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(settings.into_inner()))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "Start a conversation",
+    params(UserIdQuery),
+    responses(
+        (
+            status = 200,
+            description = "The new conversation, or the existing one-to-one conversation",
+            body = Conversation
+        ),
+    )
+)]
+#[post("/dm/conversations")]
+pub async fn create_conversation(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    request: web::Json<CreateConversationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let mut participant_ids = request.participant_ids.clone();
+    participant_ids.push(user_id);
+    participant_ids.sort();
+    participant_ids.dedup();
+    if participant_ids.len() < 2 || participant_ids.len() > MAX_CONVERSATION_PARTICIPANTS {
+        return Err(ApiError::Validation(format!(
+            "a conversation has 2 to {} participants",
+            MAX_CONVERSATION_PARTICIPANTS
+        )));
+    }
+    let is_group = participant_ids.len() > 2;
+    let now = Utc::now();
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    for &recipient_id in participant_ids.iter().filter(|&&id| id != user_id) {
+        if !user_exists(session, recipient_id).await? {
+            return Err(ApiError::NotFound("user"));
+        }
+        if !may_message(session, user_id, recipient_id).await? {
+            return Err(ApiError::Forbidden(
+                "a participant doesn't accept messages from you",
+            ));
+        }
+    }
+
+    let mut conversation_id = Uuid::new_v4();
+    if !is_group {
+        // There is at most one one-to-one conversation per pair of users;
+        // starting it again returns the existing one.
+        let result = session
+            .query(
+                "INSERT INTO twitter_clone.direct_conversations (user_a, user_b, conversation_id) VALUES (?, ?, ?) IF NOT EXISTS",
+                (participant_ids[0], participant_ids[1], conversation_id),
+            )
+            .await?;
+
+        if !lwt_applied(&result) {
+            if let Some(existing_id) =
+                fetch_direct_conversation_id(session, participant_ids[0], participant_ids[1])
+                    .await?
+            {
+                conversation_id = existing_id;
+            }
+            // If it's missing, a concurrent request is still writing it;
+            // writing the same conversation again below is harmless.
+            if let Some(conversation) = fetch_conversation(session, conversation_id).await? {
+                return Ok(HttpResponse::Ok().json(conversation));
+            }
+        }
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.conversations (conversation_id, participant_ids, is_group, created_by, created_at) VALUES (?, ?, ?, ?, ?)",
+            (conversation_id, &participant_ids, is_group, user_id, cql_timestamp),
+        )
+        .await?;
+
+    for &participant_id in &participant_ids {
+        session
+            .query(
+                "INSERT INTO twitter_clone.user_conversations (user_id, conversation_id, joined_at) VALUES (?, ?, ?)",
+                (participant_id, conversation_id, cql_timestamp),
+            )
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().json(Conversation {
+        conversation_id,
+        participant_ids,
+        is_group,
+        created_by: user_id,
+        created_at: now,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "List your conversations",
+    params(UserIdQuery),
+    responses((status = 200, description = "Your conversations", body = Vec<Conversation>))
+)]
+#[get("/dm/conversations")]
+pub async fn get_conversations(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let result = session
+        .query(
+            "SELECT conversation_id FROM twitter_clone.user_conversations WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+
+    let mut conversations = Vec::new();
+    for row in result.rows.unwrap_or_default() {
+        let conversation_id = match row.columns.first() {
+            Some(Some(CqlValue::Uuid(conversation_id))) => *conversation_id,
+            _ => continue,
+        };
+        match fetch_conversation(session, conversation_id).await {
+            Ok(Some(conversation)) => conversations.push(conversation),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to fetch conversation {}: {:?}", conversation_id, e)
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(conversations))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "Send a message",
+    params(UserIdQuery),
+    responses((status = 200, description = "The sent message", body = DirectMessage))
+)]
+#[post("/dm/conversations/{conversation_id}/messages")]
+pub async fn send_message(
+    db_pool: web::Data<DbPool>,
+    broker: web::Data<Broker>,
+    conversation_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+    message: web::Json<SendMessageRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let conversation_id = parse_id(&conversation_id, "conversation_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    if message.content.trim().is_empty() || message.content.chars().count() > MAX_MESSAGE_CHARS {
+        return Err(ApiError::Validation(format!(
+            "content is 1 to {} characters",
+            MAX_MESSAGE_CHARS
+        )));
+    }
+    let message_id = Uuid::new_v4();
+    let now = Utc::now();
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let conversation = match fetch_conversation(session, conversation_id).await? {
+        Some(conversation) if conversation.participant_ids.contains(&user_id) => conversation,
+        Some(_) => return Err(ApiError::Forbidden("not a participant in the conversation")),
+        None => return Err(ApiError::NotFound("conversation")),
+    };
+    // The recipient may have changed who can message them, or unfollowed the
+    // sender, since the conversation started.
+    if !conversation.is_group {
+        for &recipient_id in conversation
+            .participant_ids
+            .iter()
+            .filter(|&&id| id != user_id)
+        {
+            if !may_message(session, user_id, recipient_id).await? {
+                return Err(ApiError::Forbidden(
+                    "the recipient doesn't accept messages from you",
+                ));
+            }
+        }
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.direct_messages (conversation_id, created_at, message_id, sender_id, content) VALUES (?, ?, ?, ?, ?)",
+            (conversation_id, cql_timestamp, message_id, user_id, &message.content),
+        )
+        .await?;
+
+    // Senders have read everything up to their own message.
+    if let Err(e) = session
+        .query(
+            "INSERT INTO twitter_clone.conversation_read_markers (conversation_id, user_id, last_read_at) VALUES (?, ?, ?)",
+            (conversation_id, user_id, cql_timestamp),
+        )
+        .await
+    {
+        error!("Failed to update read marker: {:?}", e);
+    }
+
+    let message = DirectMessage {
+        message_id,
+        conversation_id,
+        sender_id: user_id,
+        content: message.content.clone(),
+        created_at: now,
+    };
+    for &participant_id in &conversation.participant_ids {
+        if participant_id != user_id {
+            broker.publish(participant_id, "message", &message);
+        }
+    }
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "List a conversation's messages",
+    params(UserIdQuery, PageQuery),
+    responses((status = 200, description = "Messages, newest first", body = MessagePage))
+)]
+#[get("/dm/conversations/{conversation_id}/messages")]
+pub async fn get_messages(
+    db_pool: web::Data<DbPool>,
+    conversation_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let conversation_id = parse_id(&conversation_id, "conversation_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let limit = page.limit();
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_conversation(session, conversation_id).await? {
+        Some(conversation) if conversation.participant_ids.contains(&user_id) => {}
+        Some(_) => return Err(ApiError::Forbidden("not a participant in the conversation")),
+        None => return Err(ApiError::NotFound("conversation")),
+    }
+
+    let read_markers = fetch_conversation_read_markers(session, conversation_id).await?;
+
+    let rows = fetch_page_rows(
+        session,
+        "SELECT message_id, sender_id, content, created_at FROM twitter_clone.direct_messages WHERE conversation_id = ?",
+        "message_id",
+        vec![CqlValue::Uuid(conversation_id)],
+        page.cursor()?,
+        limit,
+    )
+    .await?;
+
+    let messages: Vec<DirectMessage> = rows
+        .into_iter()
+        .filter_map(|row| message_from_row(conversation_id, row))
+        .collect();
+    let next_cursor = match messages.last() {
+        Some(last) if messages.len() == limit => Some(page_cursor(
+            last.created_at.timestamp_millis(),
+            last.message_id,
+        )),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok().json(MessagePage {
+        messages,
+        read_markers,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Direct Messages",
+    summary = "Mark a conversation read",
+    params(UserIdQuery),
+    responses((status = 200, description = "Marked read"))
+)]
+#[post("/dm/conversations/{conversation_id}/read")]
+pub async fn mark_conversation_read(
+    db_pool: web::Data<DbPool>,
+    conversation_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+    request: Option<web::Json<MarkReadRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let conversation_id = parse_id(&conversation_id, "conversation_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let up_to = request
+        .and_then(|request| request.up_to)
+        .unwrap_or_else(|| Utc::now().timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_conversation(session, conversation_id).await? {
+        Some(conversation) if conversation.participant_ids.contains(&user_id) => {}
+        Some(_) => return Err(ApiError::Forbidden("not a participant in the conversation")),
+        None => return Err(ApiError::NotFound("conversation")),
+    }
+
+    // Like notifications, read markers only move forward.
+    let last_read_at = fetch_conversation_read_markers(session, conversation_id)
+        .await?
+        .get(&user_id)
+        .copied()
+        .unwrap_or(0);
+    if up_to <= last_read_at {
+        return Ok(HttpResponse::Ok().finish());
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.conversation_read_markers (conversation_id, user_id, last_read_at) VALUES (?, ?, ?)",
+            (conversation_id, user_id, CqlTimestamp(up_to)),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Create a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "The new list", body = List))
+)]
+#[post("/lists")]
+pub async fn create_list(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+    request: web::Json<CreateListRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_LIST_NAME_CHARS {
+        return Err(ApiError::Validation(format!(
+            "name is 1 to {} characters",
+            MAX_LIST_NAME_CHARS
+        )));
+    }
+    let description = request
+        .description
+        .as_deref()
+        .map(str::trim)
+        .filter(|description| !description.is_empty())
+        .map(str::to_string);
+    if description
+        .as_ref()
+        .is_some_and(|description| description.chars().count() > MAX_LIST_DESCRIPTION_CHARS)
+    {
+        return Err(ApiError::Validation(format!(
+            "description is longer than {} characters",
+            MAX_LIST_DESCRIPTION_CHARS
+        )));
+    }
+    let now = Utc::now();
+    let cql_timestamp = CqlTimestamp(now.timestamp_millis());
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let count = count_rows(
+        session,
+        "SELECT COUNT(*) FROM twitter_clone.lists_by_owner WHERE owner_id = ?",
+        user_id,
+    )
+    .await?;
+    if count >= MAX_LISTS_PER_USER as i64 {
+        return Err(ApiError::Validation(format!(
+            "at most {} lists can be created",
+            MAX_LISTS_PER_USER
+        )));
+    }
+
+    let list = List {
+        list_id: Uuid::new_v4(),
+        owner_id: user_id,
+        name,
+        description,
+        private: request.private,
+        created_at: now,
+    };
+    session
+        .query(
+            "INSERT INTO twitter_clone.lists (list_id, owner_id, name, description, private, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+            (list.list_id, user_id, &list.name, &list.description, list.private, cql_timestamp),
+        )
+        .await?;
+    session
+        .query(
+            "INSERT INTO twitter_clone.lists_by_owner (owner_id, list_id) VALUES (?, ?)",
+            (user_id, list.list_id),
+        )
+        .await?;
+
+    info!("List created: {}", list.list_id);
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Get a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "The list", body = List))
+)]
+#[get("/lists/{list_id}")]
+pub async fn get_list(
+    db_pool: web::Data<DbPool>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let list = fetch_visible_list(session, list_id, user_id)
+        .await?
+        .ok_or(ApiError::NotFound("list"))?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Delete a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "Deleted"))
+)]
+#[delete("/lists/{list_id}")]
+pub async fn delete_list(
+    db_pool: web::Data<DbPool>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_visible_list(session, list_id, user_id).await? {
+        Some(list) if list.owner_id == user_id => {}
+        Some(_) => return Err(ApiError::Forbidden("only the owner can delete a list")),
+        None => return Err(ApiError::NotFound("list")),
+    }
+
+    delete_list_rows(session, list_id, user_id).await?;
+    info!("List deleted: {}", list_id);
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "List your lists",
+    params(UserIdQuery),
+    responses((status = 200, description = "Lists you own and subscribe to", body = UserLists))
+)]
+#[get("/users/me/lists")]
+pub async fn get_my_lists(
+    db_pool: web::Data<DbPool>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    let owned_ids = fetch_uuid_column(
+        session,
+        "SELECT list_id FROM twitter_clone.lists_by_owner WHERE owner_id = ?",
+        user_id,
+    )
+    .await?;
+    let subscribed_ids = fetch_uuid_column(
+        session,
+        "SELECT list_id FROM twitter_clone.list_subscriptions WHERE user_id = ?",
+        user_id,
+    )
+    .await?;
+
+    let mut lists = UserLists {
+        owned: Vec::new(),
+        subscribed: Vec::new(),
+    };
+    for (list_ids, into) in [
+        (owned_ids, &mut lists.owned),
+        (subscribed_ids, &mut lists.subscribed),
+    ] {
+        for list_id in list_ids {
+            // Lists deleted since are skipped.
+            if let Some(list) = fetch_visible_list(session, list_id, user_id).await? {
+                into.push(list);
+            }
+        }
+        into.sort_by_key(|list| Reverse(list.created_at));
+    }
+    Ok(HttpResponse::Ok().json(lists))
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Add a member to a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "Added"))
+)]
+#[post("/lists/{list_id}/members/{member_id}")]
+pub async fn add_list_member(
+    db_pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (list_id, member_id) = path.into_inner();
+    let list_id = parse_id(&list_id, "list_id")?;
+    let member_id = parse_id(&member_id, "member_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_visible_list(session, list_id, user_id).await? {
+        Some(list) if list.owner_id == user_id => {}
+        Some(_) => return Err(ApiError::Forbidden("only the owner can change a list")),
+        None => return Err(ApiError::NotFound("list")),
+    }
+    if !user_exists(session, member_id).await? {
+        return Err(ApiError::NotFound("user"));
+    }
+
+    let member_ids = fetch_uuid_column(
+        session,
+        "SELECT member_id FROM twitter_clone.list_members WHERE list_id = ?",
+        list_id,
+    )
+    .await?;
+    if member_ids.contains(&member_id) {
+        return Ok(HttpResponse::Ok().finish());
+    }
+    // Checked without a lock, so concurrent adds can overshoot slightly.
+    if member_ids.len() >= MAX_LIST_MEMBERS {
+        return Err(ApiError::Validation(format!(
+            "a list has at most {} members",
+            MAX_LIST_MEMBERS
+        )));
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.list_members (list_id, member_id, added_at) VALUES (?, ?, ?)",
+            (list_id, member_id, CqlTimestamp(Utc::now().timestamp_millis())),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Remove a member from a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "Removed"))
+)]
+#[delete("/lists/{list_id}/members/{member_id}")]
+pub async fn remove_list_member(
+    db_pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (list_id, member_id) = path.into_inner();
+    let list_id = parse_id(&list_id, "list_id")?;
+    let member_id = parse_id(&member_id, "member_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_visible_list(session, list_id, user_id).await? {
+        Some(list) if list.owner_id == user_id => {}
+        Some(_) => return Err(ApiError::Forbidden("only the owner can change a list")),
+        None => return Err(ApiError::NotFound("list")),
+    }
+
+    session
+        .query(
+            "DELETE FROM twitter_clone.list_members WHERE list_id = ? AND member_id = ?",
+            (list_id, member_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "List a list's members",
+    params(UserIdQuery),
+    responses((status = 200, description = "The members", body = Vec<UserSummary>))
+)]
+#[get("/lists/{list_id}/members")]
+pub async fn get_list_members(
+    db_pool: web::Data<DbPool>,
+    user_index: web::Data<UserIndex>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    if fetch_visible_list(session, list_id, user_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound("list"));
+    }
+
+    let member_ids = fetch_uuid_column(
+        session,
+        "SELECT member_id FROM twitter_clone.list_members WHERE list_id = ?",
+        list_id,
+    )
+    .await?;
+    let mut members: Vec<UserSummary> = member_ids
+        .into_iter()
+        .filter_map(|member_id| user_index.get(member_id))
+        .collect();
+    members.sort_by(|a, b| a.username.cmp(&b.username));
+    Ok(HttpResponse::Ok().json(members))
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Subscribe to a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "Subscribed"))
+)]
+#[post("/lists/{list_id}/subscribe")]
+pub async fn subscribe_list(
+    db_pool: web::Data<DbPool>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    match fetch_visible_list(session, list_id, user_id).await? {
+        // Owners see their lists anyway.
+        Some(list) if list.owner_id == user_id => {
+            return Err(ApiError::Validation(
+                "owners can't subscribe to their own list".to_string(),
+            ))
+        }
+        Some(_) => {}
+        None => return Err(ApiError::NotFound("list")),
+    }
+
+    session
+        .query(
+            "INSERT INTO twitter_clone.list_subscribers (list_id, user_id) VALUES (?, ?)",
+            (list_id, user_id),
+        )
+        .await?;
+    session
+        .query(
+            "INSERT INTO twitter_clone.list_subscriptions (user_id, list_id) VALUES (?, ?)",
+            (user_id, list_id),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Unsubscribe from a list",
+    params(UserIdQuery),
+    responses((status = 200, description = "Unsubscribed"))
+)]
+#[delete("/lists/{list_id}/subscribe")]
+pub async fn unsubscribe_list(
+    db_pool: web::Data<DbPool>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+
+    let pool = db_pool.lock().await;
+
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    delete_list_subscription(session, list_id, user_id).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[utoipa::path(
+    tag = "Lists",
+    summary = "Get a list's timeline",
+    params(UserIdQuery, PageQuery),
+    responses(
+        (
+            status = 200,
+            description = "Tweets from the list's members, newest first",
+            body = TweetPage
+        ),
+    )
+)]
+#[get("/lists/{list_id}/tweets")]
+pub async fn get_list_tweets(
+    db_pool: web::Data<DbPool>,
+    list_id: web::Path<String>,
+    query: web::Query<UserIdQuery>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let list_id = parse_id(&list_id, "list_id")?;
+    let user_id = parse_id(&query.user_id, "user_id")?;
+    let limit = page.limit();
+    let cursor = page.cursor()?;
+
+    // A list reads every member's timeline, so it runs without holding the
+    // pool.
+    let session = db_pool
+        .session()
+        .await
+        .ok_or(ApiError::DatabaseUnavailable)?;
+    let session = session.as_ref();
+    if fetch_visible_list(session, list_id, user_id)
+        .await?
+        .is_none()
+    {
+        return Err(ApiError::NotFound("list"));
+    }
+    let member_ids = fetch_uuid_column(
+        session,
+        "SELECT member_id FROM twitter_clone.list_members WHERE list_id = ?",
+        list_id,
+    )
+    .await?;
+
+    // Fan out on read: the newest `limit` tweets of every member are
+    // enough to fill the page, whichever members they come from.
+    let timelines = futures::stream::iter(member_ids)
+        .map(|member_id| fetch_user_timeline_page(session, member_id, cursor, limit))
+        .buffer_unordered(LIST_TIMELINE_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let entries = merge_timelines(timelines, limit);
+    let next_cursor = next_page_cursor(&entries, limit);
+    let tweet_ids: Vec<Uuid> = entries.iter().map(|(tweet_id, _)| *tweet_id).collect();
+    let tweets = fetch_tweets(session, &tweet_ids, Some(user_id)).await;
+    Ok(HttpResponse::Ok().json(TweetPage {
+        tweets,
+        next_cursor,
+    }))
+}
+
+async fn user_exists(session: &Session, user_id: Uuid) -> Result<bool, QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id FROM twitter_clone.users WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+    Ok(!result.rows.unwrap_or_default().is_empty())
+}
+
+async fn is_following(
+    session: &Session,
+    follower_id: Uuid,
+    followed_id: Uuid,
+) -> Result<bool, QueryError> {
+    let result = session
+        .query(
+            "SELECT follower_id FROM twitter_clone.followers WHERE user_id = ? AND follower_id = ?",
+            (followed_id, follower_id),
+        )
+        .await?;
+    Ok(!result.rows.unwrap_or_default().is_empty())
+}
+
+async fn fetch_dm_allow_from(session: &Session, user_id: Uuid) -> Result<DmAllowFrom, QueryError> {
+    let result = session
+        .query(
+            "SELECT allow_from FROM twitter_clone.dm_settings WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| match row.columns.first() {
+            Some(Some(CqlValue::Text(allow_from))) => DmAllowFrom::parse(allow_from),
+            _ => None,
+        })
+        .unwrap_or(DmAllowFrom::Following))
+}
+
+/// Whether `sender_id` may start a conversation with `recipient_id`, or keep
+/// writing in their one-to-one conversation. By default only accounts the
+/// recipient follows can message them.
+async fn may_message(
+    session: &Session,
+    sender_id: Uuid,
+    recipient_id: Uuid,
+) -> Result<bool, QueryError> {
+    match fetch_dm_allow_from(session, recipient_id).await? {
+        DmAllowFrom::Everyone => Ok(true),
+        DmAllowFrom::Following => is_following(session, recipient_id, sender_id).await,
+    }
+}
+
+async fn fetch_direct_conversation_id(
+    session: &Session,
+    user_a: Uuid,
+    user_b: Uuid,
+) -> Result<Option<Uuid>, QueryError> {
+    let result = session
+        .query(
+            "SELECT conversation_id FROM twitter_clone.direct_conversations WHERE user_a = ? AND user_b = ?",
+            (user_a, user_b),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| match row.columns.first() {
+            Some(Some(CqlValue::Uuid(conversation_id))) => Some(*conversation_id),
+            _ => None,
+        }))
+}
+
+async fn fetch_conversation(
+    session: &Session,
+    conversation_id: Uuid,
+) -> Result<Option<Conversation>, QueryError> {
+    let result = session
+        .query(
+            "SELECT participant_ids, is_group, created_by, created_at FROM twitter_clone.conversations WHERE conversation_id = ?",
+            (conversation_id,),
+        )
+        .await?;
+    Ok(result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        let mut columns = row.columns.into_iter();
+        match (
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+        ) {
+            (
+                Some(CqlValue::Set(participant_ids)),
+                Some(CqlValue::Boolean(is_group)),
+                Some(CqlValue::Uuid(created_by)),
+                Some(CqlValue::Timestamp(timestamp)),
+            ) => Some(Conversation {
+                conversation_id,
+                participant_ids: participant_ids
+                    .into_iter()
+                    .filter_map(|participant_id| match participant_id {
+                        CqlValue::Uuid(participant_id) => Some(participant_id),
+                        _ => None,
+                    })
+                    .collect(),
+                is_group,
+                created_by,
+                created_at: datetime_from_cql(timestamp),
+            }),
+            _ => None,
+        }
+    }))
+}
+
+async fn fetch_list(session: &Session, list_id: Uuid) -> Result<Option<List>, QueryError> {
+    let result = session
+        .query(
+            "SELECT owner_id, name, description, private, created_at FROM twitter_clone.lists WHERE list_id = ?",
+            (list_id,),
+        )
+        .await?;
+    Ok(result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        let mut columns = row.columns.into_iter();
+        match (
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+        ) {
+            (
+                Some(CqlValue::Uuid(owner_id)),
+                Some(CqlValue::Text(name)),
+                description,
+                private,
+                Some(CqlValue::Timestamp(timestamp)),
+            ) => Some(List {
+                list_id,
+                owner_id,
+                name,
+                description: match description {
+                    Some(CqlValue::Text(description)) => Some(description),
+                    _ => None,
+                },
+                private: matches!(private, Some(CqlValue::Boolean(true))),
+                created_at: datetime_from_cql(timestamp),
+            }),
+            _ => None,
+        }
+    }))
+}
+
+/// The list, if `user_id` may see it. Private lists are reported missing
+/// to everyone but their owner, so their existence isn't revealed.
+async fn fetch_visible_list(
+    session: &Session,
+    list_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<List>, QueryError> {
+    Ok(fetch_list(session, list_id)
+        .await?
+        .filter(|list| !list.private || list.owner_id == user_id))
+}
+
+/// Deletes a list and everything hanging off it. The list itself goes
+/// last, so a delete that fails part-way can simply be retried.
+async fn delete_list_rows(
+    session: &Session,
+    list_id: Uuid,
+    owner_id: Uuid,
+) -> Result<(), QueryError> {
+    let subscriber_ids = fetch_uuid_column(
+        session,
+        "SELECT user_id FROM twitter_clone.list_subscribers WHERE list_id = ?",
+        list_id,
+    )
+    .await?;
+    for subscriber_id in subscriber_ids {
+        delete_list_subscription(session, list_id, subscriber_id).await?;
+    }
+    session
+        .query(
+            "DELETE FROM twitter_clone.list_members WHERE list_id = ?",
+            (list_id,),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.lists_by_owner WHERE owner_id = ? AND list_id = ?",
+            (owner_id, list_id),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.lists WHERE list_id = ?",
+            (list_id,),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn delete_list_subscription(
+    session: &Session,
+    list_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "DELETE FROM twitter_clone.list_subscriptions WHERE user_id = ? AND list_id = ?",
+            (user_id, list_id),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.list_subscribers WHERE list_id = ? AND user_id = ?",
+            (list_id, user_id),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Runs `query`, which selects a single uuid column for the partition `key`.
+async fn fetch_uuid_column(
+    session: &Session,
+    query: &str,
+    key: Uuid,
+) -> Result<Vec<Uuid>, QueryError> {
+    let result = session.query(query, (key,)).await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|row| match row.columns.into_iter().next().flatten() {
+            Some(CqlValue::Uuid(id)) => Some(id),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Runs `query`, a `SELECT COUNT(*)` over the partition `key`.
+async fn count_rows(session: &Session, query: &str, key: Uuid) -> Result<i64, QueryError> {
+    let result = session.query(query, (key,)).await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| match row.columns.into_iter().next().flatten() {
+            Some(CqlValue::BigInt(count)) => Some(count),
+            _ => None,
+        })
+        .unwrap_or(0))
+}
+
+async fn fetch_conversation_read_markers(
+    session: &Session,
+    conversation_id: Uuid,
+) -> Result<HashMap<Uuid, i64>, QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id, last_read_at FROM twitter_clone.conversation_read_markers WHERE conversation_id = ?",
+            (conversation_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|row| match (row.columns.first(), row.columns.get(1)) {
+            (Some(Some(CqlValue::Uuid(user_id))), Some(Some(CqlValue::Timestamp(timestamp)))) => {
+                Some((*user_id, timestamp.0))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Reads a `message_id, sender_id, content, created_at` row of
+/// `direct_messages`.
+fn message_from_row(conversation_id: Uuid, row: Row) -> Option<DirectMessage> {
+    let mut columns = row.columns.into_iter();
+    match (
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+    ) {
+        (
+            Some(CqlValue::Uuid(message_id)),
+            Some(CqlValue::Uuid(sender_id)),
+            Some(CqlValue::Text(content)),
+            Some(CqlValue::Timestamp(timestamp)),
+        ) => Some(DirectMessage {
+            message_id,
+            conversation_id,
+            sender_id,
+            content,
+            created_at: datetime_from_cql(timestamp),
+        }),
+        _ => None,
+    }
+}
+
+/// Looks up every `@username` in `content`. Usernames that don't belong to a
+/// user are left out, so they stay plain text.
+async fn resolve_mentions(
+    session: &Session,
+    content: &str,
+) -> Result<HashMap<String, Uuid>, QueryError> {
+    let mut mentions = HashMap::new();
+    for (username, _, _) in extract_mentions(content) {
+        if mentions.contains_key(&username) {
+            continue;
+        }
+        if let Some(user_id) = fetch_user_id_by_username(session, &username).await? {
+            mentions.insert(username, user_id);
+        }
+    }
+    Ok(mentions)
+}
+
+/// Reads a multipart field into memory, failing with 413 past `limit` bytes.
+async fn read_field(field: &mut Field, limit: usize) -> Result<BytesMut, ApiError> {
+    let mut data = BytesMut::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(invalid_multipart)?;
+        if data.len() + chunk.len() > limit {
+            return Err(ApiError::PayloadTooLarge(format!(
+                "{} is larger than {} bytes",
+                field.name().unwrap_or("field"),
+                limit
+            )));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+fn invalid_multipart(e: MultipartError) -> ApiError {
+    debug!("Invalid multipart upload: {:?}", e);
+    ApiError::Validation(format!("invalid multipart upload: {}", e))
+}
+
+/// Loads the media to attach to a new tweet, checking the caller uploaded
+/// it.
+async fn resolve_media(
+    session: &Session,
+    user_id: Uuid,
+    media_ids: &[Uuid],
+) -> Result<Vec<Media>, ApiError> {
+    if media_ids.len() > MAX_MEDIA_PER_TWEET {
+        return Err(ApiError::Validation(format!(
+            "a tweet has at most {} media",
+            MAX_MEDIA_PER_TWEET
+        )));
+    }
+
+    let mut media = Vec::with_capacity(media_ids.len());
+    for (i, &media_id) in media_ids.iter().enumerate() {
+        if media_ids[..i].contains(&media_id) {
+            return Err(ApiError::Validation(format!(
+                "media {} is attached twice",
+                media_id
+            )));
+        }
+        match fetch_media(session, media_id).await? {
+            Some(stored) if stored.user_id == user_id => media.push(stored.media),
+            Some(_) => return Err(ApiError::Forbidden("media belongs to another user")),
+            None => {
+                return Err(ApiError::Validation(format!(
+                    "media {} does not exist",
+                    media_id
+                )))
+            }
+        }
+    }
+    Ok(media)
+}
+
+/// Decodes and re-encodes an upload on the worker pool, then stores it
+/// and its variants under `content_hash`. Video and GIFs are stored as
+/// uploaded.
+async fn store_media_blob(
+    db_pool: &DbPool,
+    blob_store: &dyn BlobStore,
+    processor: &MediaProcessor,
+    kind: MediaKind,
+    file: Bytes,
+    content_hash: &str,
+) -> Result<MediaBlob, ApiError> {
+    let mut files = Vec::new();
+    let blob = match kind {
+        MediaKind::Jpeg | MediaKind::Png | MediaKind::Webp => {
+            let processed = match processor.process_image(kind, file).await {
+                Ok(processed) => processed,
+                Err(ProcessError::Busy) => {
+                    return Err(ApiError::Overloaded {
+                        retry_after: Duration::from_secs(1),
+                    })
+                }
+                Err(e @ ProcessError::TooManyPixels) => {
+                    return Err(ApiError::PayloadTooLarge(e.to_string()))
+                }
+                Err(e @ ProcessError::Invalid(_)) => {
+                    debug!("Rejected upload: {}", e);
+                    return Err(ApiError::Validation(e.to_string()));
+                }
+            };
+            let blob = MediaBlob {
+                content_type: processed.content_type.to_string(),
+                size_bytes: processed.original.len() as u64,
+                width: Some(processed.width),
+                height: Some(processed.height),
+                blurhash: processed.blurhash,
+                variants: processed
+                    .variants
+                    .iter()
+                    .map(|(variant, _)| variant.clone())
+                    .collect(),
+            };
+            files.push((
+                blob_key(content_hash, None),
+                processed.original,
+                processed.content_type.to_string(),
+            ));
+            for (variant, bytes) in processed.variants {
+                files.push((
+                    blob_key(content_hash, Some(&variant.name)),
+                    bytes,
+                    variant.content_type,
+                ));
+            }
+            blob
+        }
+        MediaKind::Gif | MediaKind::Mp4 => {
+            let dimensions = kind.dimensions(&file);
+            let blob = MediaBlob {
+                content_type: kind.content_type().to_string(),
+                size_bytes: file.len() as u64,
+                width: dimensions.map(|(width, _)| width),
+                height: dimensions.map(|(_, height)| height),
+                blurhash: None,
+                variants: Vec::new(),
+            };
+            files.push((
+                blob_key(content_hash, None),
+                file,
+                blob.content_type.clone(),
+            ));
+            blob
+        }
+    };
+
+    for (key, bytes, content_type) in &files {
+        if let Err(e) = blob_store.put(key, bytes.clone(), content_type).await {
+            return Err(ApiError::Internal(format!(
+                "Failed to store {}: {}",
+                key, e
+            )));
+        }
+    }
+
+    let pool = db_pool.lock().await;
+    let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+    // A concurrent upload of the same file may have got here first; its
+    // files are identical, so either row will do.
+    let result = session
+        .query(
+            "INSERT INTO twitter_clone.media_blobs (content_hash, content_type, size_bytes, width, height, blurhash, variants, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS",
+            (
+                content_hash,
+                &blob.content_type,
+                blob.size_bytes as i64,
+                blob.width.map(|width| width as i32),
+                blob.height.map(|height| height as i32),
+                &blob.blurhash,
+                serde_json::to_string(&blob.variants).unwrap_or_default(),
+                CqlTimestamp(Utc::now().timestamp_millis()),
+            ),
+        )
+        .await;
+    if let Err(e) = result {
+        // Unrecorded files would never be found again.
+        for (key, _, _) in &files {
+            if let Err(e) = blob_store.delete(key).await {
+                error!("Failed to clean up {}: {}", key, e);
+            }
+        }
+        return Err(e.into());
+    }
+    Ok(blob)
+}
+
+async fn serve_media(
+    db_pool: &DbPool,
+    blob_store: &dyn BlobStore,
+    media_id: &str,
+    variant: Option<&str>,
+) -> Result<HttpResponse, ApiError> {
+    let media_id = parse_id(media_id, "media_id")?;
+
+    let stored = {
+        let pool = db_pool.lock().await;
+        let session = pool.first().ok_or(ApiError::DatabaseUnavailable)?;
+        fetch_media(session, media_id)
+            .await?
+            .ok_or(ApiError::NotFound("media"))?
+    };
+
+    let (key, content_type) = match variant {
+        None => (
+            blob_key(&stored.content_hash, None),
+            stored.media.content_type,
+        ),
+        Some(name) => match stored
+            .media
+            .variants
+            .into_iter()
+            .find(|variant| variant.name == name)
+        {
+            Some(variant) => (
+                blob_key(&stored.content_hash, Some(name)),
+                variant.content_type,
+            ),
+            None => return Err(ApiError::NotFound("variant")),
+        },
+    };
+
+    match blob_store.get(&key).await {
+        Ok(Some(bytes)) => Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            // Media never changes once uploaded.
+            .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, immutable"))
+            .body(bytes)),
+        Ok(None) => Err(ApiError::NotFound("media")),
+        Err(e) => Err(ApiError::Internal(format!("Failed to read {}: {}", key, e))),
+    }
+}
+
+async fn fetch_media(session: &Session, media_id: Uuid) -> Result<Option<StoredMedia>, QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id, content_hash, alt_text, content_type, size_bytes, width, height, blurhash, variants FROM twitter_clone.media WHERE media_id = ?",
+            (media_id,),
+        )
+        .await?;
+    Ok(result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        let mut columns = row.columns.into_iter();
+        let user_id = match columns.next().flatten() {
+            Some(CqlValue::Uuid(user_id)) => user_id,
+            _ => return None,
+        };
+        let content_hash = match columns.next().flatten() {
+            Some(CqlValue::Text(content_hash)) => content_hash,
+            _ => return None,
+        };
+        let alt_text = match columns.next().flatten() {
+            Some(CqlValue::Text(alt_text)) => Some(alt_text),
+            _ => None,
+        };
+        let blob = media_blob_from_columns(columns)?;
+        Some(StoredMedia {
+            user_id,
+            content_hash,
+            media: blob.to_media(media_id, alt_text),
+        })
+    }))
+}
+
+async fn fetch_media_blob(
+    session: &Session,
+    content_hash: &str,
+) -> Result<Option<MediaBlob>, QueryError> {
+    let result = session
+        .query(
+            "SELECT content_type, size_bytes, width, height, blurhash, variants FROM twitter_clone.media_blobs WHERE content_hash = ?",
+            (content_hash,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| media_blob_from_columns(row.columns.into_iter())))
+}
+
+/// Reads `content_type, size_bytes, width, height, blurhash, variants`.
+fn media_blob_from_columns(
+    mut columns: impl Iterator<Item = Option<CqlValue>>,
+) -> Option<MediaBlob> {
+    let content_type = match columns.next().flatten() {
+        Some(CqlValue::Text(content_type)) => content_type,
+        _ => return None,
+    };
+    let size_bytes = match columns.next().flatten() {
+        Some(CqlValue::BigInt(size_bytes)) => size_bytes as u64,
+        _ => return None,
+    };
+    let mut dimension = || match columns.next().flatten() {
+        Some(CqlValue::Int(value)) => u32::try_from(value).ok(),
+        _ => None,
+    };
+    let width = dimension();
+    let height = dimension();
+    let blurhash = match columns.next().flatten() {
+        Some(CqlValue::Text(blurhash)) => Some(blurhash),
+        _ => None,
+    };
+    let variants: Vec<StoredVariant> = match columns.next().flatten() {
+        Some(CqlValue::Text(variants)) => serde_json::from_str(&variants).unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    Some(MediaBlob {
+        content_type,
+        size_bytes,
+        width,
+        height,
+        blurhash,
+        variants,
+    })
+}
+
+/// Finds a user by username, ignoring case. Users who signed up before
+/// usernames were claimed in `users_by_username` are found by their exact
+/// username instead.
+async fn fetch_user_id_by_username(
+    session: &Session,
+    username: &str,
+) -> Result<Option<Uuid>, QueryError> {
+    let first_user_id = |result: QueryResult| {
+        result
+            .rows
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|row| match row.columns.first() {
+                Some(Some(CqlValue::Uuid(user_id))) => Some(*user_id),
+                _ => None,
+            })
+    };
+    let result = session
+        .query(
+            "SELECT user_id FROM twitter_clone.users_by_username WHERE username_lower = ?",
+            (username.to_ascii_lowercase(),),
+        )
+        .await?;
+    if let Some(user_id) = first_user_id(result) {
+        return Ok(Some(user_id));
+    }
+    let result = session
+        .query(
+            "SELECT user_id FROM twitter_clone.users WHERE username = ?",
+            (username,),
+        )
+        .await?;
+    Ok(first_user_id(result))
+}
+
+async fn index_mentions(
+    session: &Session,
+    entities: &TweetEntities,
+    tweet_id: Uuid,
+    author_id: Uuid,
+    created_at: CqlTimestamp,
+) -> Result<(), QueryError> {
+    for user_id in entities.mentioned_user_ids() {
+        session
+            .query(
+                "INSERT INTO twitter_clone.mentions_by_user (user_id, created_at, tweet_id, author_id) VALUES (?, ?, ?, ?)",
+                (user_id, created_at, tweet_id, author_id),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn index_hashtags(
+    session: &Session,
+    entities: &TweetEntities,
+    tweet_id: Uuid,
+    user_id: Uuid,
+    created_at: CqlTimestamp,
+) -> Result<(), QueryError> {
+    let bucket = hashtag_bucket(created_at.0);
+    for tag in entities.unique_hashtags() {
+        session
+            .query(
+                "INSERT INTO twitter_clone.tweets_by_hashtag (hashtag, bucket, created_at, tweet_id, user_id) VALUES (?, ?, ?, ?, ?)",
+                (&tag, bucket, created_at, tweet_id, user_id),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+fn hashtag_bucket(timestamp_millis: i64) -> i32 {
+    timestamp_millis.div_euclid(HASHTAG_BUCKET_MILLIS) as i32
+}
+
+fn datetime_from_cql(timestamp: CqlTimestamp) -> DateTime<Utc> {
+    let timestamp_millis = timestamp.0;
+    let seconds = timestamp_millis / 1000;
+    let nanos = ((timestamp_millis % 1000) * 1_000_000) as u32;
+    DateTime::<Utc>::from_timestamp(seconds, nanos).unwrap_or_default()
+}
+
+/// Builds a `Tweet` from a row selected as
+/// `tweet_id, user_id, content, created_at, mentions, media, poll,
+/// in_reply_to_tweet_id`. Poll
+/// results are filled in separately by `hydrate_polls`.
+fn tweet_from_row(row: Row) -> Option<Tweet> {
+    let mut columns = row.columns.into_iter();
+    match (
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+        columns.next().flatten(),
+    ) {
+        (
+            Some(CqlValue::Uuid(tweet_id)),
+            Some(CqlValue::Uuid(user_id)),
+            Some(CqlValue::Text(content)),
+            Some(CqlValue::Timestamp(timestamp)),
+            mentions,
+            media,
+            poll,
+            in_reply_to_tweet_id,
+        ) => {
+            let mentions: HashMap<String, Uuid> = match mentions {
+                Some(CqlValue::Map(entries)) => entries
+                    .into_iter()
+                    .filter_map(|entry| match entry {
+                        (CqlValue::Text(username), CqlValue::Uuid(user_id)) => {
+                            Some((username, user_id))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+                _ => HashMap::new(),
+            };
+            let entities = TweetEntities::parse(&content, &mentions);
+            let media = match media {
+                Some(CqlValue::Text(media)) => serde_json::from_str(&media).unwrap_or_else(|e| {
+                    error!("Invalid media on tweet {}: {:?}", tweet_id, e);
+                    Vec::new()
+                }),
+                _ => Vec::new(),
+            };
+            let poll = match poll {
+                Some(CqlValue::Text(poll)) => serde_json::from_str(&poll)
+                    .map_err(|e| error!("Invalid poll on tweet {}: {:?}", tweet_id, e))
+                    .ok(),
+                _ => None,
+            };
+            Some(Tweet {
+                tweet_id,
+                user_id,
+                content,
+                created_at: datetime_from_cql(timestamp),
+                entities,
+                media,
+                poll,
+                in_reply_to_tweet_id: match in_reply_to_tweet_id {
+                    Some(CqlValue::Uuid(in_reply_to_tweet_id)) => Some(in_reply_to_tweet_id),
+                    _ => None,
+                },
+                pinned: false,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Reads a `tweet_id, created_at` row from one of the timeline tables.
+fn timeline_entry_from_row(row: Row) -> Option<(Uuid, i64)> {
+    match (row.columns.first(), row.columns.get(1)) {
+        (Some(Some(CqlValue::Uuid(tweet_id))), Some(Some(CqlValue::Timestamp(timestamp)))) => {
+            Some((*tweet_id, timestamp.0))
+        }
+        _ => None,
+    }
+}
+
+/// Hydrates tweet ids in the given order, with poll results as `viewer_id`
+/// may see them. Tweets that can no longer be read are skipped.
+async fn fetch_tweets(
+    session: &Session,
+    tweet_ids: &[Uuid],
+    viewer_id: Option<Uuid>,
+) -> Vec<Tweet> {
+    let mut tweets = Vec::new();
+    for &tweet_id in tweet_ids {
+        match fetch_tweet(session, tweet_id).await {
+            Ok(Some(tweet)) => tweets.push(tweet),
+            Ok(None) => {}
+            Err(e) => error!("Failed to fetch tweet {}: {:?}", tweet_id, e),
+        }
+    }
+    // Polls are left without results rather than failing the page.
+    if let Err(e) = hydrate_polls(session, &mut tweets, viewer_id).await {
+        error!("Failed to fetch poll results: {:?}", e);
+    }
+    tweets
+}
+
+async fn fetch_tweet(session: &Session, tweet_id: Uuid) -> Result<Option<Tweet>, QueryError> {
+    let result = session
+        .query(
+            "SELECT tweet_id, user_id, content, created_at, mentions, media, poll, in_reply_to_tweet_id FROM twitter_clone.tweets WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(tweet_from_row))
+}
+
+/// The user's pinned tweet, if it still exists.
+async fn fetch_pinned_tweet(session: &Session, user_id: Uuid) -> Result<Option<Tweet>, QueryError> {
+    let result = session
+        .query(
+            "SELECT pinned_tweet_id FROM twitter_clone.users WHERE user_id = ?",
+            (user_id,),
+        )
+        .await?;
+    let pinned_tweet_id = result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        match row.columns.into_iter().next().flatten() {
+            Some(CqlValue::Uuid(tweet_id)) => Some(tweet_id),
+            _ => None,
+        }
+    });
+    match pinned_tweet_id {
+        Some(tweet_id) => fetch_tweet(session, tweet_id).await,
+        None => Ok(None),
+    }
+}
+
+/// Deletes a tweet and every row derived from it. The tweet itself goes
+/// last, so a delete that fails part-way can simply be retried.
+async fn delete_tweet_rows(session: &Session, tweet: &Tweet) -> Result<(), QueryError> {
+    let created_at = CqlTimestamp(tweet.created_at.timestamp_millis());
+
+    session
+        .query(
+            "DELETE FROM twitter_clone.user_timeline WHERE user_id = ? AND created_at = ? AND tweet_id = ?",
+            (tweet.user_id, created_at, tweet.tweet_id),
+        )
+        .await?;
+    let bucket = hashtag_bucket(created_at.0);
+    for tag in tweet.entities.unique_hashtags() {
+        session
+            .query(
+                "DELETE FROM twitter_clone.tweets_by_hashtag WHERE hashtag = ? AND bucket = ? AND created_at = ? AND tweet_id = ?",
+                (&tag, bucket, created_at, tweet.tweet_id),
+            )
+            .await?;
+    }
+    for user_id in tweet.entities.mentioned_user_ids() {
+        session
+            .query(
+                "DELETE FROM twitter_clone.mentions_by_user WHERE user_id = ? AND created_at = ? AND tweet_id = ?",
+                (user_id, created_at, tweet.tweet_id),
+            )
+            .await?;
+    }
+    session
+        .query(
+            "DELETE FROM twitter_clone.likes WHERE tweet_id = ?",
+            (tweet.tweet_id,),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.retweets WHERE tweet_id = ?",
+            (tweet.tweet_id,),
+        )
+        .await?;
+    // Replies stay up, but are no longer listed under the deleted tweet.
+    session
+        .query(
+            "DELETE FROM twitter_clone.replies_by_tweet WHERE tweet_id = ?",
+            (tweet.tweet_id,),
+        )
+        .await?;
+    if let Some(in_reply_to_tweet_id) = tweet.in_reply_to_tweet_id {
+        session
+            .query(
+                "DELETE FROM twitter_clone.replies_by_tweet WHERE tweet_id = ? AND created_at = ? AND reply_id = ?",
+                (in_reply_to_tweet_id, created_at, tweet.tweet_id),
+            )
+            .await?;
+    }
+    delete_tweet_notifications(session, tweet.tweet_id).await?;
+    let bookmarks = session
+        .query(
+            "SELECT user_id, created_at FROM twitter_clone.bookmarks_by_tweet WHERE tweet_id = ?",
+            (tweet.tweet_id,),
+        )
+        .await?;
+    for row in bookmarks.rows.unwrap_or_default() {
+        let mut columns = row.columns.into_iter();
+        if let (Some(CqlValue::Uuid(user_id)), Some(CqlValue::Timestamp(bookmarked_at))) =
+            (columns.next().flatten(), columns.next().flatten())
+        {
+            delete_bookmark_rows(session, tweet.tweet_id, user_id, bookmarked_at).await?;
+        }
+    }
+    if let Some(poll) = &tweet.poll {
+        delete_poll_rows(session, tweet.tweet_id, poll).await?;
+    }
+    // Conditional so that a different tweet pinned since is left alone.
+    session
+        .query(
+            "UPDATE twitter_clone.users SET pinned_tweet_id = null WHERE user_id = ? IF pinned_tweet_id = ?",
+            (tweet.user_id, tweet.tweet_id),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.tweets WHERE tweet_id = ?",
+            (tweet.tweet_id,),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Removes a bookmark from the user's list first, so a failure part-way
+/// leaves it findable for a retry.
+async fn delete_bookmark_rows(
+    session: &Session,
+    tweet_id: Uuid,
+    user_id: Uuid,
+    created_at: CqlTimestamp,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "DELETE FROM twitter_clone.bookmarks_by_user WHERE user_id = ? AND created_at = ? AND tweet_id = ?",
+            (user_id, created_at, tweet_id),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.bookmarks_by_tweet WHERE tweet_id = ? AND user_id = ?",
+            (tweet_id, user_id),
+        )
+        .await?;
+    Ok(())
+}
+
+/// A page of `user_id`'s own tweets as `(tweet_id, created_at)`.
+async fn fetch_user_timeline_page(
+    session: &Session,
+    user_id: Uuid,
+    cursor: PageCursor,
+    limit: usize,
+) -> Result<Vec<(Uuid, i64)>, QueryError> {
+    let rows = fetch_page_rows(
+        session,
+        "SELECT tweet_id, created_at FROM twitter_clone.user_timeline WHERE user_id = ?",
+        "tweet_id",
+        vec![CqlValue::Uuid(user_id)],
+        cursor,
+        limit,
+    )
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(timeline_entry_from_row)
+        .collect())
+}
+
+/// Merges timelines that are each in page order (newest first, ties by
+/// ascending id) into one page in the same order, by repeatedly taking the
+/// first head among them.
+fn merge_timelines(timelines: Vec<Vec<(Uuid, i64)>>, limit: usize) -> Vec<(Uuid, i64)> {
+    let mut timelines: Vec<_> = timelines.into_iter().map(Vec::into_iter).collect();
+    let mut heads = BinaryHeap::new();
+    for (index, timeline) in timelines.iter_mut().enumerate() {
+        if let Some((tweet_id, created_at)) = timeline.next() {
+            heads.push((created_at, Reverse(tweet_id), index));
+        }
+    }
+
+    let mut merged = Vec::with_capacity(limit);
+    while merged.len() < limit {
+        let (created_at, Reverse(tweet_id), index) = match heads.pop() {
+            Some(head) => head,
+            None => break,
+        };
+        merged.push((tweet_id, created_at));
+        if let Some((tweet_id, created_at)) = timelines[index].next() {
+            heads.push((created_at, Reverse(tweet_id), index));
+        }
+    }
+    merged
+}
+
+/// The cursor for the page after `entries`, or `None` if this was the last.
+fn next_page_cursor(entries: &[(Uuid, i64)], limit: usize) -> Option<String> {
+    if entries.len() < limit {
+        return None;
+    }
+    entries
+        .last()
+        .map(|&(id, created_at)| page_cursor(created_at, id))
+}
+
+/// A `next_cursor` pointing after the row with this `created_at` and id.
+fn page_cursor(created_at: i64, id: Uuid) -> String {
+    format!("{}_{}", created_at, id)
+}
+
+/// Up to `limit` rows of a partition clustered by `created_at DESC` and
+/// then an ascending id, in that order, from `cursor` on. `select` is the
+/// query restricted to the partition, bound to `partition`.
+///
+/// The two clustering columns sort in opposite directions, so rows after
+/// the cursor aren't those with `(created_at, id) < (?, ?)`: the ones
+/// sharing the cursor's `created_at` with a greater id are read first,
+/// then the older ones.
+async fn fetch_page_rows(
+    session: &Session,
+    select: &str,
+    id_column: &str,
+    partition: Vec<CqlValue>,
+    cursor: PageCursor,
+    limit: usize,
+) -> Result<Vec<Row>, QueryError> {
+    let mut rows = Vec::new();
+    if let Some(id) = cursor.id {
+        let mut values = partition.clone();
+        values.extend([
+            CqlValue::Timestamp(CqlTimestamp(cursor.created_at)),
+            CqlValue::Uuid(id),
+            CqlValue::Int(limit as i32),
+        ]);
+        let result = session
+            .query(
+                format!(
+                    "{} AND created_at = ? AND {} > ? LIMIT ?",
+                    select, id_column
+                ),
+                values,
+            )
+            .await?;
+        rows.extend(result.rows.unwrap_or_default());
+    }
+    if rows.len() < limit {
+        let mut values = partition;
+        values.extend([
+            CqlValue::Timestamp(CqlTimestamp(cursor.created_at)),
+            CqlValue::Int((limit - rows.len()) as i32),
+        ]);
+        let result = session
+            .query(format!("{} AND created_at < ? LIMIT ?", select), values)
+            .await?;
+        rows.extend(result.rows.unwrap_or_default());
+    }
+    Ok(rows)
+}
+
+/// Parses an id from a path or query parameter called `name`.
+fn parse_id(value: &str, name: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(value).map_err(|_| ApiError::Validation(format!("{} is not a valid id", name)))
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserIdQuery {
+    /// The user making the request.
+    pub user_id: String,
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchTweetsQuery {
+    /// Words, `"phrases"`, `#hashtags`, `from:username` and
+    /// `since:`/`until:` dates (`YYYY-MM-DD`).
+    q: String,
+    /// Defaults to `relevance`.
+    sort: Option<SearchOrder>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchUsersQuery {
+    /// A prefix of the username or display name.
+    q: String,
+    /// Return only a handful of results, for mention autocompletion.
+    #[serde(default)]
+    typeahead: bool,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TrendsQuery {
+    /// Defaults to `1h`.
+    window: Option<TrendWindow>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StreamQuery {
+    user_id: String,
+    /// Resume after this event, for clients that can't send `Last-Event-ID`.
+    last_event_id: Option<u64>,
+}
+
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageQuery {
+    /// A page's `next_cursor`, to fetch the one after it. A bare time in
+    /// milliseconds starts the page just before it.
+    before: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Where a page starts: after the row with this `created_at` (milliseconds)
+/// and id or, without an id, before `created_at`.
+#[derive(Clone, Copy)]
+struct PageCursor {
+    created_at: i64,
+    id: Option<Uuid>,
+}
+
+impl PageQuery {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// Parses `before`, as written by `page_cursor`; the first page starts
+    /// now.
+    fn cursor(&self) -> Result<PageCursor, ApiError> {
+        let Some(before) = &self.before else {
+            return Ok(PageCursor {
+                created_at: Utc::now().timestamp_millis() + 1,
+                id: None,
+            });
+        };
+        let invalid = || ApiError::Validation("before is not a valid cursor".to_string());
+        let (created_at, id) = match before.split_once('_') {
+            Some((created_at, id)) => (created_at, Some(id)),
+            None => (before.as_str(), None),
+        };
+        Ok(PageCursor {
+            created_at: created_at.parse().map_err(|_| invalid())?,
+            id: id
+                .map(|id| Uuid::parse_str(id).map_err(|_| invalid()))
+                .transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    #[test]
+    fn merge_interleaves_timelines_newest_first() {
+        let timelines = vec![
+            vec![(id(1), 50), (id(2), 30), (id(3), 10)],
+            vec![(id(4), 40), (id(5), 20)],
+            vec![],
+            vec![(id(6), 45)],
+        ];
+        assert_eq!(
+            merge_timelines(timelines, 10),
+            [
+                (id(1), 50),
+                (id(6), 45),
+                (id(4), 40),
+                (id(2), 30),
+                (id(5), 20),
+                (id(3), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_breaks_ties_by_ascending_id() {
+        let timelines = vec![
+            vec![(id(9), 20), (id(3), 10)],
+            vec![(id(2), 20), (id(7), 20), (id(1), 10)],
+        ];
+        assert_eq!(
+            merge_timelines(timelines, 10),
+            [
+                (id(2), 20),
+                (id(7), 20),
+                (id(9), 20),
+                (id(1), 10),
+                (id(3), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_stops_at_limit() {
+        let timelines = vec![
+            vec![(id(1), 30), (id(2), 10)],
+            vec![(id(3), 20), (id(4), 0)],
+        ];
+        let merged = merge_timelines(timelines, 3);
+        assert_eq!(merged, [(id(1), 30), (id(3), 20), (id(2), 10)]);
+        assert_eq!(next_page_cursor(&merged, 3), Some(page_cursor(10, id(2))));
+        assert!(merge_timelines(vec![], 3).is_empty());
+        assert_eq!(next_page_cursor(&merged[..2], 3), None);
+    }
 }
@@ -0,0 +1,92 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse};
+use std::time::Duration;
+
+use crate::db::DbPool;
+use crate::models::{Readiness, ReadinessCheck};
+use crate::shutdown::Shutdown;
+
+/// How long readiness waits for the cluster before reporting it down.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness: the process is up and serving requests.
+#[utoipa::path(
+    tag = "Health",
+    responses((
+        status = 200,
+        description = "The process is up",
+        body = Object,
+        example = json!({ "status": "ok" })
+    ))
+)]
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: the server isn't shutting down, every Scylla node answers,
+/// and the nodes agree on the schema version. Answers `503` otherwise, so
+/// a load balancer stops sending traffic.
+#[utoipa::path(
+    tag = "Health",
+    responses(
+        (status = 200, description = "Ready for traffic", body = Readiness),
+        (status = 503, description = "Not ready; failing checks have `ok: false`", body = Readiness),
+    )
+)]
+#[get("/readyz")]
+pub async fn readyz(db_pool: web::Data<DbPool>, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    let mut checks = vec![ReadinessCheck {
+        name: "accepting_requests",
+        ok: !shutdown.is_stopping(),
+        detail: None,
+    }];
+
+    // Waiting for the pool counts against the timeout too: a pool stuck
+    // behind a slow request means the server isn't ready.
+    let agreement = match tokio::time::timeout(READY_TIMEOUT, async {
+        let session = db_pool
+            .session()
+            .await
+            .ok_or_else(|| "no database session".to_string())?;
+        session
+            .check_schema_agreement()
+            .await
+            .map_err(|e| e.to_string())
+    })
+    .await
+    {
+        Ok(agreement) => agreement,
+        Err(_) => Err(format!("no answer within {:?}", READY_TIMEOUT)),
+    };
+    match agreement {
+        Ok(version) => {
+            checks.push(ReadinessCheck {
+                name: "database",
+                ok: true,
+                detail: None,
+            });
+            checks.push(ReadinessCheck {
+                name: "schema_agreement",
+                ok: version.is_some(),
+                detail: Some(match version {
+                    Some(version) => format!("schema version {}", version),
+                    None => "nodes disagree on the schema version".to_string(),
+                }),
+            });
+        }
+        Err(e) => checks.push(ReadinessCheck {
+            name: "database",
+            ok: false,
+            detail: Some(e),
+        }),
+    }
+
+    let ready = checks.iter().all(|check| check.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    HttpResponse::build(status).json(Readiness { ready, checks })
+}
@@ -0,0 +1,442 @@
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use bytes::BytesMut;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use log::error;
+use scylla::{frame::response::result::CqlValue, frame::value::CqlTimestamp};
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::db::{lwt_applied, Session};
+use crate::error::ApiError;
+use crate::media::MAX_UPLOAD_BYTES;
+use crate::rate_limit::{client_ip, user_id};
+
+pub const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+const REPLAYED_HEADER: HeaderName = HeaderName::from_static("idempotent-replayed");
+const MAX_KEY_LEN: usize = 255;
+/// How long a response is kept for replaying.
+const RESPONSE_TTL_SECS: i32 = 24 * 60 * 60;
+/// How long a key stays claimed by a request that never finishes, e.g.
+/// because its server died.
+const PENDING_TTL_SECS: i32 = 5 * 60;
+/// Largest request body accepted, the largest upload plus room for the
+/// multipart framing.
+const MAX_BODY_BYTES: usize = MAX_UPLOAD_BYTES + 64 * 1024;
+
+/// A key's row in `idempotency_keys`.
+struct StoredKey {
+    request_hash: String,
+    /// `None` while the first request is still being handled.
+    response: Option<StoredResponse>,
+}
+
+struct StoredResponse {
+    status: StatusCode,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Makes `POST` requests that carry an `Idempotency-Key` header safe to
+/// retry. The first request with a key is handled and its response kept
+/// for a day, per caller and key; repeats get that response again, with
+/// `Idempotent-Replayed: true`, without being handled. A repeat while the
+/// first is still running gets `409`, and one with a different method,
+/// path, query or body `422`.
+///
+/// The caller is the calling user (the `user_id` query parameter) or,
+/// without one, the client address, so that clients signing up don't
+/// share keys.
+///
+/// Server errors and `429`s aren't kept, so a retry after one is handled
+/// afresh.
+#[derive(Clone)]
+pub struct Idempotency {
+    session: Arc<Session>,
+    /// Take the client address from `Forwarded` or `X-Forwarded-For`, as
+    /// the rate limiter does.
+    trust_proxy: bool,
+}
+
+impl Idempotency {
+    pub fn new(session: Arc<Session>, trust_proxy: bool) -> Self {
+        Idempotency {
+            session,
+            trust_proxy,
+        }
+    }
+
+    /// Whose keys a request's key is kept among.
+    fn scope(&self, req: &ServiceRequest) -> Option<String> {
+        match user_id(req) {
+            Some(user_id) => Some(user_id.to_string()),
+            None => client_ip(req, self.trust_proxy).map(|ip| format!("ip:{}", ip)),
+        }
+    }
+
+    async fn handle<S, B>(
+        &self,
+        service: &S,
+        mut req: ServiceRequest,
+        key: String,
+    ) -> Result<ServiceResponse<BoxBody>, actix_web::Error>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody + 'static,
+    {
+        let Some(scope) = self.scope(&req) else {
+            return Ok(refuse(
+                req,
+                ApiError::Validation(
+                    "Idempotency-Key needs a user_id when the client address is unknown"
+                        .to_string(),
+                ),
+            ));
+        };
+
+        // The body is hashed, then handed on to the handler as if unread.
+        let mut payload = req.take_payload();
+        let mut body = BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => return Ok(refuse(req, ApiError::Validation(e.to_string()))),
+            };
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_BODY_BYTES {
+                let message = format!("request body is larger than {} bytes", MAX_BODY_BYTES);
+                return Ok(refuse(req, ApiError::PayloadTooLarge(message)));
+            }
+        }
+        let body = body.freeze();
+        let request_hash = request_hash(&req, &body);
+        req.set_payload(Payload::from(body));
+
+        let claimed = match self
+            .session
+            .query(
+                "INSERT INTO twitter_clone.idempotency_keys (scope, idempotency_key, request_hash, created_at) VALUES (?, ?, ?, ?) IF NOT EXISTS USING TTL ?",
+                (
+                    &scope,
+                    &key,
+                    &request_hash,
+                    CqlTimestamp(Utc::now().timestamp_millis()),
+                    PENDING_TTL_SECS,
+                ),
+            )
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Ok(refuse(req, e.into())),
+        };
+
+        if !lwt_applied(&claimed) {
+            return Ok(match self.fetch(&scope, &key).await {
+                Err(e) => refuse(req, e),
+                Ok(Some(stored)) if stored.request_hash != request_hash => {
+                    refuse(req, ApiError::IdempotencyKeyReused)
+                }
+                Ok(Some(StoredKey {
+                    response: Some(response),
+                    ..
+                })) => req.into_response(replay(response)),
+                // Still being handled, or the claim expired in between.
+                Ok(_) => refuse(
+                    req,
+                    ApiError::Conflict("a request with this Idempotency-Key is in progress"),
+                ),
+            });
+        }
+
+        let response = match service.call(req).await {
+            Ok(response) => response.map_into_boxed_body(),
+            Err(e) => {
+                self.release(&scope, &key, &request_hash).await;
+                return Err(e);
+            }
+        };
+        let status = response.status();
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            self.release(&scope, &key, &request_hash).await;
+            return Ok(response);
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let (req, response) = response.into_parts();
+        let (response, body) = response.into_parts();
+        let body = match body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                let error = ApiError::Internal(format!("Failed to read response body: {}", e));
+                return Ok(ServiceResponse::new(req, error.error_response()));
+            }
+        };
+
+        // The claim was a lightweight transaction, so the row is only ever
+        // written conditionally: mixing in plain writes could lose them.
+        // `request_hash` is set again to give it the response's TTL.
+        match self
+            .session
+            .query(
+                "UPDATE twitter_clone.idempotency_keys USING TTL ? SET request_hash = ?, status = ?, content_type = ?, body = ?, created_at = ? WHERE scope = ? AND idempotency_key = ? IF request_hash = ?",
+                (
+                    RESPONSE_TTL_SECS,
+                    &request_hash,
+                    status.as_u16() as i32,
+                    &content_type,
+                    body.to_vec(),
+                    CqlTimestamp(Utc::now().timestamp_millis()),
+                    &scope,
+                    &key,
+                    &request_hash,
+                ),
+            )
+            .await
+        {
+            Ok(stored) if lwt_applied(&stored) => {}
+            // The claim expired while the request was handled.
+            Ok(_) => error!(
+                "Idempotency-Key {:?} expired before its response was stored",
+                key
+            ),
+            // The request went through; a retry will get 409 until the
+            // claim expires rather than doing it twice.
+            Err(e) => error!("Failed to store response for Idempotency-Key {:?}: {:?}", key, e),
+        }
+
+        Ok(ServiceResponse::new(
+            req,
+            response.set_body(body).map_into_boxed_body(),
+        ))
+    }
+
+    async fn fetch(&self, scope: &str, key: &str) -> Result<Option<StoredKey>, ApiError> {
+        let result = self
+            .session
+            .query(
+                "SELECT request_hash, status, content_type, body FROM twitter_clone.idempotency_keys WHERE scope = ? AND idempotency_key = ?",
+                (scope, key),
+            )
+            .await?;
+        let Some(row) = result.rows.unwrap_or_default().into_iter().next() else {
+            return Ok(None);
+        };
+        let mut columns = row.columns.into_iter();
+        let Some(Some(CqlValue::Text(request_hash))) = columns.next() else {
+            return Ok(None);
+        };
+        let response = match (columns.next(), columns.next(), columns.next()) {
+            (Some(Some(CqlValue::Int(status))), content_type, body) => Some(StoredResponse {
+                status: StatusCode::from_u16(status as u16)
+                    .map_err(|e| ApiError::Internal(format!("Stored status {}: {}", status, e)))?,
+                content_type: match content_type {
+                    Some(Some(CqlValue::Text(content_type))) => Some(content_type),
+                    _ => None,
+                },
+                body: match body {
+                    Some(Some(CqlValue::Blob(body))) => body,
+                    _ => Vec::new(),
+                },
+            }),
+            _ => None,
+        };
+        Ok(Some(StoredKey {
+            request_hash,
+            response,
+        }))
+    }
+
+    /// Frees a key whose request failed, so that it can be retried. Like
+    /// every write to the row after the claim, this is conditional.
+    async fn release(&self, scope: &str, key: &str, request_hash: &str) {
+        if let Err(e) = self
+            .session
+            .query(
+                "DELETE FROM twitter_clone.idempotency_keys WHERE scope = ? AND idempotency_key = ? IF request_hash = ?",
+                (scope, key, request_hash),
+            )
+            .await
+        {
+            error!("Failed to release Idempotency-Key {:?}: {:?}", key, e);
+        }
+    }
+}
+
+fn refuse(req: ServiceRequest, e: ApiError) -> ServiceResponse<BoxBody> {
+    req.into_response(e.error_response())
+}
+
+fn replay(stored: StoredResponse) -> HttpResponse {
+    let mut response = HttpResponse::build(stored.status);
+    if let Some(content_type) = stored.content_type {
+        response.insert_header((header::CONTENT_TYPE, content_type));
+    }
+    response.insert_header((REPLAYED_HEADER, HeaderValue::from_static("true")));
+    response.body(stored.body)
+}
+
+/// Identifies what a request asks for: its method, path, query and body.
+fn request_hash(req: &ServiceRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    for part in [req.method().as_str(), req.path(), req.query_string()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// The request's `Idempotency-Key`, if it is a `POST` with one.
+fn idempotency_key(req: &ServiceRequest) -> Result<Option<String>, ApiError> {
+    if req.method() != Method::POST {
+        return Ok(None);
+    }
+    let Some(value) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key)
+            if !key.is_empty()
+                && key.len() <= MAX_KEY_LEN
+                && key.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(ApiError::Validation(format!(
+            "Idempotency-Key must be 1 to {} printable ASCII characters",
+            MAX_KEY_LEN
+        ))),
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Idempotency
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = IdempotencyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IdempotencyMiddleware {
+            service: Rc::new(service),
+            idempotency: self.clone(),
+        }))
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    service: Rc<S>,
+    idempotency: Idempotency,
+}
+
+impl<S, B> Service<ServiceRequest> for IdempotencyMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let idempotency = self.idempotency.clone();
+        Box::pin(async move {
+            let key = match idempotency_key(&req) {
+                Ok(Some(key)) => key,
+                Ok(None) => return Ok(service.call(req).await?.map_into_boxed_body()),
+                Err(e) => return Ok(refuse(req, e)),
+            };
+            idempotency.handle(&*service, req, key).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn hash(method: Method, uri: &str, body: &[u8]) -> String {
+        let req = TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .to_srv_request();
+        request_hash(&req, body)
+    }
+
+    fn key(value: &[u8]) -> Result<Option<String>, ApiError> {
+        let req = TestRequest::post()
+            .uri("/api/tweets")
+            .insert_header((
+                IDEMPOTENCY_KEY_HEADER,
+                HeaderValue::from_bytes(value).unwrap(),
+            ))
+            .to_srv_request();
+        idempotency_key(&req)
+    }
+
+    #[test]
+    fn request_hashes_cover_method_path_query_and_body() {
+        let base = hash(Method::POST, "/api/tweets?user_id=1", b"{}");
+        assert_eq!(base, hash(Method::POST, "/api/tweets?user_id=1", b"{}"));
+        for other in [
+            hash(Method::PUT, "/api/tweets?user_id=1", b"{}"),
+            hash(Method::POST, "/api/drafts?user_id=1", b"{}"),
+            hash(Method::POST, "/api/tweets?user_id=2", b"{}"),
+            hash(Method::POST, "/api/tweets", b"{}"),
+            hash(Method::POST, "/api/tweets?user_id=1", b"{ }"),
+            hash(Method::POST, "/api/tweets?user_id=1", b""),
+        ] {
+            assert_ne!(base, other);
+        }
+        // Parts are separated, so moving bytes between them changes the hash.
+        assert_ne!(
+            hash(Method::POST, "/api/a?b", b""),
+            hash(Method::POST, "/api/a", b"b")
+        );
+    }
+
+    #[test]
+    fn keys_are_1_to_255_printable_ascii_characters() {
+        assert_eq!(
+            key(b"abc-123_XYZ:~").unwrap().as_deref(),
+            Some("abc-123_XYZ:~")
+        );
+        let longest = "k".repeat(MAX_KEY_LEN);
+        assert_eq!(key(longest.as_bytes()).unwrap(), Some(longest.clone()));
+        assert!(key(format!("{}k", longest).as_bytes()).is_err());
+        assert!(key(b"").is_err());
+        assert!(key(b"has space").is_err());
+        assert!(key(b"tab\there").is_err());
+        assert!(key("caf\u{e9}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn only_posts_use_keys() {
+        let get = TestRequest::get()
+            .uri("/api/tweets")
+            .insert_header((IDEMPOTENCY_KEY_HEADER, "abc"))
+            .to_srv_request();
+        assert_eq!(idempotency_key(&get).unwrap(), None);
+        let post = TestRequest::post().uri("/api/tweets").to_srv_request();
+        assert_eq!(idempotency_key(&post).unwrap(), None);
+    }
+}
@@ -0,0 +1,372 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{get, put, web, HttpRequest, HttpResponse};
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use regex::Regex;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Subscriber};
+use tracing_log::{AsLog, NormalizeEvent};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, FilterExt};
+use tracing_subscriber::fmt::format::{FormatEvent, JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{reload, Layer, Registry};
+
+use crate::error::{ApiError, FieldError};
+use crate::models::LogFilter;
+
+/// Log filter used when `RUST_LOG` isn't set.
+const DEFAULT_LOG_FILTER: &str = "info";
+const REDACTED: &str = "[REDACTED]";
+/// Fields with any of these in their name are never logged.
+const SENSITIVE_FIELDS: [&str; 7] = [
+    "api_key",
+    "authorization",
+    "cookie",
+    "email",
+    "password",
+    "secret",
+    "token",
+];
+
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+").expect("invalid regex")
+});
+static BEARER_TOKEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\bbearer\s+[A-Za-z0-9._~+/=-]+").expect("invalid regex"));
+/// A sensitive name followed by its value, as in `password: "..."` or
+/// `token=...` in a formatted struct or query string.
+static SECRET_VALUE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?i)((?:api_key|authorization|cookie|password|secret|token)\w*"?\s*[:=]\s*)("(?:[^"\\]|\\.)*"|[^\s,;&)}]+)"#,
+    )
+    .expect("invalid regex")
+});
+
+/// How log lines are written.
+#[derive(Clone, Copy)]
+enum LogFormat {
+    /// One JSON object per line.
+    Json,
+    /// The same fields as `key=value` pairs, for reading in a terminal.
+    Text,
+}
+
+/// The filter deciding which log lines are written. It can be replaced
+/// while the server runs.
+#[derive(Clone)]
+pub struct LogLevels(reload::Handle<EnvFilter, Registry>);
+
+impl LogLevels {
+    /// The filter's directives, e.g. `info,twitter_clone::handlers=debug`.
+    pub fn current(&self) -> Result<String, ApiError> {
+        self.0
+            .with_current(|filter| filter.to_string())
+            .map_err(|e| ApiError::Internal(format!("Failed to read log filter: {}", e)))
+    }
+
+    pub fn set(&self, filter: EnvFilter) -> Result<(), ApiError> {
+        self.0
+            .reload(filter)
+            .map_err(|e| ApiError::Internal(format!("Failed to set log filter: {}", e)))?;
+        // `log` macros are skipped below the level set at startup unless
+        // it is raised too.
+        log::set_max_level(LevelFilter::current().as_log());
+        Ok(())
+    }
+}
+
+/// The layer that writes log lines to stderr, as JSON unless `LOG_FORMAT`
+/// is `text`. Lines are filtered by `RUST_LOG` (default `info`) and carry
+/// the fields of the spans they were written in, such as a request's
+/// `request_id`, `user_id` and `http.route`. Sensitive values are redacted.
+pub fn layer() -> Result<(impl Layer<Registry>, LogLevels), Box<dyn Error>> {
+    let format = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") | Err(_) => LogFormat::Json,
+        Ok("text") => LogFormat::Text,
+        Ok(other) => return Err(format!("Unknown LOG_FORMAT {:?}", other).into()),
+    };
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::new(DEFAULT_LOG_FILTER),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    // Request and query spans are always kept, so that the lines written
+    // in them get their fields whatever the filter lets through.
+    let spans = filter_fn(|metadata| metadata.is_span() && *metadata.level() <= Level::INFO)
+        .with_max_level_hint(LevelFilter::INFO);
+    let layer = tracing_subscriber::fmt::layer()
+        .fmt_fields(JsonFields::new())
+        .event_format(Formatter(format))
+        .with_writer(std::io::stderr)
+        .with_filter(filter.or(spans));
+    Ok((layer, LogLevels(handle)))
+}
+
+/// Writes the access log line for a finished request. Called in the
+/// request's span, which supplies the method, route, path and ids.
+pub fn access(status: StatusCode, latency: Duration) {
+    tracing::info!(
+        target: "access",
+        status = status.as_u16(),
+        latency_ms = latency.as_micros() as f64 / 1000.0,
+        "request handled"
+    );
+}
+
+struct Formatter(LogFormat);
+
+impl<S> FormatEvent<S, JsonFields> for Formatter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        // Lines from the `log` macros carry their real target separately.
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut fields = Map::new();
+        // Outermost span first, so that inner spans' fields win.
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(formatted) = extensions.get::<FormattedFields<JsonFields>>() {
+                    if let Ok(Value::Object(span_fields)) = serde_json::from_str(formatted) {
+                        fields.extend(span_fields);
+                    }
+                }
+            }
+        }
+        event.record(&mut FieldVisitor(&mut fields));
+        fields.retain(|name, _| !name.starts_with("otel.") && !name.starts_with("log."));
+        redact(&mut fields);
+        let message = fields.remove("message");
+
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        match self.0 {
+            LogFormat::Json => {
+                let mut line = Map::new();
+                line.insert("timestamp".to_string(), timestamp.into());
+                line.insert("level".to_string(), metadata.level().as_str().into());
+                line.insert("target".to_string(), metadata.target().into());
+                if let Some(message) = message {
+                    line.insert("message".to_string(), message);
+                }
+                line.extend(fields);
+                writeln!(writer, "{}", Value::Object(line))
+            }
+            LogFormat::Text => {
+                write!(
+                    writer,
+                    "{} {:>5} {}:",
+                    timestamp,
+                    metadata.level(),
+                    metadata.target()
+                )?;
+                if let Some(Value::String(message)) = message {
+                    write!(writer, " {}", message)?;
+                }
+                for (name, value) in fields {
+                    write!(writer, " {}={}", name, value)?;
+                }
+                writeln!(writer)
+            }
+        }
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl FieldVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: impl Into<Value>) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, format!("{:?}", value));
+    }
+}
+
+/// Blanks the values of sensitive fields, and email addresses, bearer
+/// tokens and `name: value` pairs with sensitive names in the others.
+fn redact(fields: &mut Map<String, Value>) {
+    for (name, value) in fields.iter_mut() {
+        let name = name.to_ascii_lowercase();
+        if SENSITIVE_FIELDS
+            .iter()
+            .any(|sensitive| name.contains(sensitive))
+        {
+            *value = REDACTED.into();
+        } else if let Value::String(text) = value {
+            *text = redact_text(text);
+        }
+    }
+}
+
+fn redact_text(text: &str) -> String {
+    let text = EMAIL.replace_all(text, REDACTED);
+    let text = BEARER_TOKEN.replace_all(&text, format!("Bearer {}", REDACTED));
+    SECRET_VALUE
+        .replace_all(&text, format!("${{1}}{}", REDACTED))
+        .into_owned()
+}
+
+/// Checks the request's `Authorization: Bearer` token against
+/// `ADMIN_TOKEN`. Admin endpoints don't exist while it isn't set.
+fn authorize(req: &HttpRequest) -> Result<(), ApiError> {
+    let Ok(expected) = std::env::var("ADMIN_TOKEN") else {
+        return Err(ApiError::NotFound("route"));
+    };
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized("an admin token is required"))?;
+    // Comparing digests takes the same time however much of the token
+    // is right.
+    if Sha256::digest(token) != Sha256::digest(&expected) {
+        return Err(ApiError::Unauthorized("the admin token is not valid"));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    tag = "Admin",
+    summary = "Get the log filter",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The current filter", body = LogFilter))
+)]
+#[get("/log-levels")]
+pub async fn get_log_levels(
+    req: HttpRequest,
+    log_levels: web::Data<LogLevels>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req)?;
+    Ok(HttpResponse::Ok().json(LogFilter {
+        filter: log_levels.current()?,
+    }))
+}
+
+#[utoipa::path(
+    tag = "Admin",
+    summary = "Replace the log filter",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The new filter", body = LogFilter))
+)]
+#[put("/log-levels")]
+pub async fn set_log_levels(
+    req: HttpRequest,
+    log_levels: web::Data<LogLevels>,
+    body: web::Json<LogFilter>,
+) -> Result<HttpResponse, ApiError> {
+    authorize(&req)?;
+    let filter = EnvFilter::try_new(&body.filter).map_err(|e| {
+        ApiError::InvalidFields(vec![FieldError {
+            field: "filter",
+            code: "format",
+            message: e.to_string(),
+        }])
+    })?;
+    log_levels.set(filter)?;
+    let filter = log_levels.current()?;
+    info!("Log filter set to {}", filter);
+    Ok(HttpResponse::Ok().json(LogFilter { filter }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn emails_in_messages_are_redacted() {
+        assert_eq!(
+            redact_text("Signup failed for jane.doe+x@mail.example.com, retrying"),
+            "Signup failed for [REDACTED], retrying"
+        );
+        assert_eq!(redact_text("user @alice replied"), "user @alice replied");
+    }
+
+    #[test]
+    fn bearer_tokens_are_redacted() {
+        assert_eq!(
+            redact_text("rejected bearer eyJhbGciOi.J9-x_y~z/+= from 10.0.0.1"),
+            "rejected Bearer [REDACTED] from 10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn sensitive_pairs_are_redacted() {
+        assert_eq!(
+            redact_text(r#"CreateUserRequest { username: "bob", password: "hunter2 \"x\"" }"#),
+            r#"CreateUserRequest { username: "bob", password: [REDACTED] }"#
+        );
+        assert_eq!(
+            redact_text("GET /callback?token=abc123&state=ok"),
+            "GET /callback?token=[REDACTED]&state=ok"
+        );
+        assert_eq!(
+            redact_text("api_key = k-1; next"),
+            "api_key = [REDACTED]; next"
+        );
+        assert_eq!(
+            redact_text("refresh_token: r-42, expires_in: 60"),
+            "refresh_token: [REDACTED], expires_in: 60"
+        );
+        assert_eq!(redact_text("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn sensitive_fields_are_blanked_whatever_their_value() {
+        let mut fields = Map::new();
+        fields.insert("user_email".to_string(), json!("no address here"));
+        fields.insert("Password".to_string(), json!(12345));
+        fields.insert("status".to_string(), json!(200));
+        fields.insert("message".to_string(), json!("mail a@b.io"));
+        redact(&mut fields);
+        assert_eq!(
+            Value::Object(fields),
+            json!({
+                "user_email": REDACTED,
+                "Password": REDACTED,
+                "status": 200,
+                "message": "mail [REDACTED]",
+            })
+        );
+    }
+}
@@ -1,13 +1,41 @@
+mod blob_store;
 mod db;
+mod entities;
+mod error;
 mod handlers;
+mod health;
+mod idempotency;
+mod logging;
+mod media;
+mod media_processing;
+mod metrics;
 mod models;
+mod notifications;
+mod openapi;
+mod polls;
+mod rate_limit;
+mod scheduler;
+mod search;
+mod shutdown;
+mod stream;
+mod telemetry;
+mod tls;
+mod trends;
+mod user_index;
+mod validation;
 
+use actix_web::dev::Service;
 use actix_web::{web, App, HttpServer};
-use env_logger::Builder;
 
-use log::{info, LevelFilter};
+use log::info;
 use std::sync::Arc;
 
+/// Uploads waiting for an image worker before new ones are turned away.
+const MEDIA_PROCESSING_QUEUE: usize = 64;
+/// How long a stopping server waits for requests in flight, and then for
+/// its background queues, before giving up on them.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
 #[actix_web::main]
 // async fn main() -> std::io::Result<()> {
 //     Builder::new()
@@ -49,10 +77,8 @@ use std::sync::Arc;
 // }
 
 async fn main() -> std::io::Result<()> {
-    Builder::new()
-        .filter_level(LevelFilter::Debug)
-        .format_timestamp_secs()
-        .init();
+    let telemetry = telemetry::init().expect("Failed to set up logging and tracing");
+    let log_levels = web::Data::new(telemetry.log_levels());
 
     info!("Starting Twitter clone backend...");
     // Connect to ScyllaDB
@@ -60,11 +86,66 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to create database session");
     let session = Arc::new(session);
+
+    let search_index_dir =
+        std::env::var("SEARCH_INDEX_DIR").unwrap_or_else(|_| "data/search_index".to_string());
+    let search_index = search::SearchIndex::open(std::path::Path::new(&search_index_dir))
+        .expect("Failed to open search index");
+    if std::env::args().nth(1).as_deref() == Some("reindex") {
+        let indexed = search::reindex(&session, &search_index)
+            .await
+            .expect("Failed to rebuild search index");
+        info!("Reindexed {} tweets into {}", indexed, search_index_dir);
+        telemetry.shutdown();
+        return Ok(());
+    }
+
+    let user_index = user_index::UserIndex::new();
+    user_index
+        .load(&session)
+        .await
+        .expect("Failed to load user index");
+
     let db_pool = db::create_connection_pool()
         .await
         .expect("Failed to created db pool");
     info!("Connected to ScyllaDB");
 
+    let blob_store =
+        web::Data::from(blob_store::from_env().expect("Failed to set up media storage"));
+
+    let media_processor =
+        media_processing::MediaProcessor::new(num_cpus::get(), MEDIA_PROCESSING_QUEUE);
+
+    let shutdown = shutdown::Shutdown::new();
+    let broker = stream::Broker::new();
+    stream::spawn_sweeper(broker.clone(), &shutdown);
+    let notification_sender =
+        notifications::spawn_producer(db_pool.clone(), broker.clone(), &shutdown);
+    search::spawn_committer(search_index.clone(), &shutdown);
+    polls::spawn_closer(db_pool.clone(), notification_sender.clone(), &shutdown);
+    let trends = trends::Trends::new();
+    let trend_sender = trends::spawn_consumer(trends.clone(), &shutdown);
+    let tweet_sinks = handlers::TweetSinks {
+        notifications: notification_sender.clone(),
+        broker: broker.clone(),
+        search_index: search_index.clone(),
+        trend_sender: trend_sender.clone(),
+    };
+    scheduler::spawn_scheduler(db_pool.clone(), tweet_sinks.clone(), &shutdown);
+
+    let rate_limit_store =
+        rate_limit::from_env(session.clone()).expect("Failed to set up rate limiting");
+    if let Some(store) = &rate_limit_store {
+        rate_limit::spawn_sweeper(store.clone(), &shutdown);
+    }
+    let trust_proxy = std::env::var("RATE_LIMIT_TRUST_PROXY").as_deref() == Ok("1");
+    let idempotency = idempotency::Idempotency::new(session.clone(), trust_proxy);
+    let rate_limit = rate_limit::RateLimit::new(rate_limit_store, trust_proxy);
+
+    let tls = tls::from_env().expect("Invalid TLS configuration");
+    let https = tls.as_ref().map(|tls| tls.https);
+
     let num_workers = num_cpus::get().max(4) * 2;
     info!(
         "Starting Twitter clone backend with {} workers...",
@@ -87,24 +168,149 @@ async fn main() -> std::io::Result<()> {
     // .bind("127.0.0.1:8080")?
     // .run()
     // .await
-    HttpServer::new(move || {
+    let server_shutdown = shutdown.clone();
+    let server = HttpServer::new(move || {
         App::new()
+            .wrap(idempotency.clone())
+            .wrap(rate_limit.clone())
+            .wrap_fn(move |req, srv| tls::enforce(https, req, srv))
+            .wrap_fn(|req, srv| {
+                let request_id = error::request_id(&req);
+                let span = telemetry::request_span(&req, &request_id);
+                telemetry::traced(span, error::with_request_id(request_id, srv.call(req)))
+            })
+            .wrap_fn(|req, srv| {
+                let labels = metrics::RequestLabels::of(&req);
+                metrics::track_request(labels, srv.call(req))
+            })
+            .app_data(web::JsonConfig::default().error_handler(error::json_error))
+            .app_data(web::PathConfig::default().error_handler(error::path_error))
+            .app_data(web::QueryConfig::default().error_handler(error::query_error))
             .app_data(web::Data::new(db_pool.clone()))
-            .service(
-                web::scope("/api")
-                    .service(handlers::create_user)
-                    .service(handlers::create_tweet)
-                    .service(handlers::like_tweet)
-                    .service(handlers::get_home_feed)
-                    .service(handlers::get_user_tweets),
-            )
+            .app_data(web::Data::new(notification_sender.clone()))
+            .app_data(web::Data::new(broker.clone()))
+            .app_data(web::Data::new(tweet_sinks.clone()))
+            .app_data(blob_store.clone())
+            .app_data(web::Data::new(media_processor.clone()))
+            .app_data(web::Data::new(search_index.clone()))
+            .app_data(web::Data::new(user_index.clone()))
+            .app_data(web::Data::new(trends.clone()))
+            .app_data(web::Data::new(trend_sender.clone()))
+            .app_data(log_levels.clone())
+            .app_data(web::Data::new(server_shutdown.clone()))
+            .configure(routes)
+            .default_service(web::to(error::not_found))
     })
     .workers(num_workers)
     .keep_alive(std::time::Duration::from_secs(75))
     .max_connections(50_000)
     .max_connection_rate(20_000)
     .backlog(2048)
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs())
+    .bind("0.0.0.0:8080")?;
+    // With TLS on, the plain HTTP listener only redirects to HTTPS.
+    let server = match &tls {
+        Some(tls) => {
+            let config = tls
+                .server_config(&shutdown)
+                .expect("Failed to load TLS certificate");
+            info!("Serving HTTPS on {}", tls.bind);
+            server.bind_rustls(&tls.bind, config)?
+        }
+        None => server,
+    }
+    .run();
+
+    // Stop taking requests on a signal, letting those in flight finish
+    // and event streams end.
+    let server_handle = server.handle();
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        info!("Shutting down, finishing requests in flight...");
+        signal_shutdown.stop();
+        server_handle.stop(true).await;
+    });
+    server.await?;
+
+    info!("Flushing background queues...");
+    shutdown.drain(SHUTDOWN_TIMEOUT).await;
+    telemetry.shutdown();
+    Ok(())
+}
+
+/// Every route the server answers. The OpenAPI document in `openapi.rs`
+/// lists the same handlers; its tests check the two agree.
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(health::healthz)
+        .service(health::readyz)
+        .service(metrics::metrics)
+        .service(openapi::swagger_ui())
+        .service(
+            web::scope("/admin")
+                .service(logging::get_log_levels)
+                .service(logging::set_log_levels),
+        )
+        .service(
+            web::scope("/api")
+                .service(openapi::openapi_json)
+                .service(handlers::create_user)
+                .service(handlers::create_tweet)
+                .service(handlers::schedule_tweet)
+                .service(handlers::get_scheduled_tweets)
+                .service(handlers::cancel_scheduled_tweet)
+                .service(handlers::create_draft)
+                .service(handlers::get_drafts)
+                .service(handlers::get_draft)
+                .service(handlers::update_draft)
+                .service(handlers::delete_draft)
+                .service(handlers::publish_draft)
+                .service(handlers::like_tweet)
+                .service(handlers::retweet_tweet)
+                .service(handlers::undo_retweet)
+                .service(handlers::reply_to_tweet)
+                .service(handlers::get_replies)
+                .service(handlers::vote_in_poll)
+                .service(handlers::bookmark_tweet)
+                .service(handlers::remove_bookmark)
+                .service(handlers::get_home_feed)
+                .service(handlers::get_user_tweets)
+                .service(handlers::delete_tweet)
+                .service(handlers::search_tweets)
+                .service(handlers::upload_media)
+                .service(handlers::get_media)
+                .service(handlers::get_media_variant)
+                .service(handlers::update_profile)
+                .service(handlers::pin_tweet)
+                .service(handlers::unpin_tweet)
+                .service(handlers::search_users)
+                .service(handlers::get_hashtag_tweets)
+                .service(handlers::get_trends)
+                .service(handlers::get_my_mentions)
+                .service(handlers::get_my_bookmarks)
+                .service(handlers::get_notifications)
+                .service(handlers::get_unread_notification_count)
+                .service(handlers::mark_notifications_read)
+                .service(handlers::stream_events)
+                .service(handlers::follow_user)
+                .service(handlers::unfollow_user)
+                .service(handlers::get_dm_settings)
+                .service(handlers::update_dm_settings)
+                .service(handlers::create_conversation)
+                .service(handlers::get_conversations)
+                .service(handlers::send_message)
+                .service(handlers::get_messages)
+                .service(handlers::mark_conversation_read)
+                .service(handlers::create_list)
+                .service(handlers::get_my_lists)
+                .service(handlers::get_list)
+                .service(handlers::delete_list)
+                .service(handlers::add_list_member)
+                .service(handlers::remove_list_member)
+                .service(handlers::get_list_members)
+                .service(handlers::subscribe_list)
+                .service(handlers::unsubscribe_list)
+                .service(handlers::get_list_tweets),
+        );
 }
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::models::{Media, MediaVariant};
+
+/// Media that can be attached to one tweet.
+pub const MAX_MEDIA_PER_TWEET: usize = 4;
+pub const MAX_ALT_TEXT_CHARS: usize = 1000;
+/// Uploads are read into memory, so nothing larger is accepted at all.
+pub const MAX_UPLOAD_BYTES: usize = MAX_VIDEO_BYTES;
+
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+const MAX_GIF_BYTES: usize = 15 * 1024 * 1024;
+const MAX_VIDEO_BYTES: usize = 50 * 1024 * 1024;
+
+/// An upload's type, decided from its leading bytes rather than whatever
+/// the client claimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Mp4,
+}
+
+impl MediaKind {
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(MediaKind::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(MediaKind::Png)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(MediaKind::Gif)
+        } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+            Some(MediaKind::Webp)
+        } else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+            Some(MediaKind::Mp4)
+        } else {
+            None
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            MediaKind::Jpeg => "image/jpeg",
+            MediaKind::Png => "image/png",
+            MediaKind::Gif => "image/gif",
+            MediaKind::Webp => "image/webp",
+            MediaKind::Mp4 => "video/mp4",
+        }
+    }
+
+    pub fn max_bytes(self) -> usize {
+        match self {
+            MediaKind::Jpeg | MediaKind::Png | MediaKind::Webp => MAX_IMAGE_BYTES,
+            MediaKind::Gif => MAX_GIF_BYTES,
+            MediaKind::Mp4 => MAX_VIDEO_BYTES,
+        }
+    }
+
+    /// Width and height, read from the file header. `None` for video, or if
+    /// the header can't be parsed.
+    pub fn dimensions(self, bytes: &[u8]) -> Option<(u32, u32)> {
+        if self == MediaKind::Mp4 {
+            return None;
+        }
+        let size = imagesize::blob_size(bytes).ok()?;
+        Some((
+            u32::try_from(size.width).ok()?,
+            u32::try_from(size.height).ok()?,
+        ))
+    }
+}
+
+/// A resized copy of an image, as recorded in the database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredVariant {
+    pub name: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl StoredVariant {
+    pub fn to_variant(&self, media_id: Uuid) -> MediaVariant {
+        MediaVariant {
+            name: self.name.clone(),
+            content_type: self.content_type.clone(),
+            url: variant_url(media_id, &self.name),
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// Identifies identical uploads, which share their stored blobs.
+pub fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Where the stored file for `content_hash` lives in the blob store.
+/// `variant` is `None` for the full-size file.
+pub fn blob_key(content_hash: &str, variant: Option<&str>) -> String {
+    format!("media/{}/{}", content_hash, variant.unwrap_or("original"))
+}
+
+/// Where clients fetch the media from.
+pub fn media_url(media_id: Uuid) -> String {
+    format!("/api/media/{}", media_id)
+}
+
+pub fn variant_url(media_id: Uuid, variant: &str) -> String {
+    format!("/api/media/{}/{}", media_id, variant)
+}
+
+/// What is known about a stored file, shared by every upload of it.
+#[derive(Debug, Clone)]
+pub struct MediaBlob {
+    pub content_type: String,
+    pub size_bytes: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub blurhash: Option<String>,
+    pub variants: Vec<StoredVariant>,
+}
+
+/// A row of the `media` table.
+pub struct StoredMedia {
+    pub user_id: Uuid,
+    pub content_hash: String,
+    pub media: Media,
+}
+
+impl MediaBlob {
+    pub fn to_media(&self, media_id: Uuid, alt_text: Option<String>) -> Media {
+        Media {
+            media_id,
+            content_type: self.content_type.clone(),
+            url: media_url(media_id),
+            size_bytes: self.size_bytes,
+            width: self.width,
+            height: self.height,
+            alt_text,
+            blurhash: self.blurhash.clone(),
+            variants: self
+                .variants
+                .iter()
+                .map(|variant| variant.to_variant(media_id))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_are_sniffed_by_magic_number() {
+        let cases: [(&[u8], Option<MediaKind>); 9] = [
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", Some(MediaKind::Jpeg)),
+            (
+                b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR",
+                Some(MediaKind::Png),
+            ),
+            (b"GIF87a\x01\x00", Some(MediaKind::Gif)),
+            (b"GIF89a\x01\x00", Some(MediaKind::Gif)),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", Some(MediaKind::Webp)),
+            (b"\x00\x00\x00\x18ftypmp42", Some(MediaKind::Mp4)),
+            (
+                b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00",
+                Some(MediaKind::Mp4),
+            ),
+            (b"<svg xmlns=", None),
+            (b"", None),
+        ];
+        for (bytes, kind) in cases {
+            assert_eq!(MediaKind::sniff(bytes), kind, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn truncated_headers_are_not_recognized() {
+        // RIFF without room for the WEBP form type.
+        assert_eq!(MediaKind::sniff(b"RIFF\x24\x00\x00\x00WEB"), None);
+        // Other RIFF formats aren't images.
+        assert_eq!(MediaKind::sniff(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        // An ftyp box needs its 12-byte header.
+        assert_eq!(MediaKind::sniff(b"\x00\x00\x00\x18ftypmp4"), None);
+        assert_eq!(MediaKind::sniff(b"\x00\x00\x00\x18ftyp"), None);
+        assert_eq!(MediaKind::sniff(b"\xFF\xD8"), None);
+        assert_eq!(MediaKind::sniff(b"\x89PNG\r\n"), None);
+        assert_eq!(MediaKind::sniff(b"GIF8"), None);
+    }
+
+    #[test]
+    fn size_limits_depend_on_the_kind() {
+        assert_eq!(MediaKind::Jpeg.max_bytes(), 5 * 1024 * 1024);
+        assert_eq!(MediaKind::Png.max_bytes(), 5 * 1024 * 1024);
+        assert_eq!(MediaKind::Webp.max_bytes(), 5 * 1024 * 1024);
+        assert_eq!(MediaKind::Gif.max_bytes(), 15 * 1024 * 1024);
+        assert_eq!(MediaKind::Mp4.max_bytes(), 50 * 1024 * 1024);
+        assert_eq!(MAX_UPLOAD_BYTES, MediaKind::Mp4.max_bytes());
+    }
+}
@@ -0,0 +1,368 @@
+use bytes::Bytes;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+use log::error;
+use std::fmt;
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+use crate::media::{MediaKind, StoredVariant};
+
+/// Images with more pixels than this are rejected before decoding, so a
+/// small file that expands to gigabytes (a decompression bomb) is never
+/// allocated.
+const MAX_PIXELS: u64 = 40_000_000;
+const MAX_SIDE: u32 = 16_384;
+/// Upper bound on what a decoder may allocate, in case a header lies.
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+/// The stored original is downscaled to fit within this.
+const MAX_ORIGINAL_SIDE: u32 = 4096;
+const JPEG_QUALITY: u8 = 85;
+/// Blurhash components across and down; 4x3 suits most aspect ratios.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// Variants generated for every image, as (name, longest side).
+const VARIANTS: [(&str, u32); 2] = [("thumb", 150), ("medium", 1200)];
+
+#[derive(Debug)]
+pub enum ProcessError {
+    /// Every worker is busy and the queue is full.
+    Busy,
+    TooManyPixels,
+    Invalid(String),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Busy => write!(f, "media processing queue is full"),
+            ProcessError::TooManyPixels => write!(f, "image dimensions are too large"),
+            ProcessError::Invalid(reason) => write!(f, "invalid image: {}", reason),
+        }
+    }
+}
+
+impl From<ImageError> for ProcessError {
+    fn from(e: ImageError) -> Self {
+        match e {
+            ImageError::Limits(_) => ProcessError::TooManyPixels,
+            e => ProcessError::Invalid(e.to_string()),
+        }
+    }
+}
+
+/// A decoded, cleaned and re-encoded image with its variants.
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+    pub original: Bytes,
+    pub variants: Vec<(StoredVariant, Bytes)>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads for CPU-heavy image work, fed through a bounded
+/// queue so a burst of uploads can't starve the actix workers or pile up
+/// unbounded work.
+#[derive(Clone)]
+pub struct MediaProcessor {
+    jobs: SyncSender<Job>,
+}
+
+impl MediaProcessor {
+    pub fn new(workers: usize, queue_capacity: usize) -> Self {
+        let (jobs, receiver) = mpsc::sync_channel::<Job>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("media-worker-{}", i))
+                .spawn(move || run_worker(&receiver))
+                .expect("Failed to start media worker");
+        }
+        MediaProcessor { jobs }
+    }
+
+    pub async fn process_image(
+        &self,
+        kind: MediaKind,
+        bytes: Bytes,
+    ) -> Result<ProcessedImage, ProcessError> {
+        let (result_sender, result) = oneshot::channel();
+        self.jobs
+            .try_send(Box::new(move || {
+                let _ = result_sender.send(process_image(kind, &bytes));
+            }))
+            .map_err(|_| ProcessError::Busy)?;
+        // The sender is only dropped unanswered if the job panicked.
+        result
+            .await
+            .unwrap_or_else(|_| Err(ProcessError::Invalid("processing failed".to_string())))
+    }
+}
+
+fn run_worker(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("Media processing job panicked");
+        }
+    }
+}
+
+/// Decodes an image, applies and drops its EXIF orientation along with all
+/// other metadata, and re-encodes it with its variants and blurhash.
+fn process_image(kind: MediaKind, bytes: &[u8]) -> Result<ProcessedImage, ProcessError> {
+    let format = match kind {
+        MediaKind::Jpeg => ImageFormat::Jpeg,
+        MediaKind::Png => ImageFormat::Png,
+        MediaKind::Webp => ImageFormat::WebP,
+        MediaKind::Gif | MediaKind::Mp4 => {
+            return Err(ProcessError::Invalid("not a still image".to_string()))
+        }
+    };
+
+    // Cheap header check first; the decoder limits below back it up.
+    let (width, height) = kind
+        .dimensions(bytes)
+        .ok_or_else(|| ProcessError::Invalid("unreadable image header".to_string()))?;
+    if width > MAX_SIDE || height > MAX_SIDE || width as u64 * height as u64 > MAX_PIXELS {
+        return Err(ProcessError::TooManyPixels);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SIDE);
+    limits.max_image_height = Some(MAX_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    if image.width().max(image.height()) > MAX_ORIGINAL_SIDE {
+        image = image.resize(MAX_ORIGINAL_SIDE, MAX_ORIGINAL_SIDE, FilterType::Lanczos3);
+    }
+
+    // Keep transparency where there is any; everything else becomes JPEG.
+    let keep_alpha = image.has_alpha();
+    let (content_type, original) = encode(&image, keep_alpha)?;
+
+    let mut variants = Vec::with_capacity(VARIANTS.len());
+    let mut blurhash = None;
+    for (name, side) in VARIANTS {
+        let variant = if image.width().max(image.height()) > side {
+            image.resize(side, side, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        if name == "thumb" {
+            let rgba = variant.to_rgba8();
+            let (x, y) = BLURHASH_COMPONENTS;
+            blurhash = blurhash::encode(x, y, rgba.width(), rgba.height(), rgba.as_raw()).ok();
+        }
+        let (content_type, bytes) = encode(&variant, keep_alpha)?;
+        variants.push((
+            StoredVariant {
+                name: name.to_string(),
+                content_type: content_type.to_string(),
+                width: variant.width(),
+                height: variant.height(),
+            },
+            bytes,
+        ));
+    }
+
+    Ok(ProcessedImage {
+        content_type,
+        width: image.width(),
+        height: image.height(),
+        blurhash,
+        original,
+        variants,
+    })
+}
+
+fn encode(image: &DynamicImage, keep_alpha: bool) -> Result<(&'static str, Bytes), ImageError> {
+    let mut buffer = Vec::new();
+    if keep_alpha {
+        image
+            .to_rgba8()
+            .write_with_encoder(PngEncoder::new(&mut buffer))?;
+        Ok(("image/png", Bytes::from(buffer)))
+    } else {
+        image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY))?;
+        Ok(("image/jpeg", Bytes::from(buffer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    /// A PNG that is nothing but a header claiming `width` by `height`.
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.extend_from_slice(&13u32.to_be_bytes());
+        bytes.extend_from_slice(b"IHDR");
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        // 8-bit RGB, default compression, filtering and no interlacing.
+        bytes.extend_from_slice(&[8, 2, 0, 0, 0]);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes
+    }
+
+    /// An APP1 segment holding only an EXIF orientation tag.
+    fn exif_orientation(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"MM\x00\x2a\x00\x00\x00\x08".to_vec();
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0; 2]);
+        tiff.extend_from_slice(&[0; 4]);
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(2 + 6 + tiff.len() as u16).to_be_bytes());
+        segment.extend_from_slice(b"Exif\x00\x00");
+        segment.extend_from_slice(&tiff);
+        segment
+    }
+
+    fn jpeg(image: &RgbImage) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        image
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 95))
+            .unwrap();
+        buffer
+    }
+
+    fn png(image: &DynamicImage) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        image
+            .write_with_encoder(PngEncoder::new(&mut buffer))
+            .unwrap();
+        buffer
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn oversized_headers_are_rejected_before_decoding() {
+        let bomb = png_header(10_000, 10_000);
+        assert!(bomb.len() < 64);
+        assert!(matches!(
+            process_image(MediaKind::Png, &bomb),
+            Err(ProcessError::TooManyPixels)
+        ));
+        assert!(matches!(
+            process_image(MediaKind::Png, &png_header(MAX_SIDE + 1, 1)),
+            Err(ProcessError::TooManyPixels)
+        ));
+    }
+
+    #[test]
+    fn exif_orientation_is_applied_and_dropped() {
+        // Red on the left, blue on the right.
+        let image = RgbImage::from_fn(64, 32, |x, _| match x < 32 {
+            true => Rgb([255, 0, 0]),
+            false => Rgb([0, 0, 255]),
+        });
+        let plain = jpeg(&image);
+        // Orientation 6: the camera was turned a quarter clockwise.
+        let mut rotated = plain[..2].to_vec();
+        rotated.extend_from_slice(&exif_orientation(6));
+        rotated.extend_from_slice(&plain[2..]);
+
+        let processed = process_image(MediaKind::Jpeg, &rotated).unwrap();
+        assert_eq!((processed.width, processed.height), (32, 64));
+        assert!(!contains(&processed.original, b"Exif"));
+        let decoded = image::load_from_memory(&processed.original)
+            .unwrap()
+            .to_rgb8();
+        let top = decoded.get_pixel(16, 8);
+        let bottom = decoded.get_pixel(16, 56);
+        assert!(top[0] > 200 && top[2] < 60, "top is {:?}", top);
+        assert!(bottom[2] > 200 && bottom[0] < 60, "bottom is {:?}", bottom);
+    }
+
+    #[test]
+    fn variants_keep_the_aspect_ratio() {
+        let image = RgbImage::from_pixel(3000, 1500, Rgb([10, 200, 30]));
+        let processed = process_image(MediaKind::Jpeg, &jpeg(&image)).unwrap();
+        assert_eq!(processed.content_type, "image/jpeg");
+        assert_eq!((processed.width, processed.height), (3000, 1500));
+        assert!(processed.blurhash.is_some());
+        let sizes: Vec<(&str, &str, u32, u32)> = processed
+            .variants
+            .iter()
+            .map(|(variant, _)| {
+                (
+                    variant.name.as_str(),
+                    variant.content_type.as_str(),
+                    variant.width,
+                    variant.height,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                ("thumb", "image/jpeg", 150, 75),
+                ("medium", "image/jpeg", 1200, 600)
+            ]
+        );
+    }
+
+    #[test]
+    fn small_images_are_not_upscaled() {
+        let image = RgbImage::from_pixel(100, 40, Rgb([0, 0, 0]));
+        let processed = process_image(MediaKind::Jpeg, &jpeg(&image)).unwrap();
+        assert!(processed
+            .variants
+            .iter()
+            .all(|(variant, _)| (variant.width, variant.height) == (100, 40)));
+    }
+
+    #[test]
+    fn transparency_keeps_png_and_opaque_pngs_become_jpeg() {
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::from_fn(300, 200, |x, _| {
+            Rgba([0, 0, 0, (x % 256) as u8])
+        }));
+        let processed = process_image(MediaKind::Png, &png(&transparent)).unwrap();
+        assert_eq!(processed.content_type, "image/png");
+        assert!(processed.original.starts_with(b"\x89PNG"));
+        assert!(processed
+            .variants
+            .iter()
+            .all(|(variant, bytes)| variant.content_type == "image/png"
+                && bytes.starts_with(b"\x89PNG")));
+        let (thumb, _) = &processed.variants[0];
+        assert_eq!((thumb.width, thumb.height), (150, 100));
+
+        let opaque = DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 200, Rgb([1, 2, 3])));
+        let processed = process_image(MediaKind::Png, &png(&opaque)).unwrap();
+        assert_eq!(processed.content_type, "image/jpeg");
+        assert!(processed.original.starts_with(&[0xFF, 0xD8, 0xFF]));
+    }
+}
@@ -0,0 +1,242 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{get, web, HttpResponse};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use crate::error::ApiError;
+use crate::notifications::NotificationSender;
+use crate::trends::TrendSender;
+
+/// Buckets for database round trips and pool waits, finer than the
+/// defaults at the low end.
+const DB_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests handled"),
+        &["method", "route", "status"],
+    ))
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "Time to produce an HTTP response",
+        ),
+        &["method", "route"],
+    ))
+});
+
+static HTTP_REQUESTS_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new(
+        "http_requests_in_flight",
+        "HTTP requests being handled",
+    ))
+});
+
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("db_query_duration_seconds", "Time to run a CQL statement")
+            .buckets(DB_BUCKETS.to_vec()),
+        &["statement"],
+    ))
+});
+
+static DB_QUERY_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("db_query_errors_total", "CQL statements that failed"),
+        &["statement"],
+    ))
+});
+
+static DB_POOL_WAIT: LazyLock<Histogram> = LazyLock::new(|| {
+    register(Histogram::with_opts(
+        HistogramOpts::new(
+            "db_pool_wait_seconds",
+            "Time spent waiting to check out the session pool",
+        )
+        .buckets(DB_BUCKETS.to_vec()),
+    ))
+});
+
+static FANOUT_QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new(
+            "fanout_queue_depth",
+            "Events waiting in a background fan-out queue",
+        ),
+        &["queue"],
+    ))
+});
+
+static FANOUT_EVENTS_DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "fanout_events_dropped_total",
+            "Events dropped because a fan-out queue was full",
+        ),
+        &["queue"],
+    ))
+});
+
+/// Adds a metric to the registry. The metrics are fixed, so any error is
+/// a bug.
+fn register<C: Collector + Clone + 'static>(collector: prometheus::Result<C>) -> C {
+    let collector = collector.expect("invalid metric");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric registered twice");
+    collector
+}
+
+/// What a request is counted under: its method and route pattern, so that
+/// ids in paths don't each get their own series.
+pub struct RequestLabels {
+    method: String,
+    route: String,
+}
+
+impl RequestLabels {
+    pub fn of(req: &ServiceRequest) -> Self {
+        RequestLabels {
+            method: req.method().to_string(),
+            route: route(req),
+        }
+    }
+}
+
+/// The route pattern a request matched, e.g. `/api/tweets/{tweet_id}`.
+pub fn route(req: &ServiceRequest) -> String {
+    req.match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string())
+}
+
+/// Counts a request as in flight for as long as it lives, including when
+/// the client goes away and it is dropped.
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        HTTP_REQUESTS_IN_FLIGHT.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        HTTP_REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+/// Times and counts a request. Used as middleware around the app.
+pub async fn track_request<B>(
+    labels: RequestLabels,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let RequestLabels { method, route } = labels;
+    let started = Instant::now();
+    let in_flight = InFlight::start();
+    let response = response.await;
+    drop(in_flight);
+
+    let status = match &response {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, status.as_str()])
+        .inc();
+    response
+}
+
+/// Records a statement run, under its `statement_label`.
+pub fn observe_query(statement: &str, started: Instant, succeeded: bool) {
+    DB_QUERY_DURATION
+        .with_label_values(&[statement])
+        .observe(started.elapsed().as_secs_f64());
+    if !succeeded {
+        DB_QUERY_ERRORS.with_label_values(&[statement]).inc();
+    }
+}
+
+pub fn observe_pool_wait(started: Instant) {
+    DB_POOL_WAIT.observe(started.elapsed().as_secs_f64());
+}
+
+/// Names a CQL statement by its verb and table, e.g. `SELECT tweets`.
+pub fn statement_label(cql: &str) -> String {
+    let mut words = cql.split_whitespace();
+    let verb = words.next().unwrap_or_default().to_ascii_uppercase();
+    let table = match verb.as_str() {
+        "UPDATE" => words.next(),
+        "INSERT" => words
+            .skip_while(|word| !word.eq_ignore_ascii_case("INTO"))
+            .nth(1),
+        _ => words
+            .skip_while(|word| !word.eq_ignore_ascii_case("FROM"))
+            .nth(1),
+    };
+    match table {
+        Some(table) => {
+            let table = table.rsplit('.').next().unwrap_or(table);
+            let table = table.split('(').next().unwrap_or(table);
+            format!("{} {}", verb, table)
+        }
+        None => verb,
+    }
+}
+
+#[utoipa::path(
+    tag = "Monitoring",
+    summary = "Get Prometheus metrics",
+    responses((
+        status = 200,
+        description = "Metrics in the Prometheus text format",
+        body = String,
+        content_type = "text/plain"
+    ))
+)]
+#[get("/metrics")]
+pub async fn metrics(
+    notifications: web::Data<NotificationSender>,
+    trend_sender: web::Data<TrendSender>,
+) -> Result<HttpResponse, ApiError> {
+    for (queue, queued, dropped) in [
+        (
+            "notifications",
+            notifications.queued(),
+            notifications.dropped(),
+        ),
+        ("trends", trend_sender.queued(), trend_sender.dropped()),
+    ] {
+        FANOUT_QUEUE_DEPTH
+            .with_label_values(&[queue])
+            .set(queued as i64);
+        // The senders count drops themselves; catch the counter up.
+        let counter = FANOUT_EVENTS_DROPPED.with_label_values(&[queue]);
+        counter.inc_by(dropped.saturating_sub(counter.get()));
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut body)
+        .map_err(|e| ApiError::Internal(format!("Failed to encode metrics: {:?}", e)))?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body))
+}
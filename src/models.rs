@@ -1,37 +1,417 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::trends::{Trend, TrendWindow};
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct User {
     pub user_id: Uuid,
     pub username: String,
+    pub display_name: Option<String>,
     pub email: String,
     #[serde(skip_serializing)]
+    #[schema(ignore)]
     pub password_hash: String,
     #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
     pub created_at: DateTime<Utc>,
     #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Tweet {
     pub tweet_id: Uuid,
     pub user_id: Uuid,
     pub content: String,
     #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
     pub created_at: DateTime<Utc>,
+    pub entities: TweetEntities,
+    pub media: Vec<Media>,
+    #[serde(default)]
+    pub poll: Option<Poll>,
+    /// The tweet this one replies to.
+    #[serde(default)]
+    pub in_reply_to_tweet_id: Option<Uuid>,
+    /// Set on the author's pinned tweet when listing their tweets.
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// A poll attached to a tweet. Vote counts are `None` while its results
+/// are hidden from the viewer.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Poll {
+    pub options: Vec<PollOption>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub ends_at: DateTime<Utc>,
+    pub closed: bool,
+    /// Results are only shown to voters and the author until it closes.
+    pub hide_results: bool,
+    pub total_votes: Option<i64>,
+    /// Index of the option the viewer voted for.
+    pub voted_option: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PollOption {
+    pub label: String,
+    pub votes: Option<i64>,
+}
+
+/// An uploaded image or video, as attached to tweets.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Media {
+    pub media_id: Uuid,
+    pub content_type: String,
+    pub url: String,
+    pub size_bytes: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub alt_text: Option<String>,
+    /// Compact placeholder to show while the image loads.
+    pub blurhash: Option<String>,
+    /// Smaller copies of images ("thumb", "medium").
+    pub variants: Vec<MediaVariant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MediaVariant {
+    pub name: String,
+    pub content_type: String,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct TweetEntities {
+    pub hashtags: Vec<HashtagEntity>,
+    pub mentions: Vec<MentionEntity>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HashtagEntity {
+    pub tag: String,
+    pub start: usize,
+    pub end: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MentionEntity {
+    pub username: String,
+    pub user_id: Uuid,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A page of tweets in reverse chronological order. `next_cursor` is passed
+/// back as `before` to fetch the following page.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TweetPage {
+    pub tweets: Vec<Tweet>,
+    pub next_cursor: Option<String>,
+}
+
+/// Search results. `next_offset` is passed back as `offset` to fetch the
+/// following page.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TweetSearchResults {
+    pub tweets: Vec<Tweet>,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TrendList {
+    pub window: TrendWindow,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub as_of: DateTime<Utc>,
+    pub trends: Vec<Trend>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct CreateUserRequest {
     pub username: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
     pub email: String,
     pub password: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PinTweetRequest {
+    pub tweet_id: Uuid,
+}
+
+/// A blank `display_name` clears it.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserSummary {
+    pub user_id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub follower_count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserSearchResults {
+    pub users: Vec<UserSummary>,
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct CreateTweetRequest {
     pub content: String,
+    #[serde(default)]
+    pub media_ids: Vec<Uuid>,
+    #[serde(default)]
+    pub poll: Option<CreatePollRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CreatePollRequest {
+    pub options: Vec<String>,
+    pub duration_minutes: u32,
+    #[serde(default)]
+    pub hide_results: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VoteRequest {
+    pub option: usize,
+}
+
+/// A tweet to publish later. `publish_at` is in milliseconds.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleTweetRequest {
+    #[serde(flatten)]
+    pub tweet: CreateTweetRequest,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub publish_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleStatus {
+    Scheduled,
+    /// Claimed by a server that is publishing it right now.
+    Publishing,
+    /// Could not be published, e.g. because its media was deleted.
+    Failed,
+}
+
+impl ScheduleStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScheduleStatus::Scheduled => "scheduled",
+            ScheduleStatus::Publishing => "publishing",
+            ScheduleStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "scheduled" => Some(ScheduleStatus::Scheduled),
+            "publishing" => Some(ScheduleStatus::Publishing),
+            "failed" => Some(ScheduleStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A scheduled tweet that hasn't been published yet. Once published, the
+/// tweet has `scheduled_id` as its id.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ScheduledTweet {
+    pub scheduled_id: Uuid,
+    pub user_id: Uuid,
+    pub content: String,
+    pub media_ids: Vec<Uuid>,
+    pub poll: Option<CreatePollRequest>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub publish_at: DateTime<Utc>,
+    pub status: ScheduleStatus,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// An unpublished tweet, kept until it is posted or deleted.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Draft {
+    pub draft_id: Uuid,
+    pub content: String,
+    pub media_ids: Vec<Uuid>,
+    pub poll: Option<CreatePollRequest>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One or more notifications about the same thing, newest first.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NotificationGroup {
+    pub kind: String,
+    pub tweet_id: Option<Uuid>,
+    pub actor_ids: Vec<Uuid>,
+    pub message: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub latest_at: DateTime<Utc>,
+    pub read: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct NotificationPage {
+    pub notifications: Vec<NotificationGroup>,
+    pub unread_count: i64,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UnreadCount {
+    pub unread_count: i64,
+}
+
+/// Marks everything up to `up_to` (milliseconds, default now) read.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct MarkReadRequest {
+    pub up_to: Option<i64>,
+}
+
+/// Who may start a direct message conversation with a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DmAllowFrom {
+    Following,
+    Everyone,
+}
+
+impl DmAllowFrom {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DmAllowFrom::Following => "following",
+            DmAllowFrom::Everyone => "everyone",
+        }
+    }
+
+    pub fn parse(allow_from: &str) -> Option<Self> {
+        match allow_from {
+            "following" => Some(DmAllowFrom::Following),
+            "everyone" => Some(DmAllowFrom::Everyone),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DmSettings {
+    pub allow_from: DmAllowFrom,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Conversation {
+    pub conversation_id: Uuid,
+    pub participant_ids: Vec<Uuid>,
+    pub is_group: bool,
+    pub created_by: Uuid,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DirectMessage {
+    pub message_id: Uuid,
+    pub conversation_id: Uuid,
+    pub sender_id: Uuid,
+    pub content: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// A page of messages, newest first, with how far each participant has read
+/// (milliseconds).
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MessagePage {
+    pub messages: Vec<DirectMessage>,
+    pub read_markers: HashMap<Uuid, i64>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateConversationRequest {
+    pub participant_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SendMessageRequest {
+    pub content: String,
+}
+
+/// A curated list of accounts. Private lists are only visible to their
+/// owner.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct List {
+    pub list_id: Uuid,
+    pub owner_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub private: bool,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    #[schema(value_type = i64)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateListRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub private: bool,
+}
+
+/// Lists a user owns and public lists they subscribe to.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserLists {
+    pub owned: Vec<List>,
+    pub subscribed: Vec<List>,
+}
+
+/// A log filter in `RUST_LOG` syntax, e.g. `info,twitter_clone::handlers=debug`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LogFilter {
+    pub filter: String,
+}
+
+/// Whether the server can take traffic, and the checks that decided it.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: Option<String>,
 }
@@ -0,0 +1,543 @@
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use scylla::{
+    frame::response::result::CqlValue, frame::value::CqlTimestamp, transport::errors::QueryError,
+};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::db::{DbPool, Session};
+use crate::models::NotificationGroup;
+use crate::shutdown::Shutdown;
+use crate::stream::Broker;
+
+/// Events waiting to be written are dropped once this many are queued, so a
+/// slow cluster can't make `like_tweet` or `create_tweet` block.
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationKind {
+    Like,
+    Mention,
+    Follow,
+    Reply,
+    Retweet,
+    PollClosed,
+}
+
+impl NotificationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotificationKind::Like => "like",
+            NotificationKind::Mention => "mention",
+            NotificationKind::Follow => "follow",
+            NotificationKind::Reply => "reply",
+            NotificationKind::Retweet => "retweet",
+            NotificationKind::PollClosed => "poll_closed",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "like" => Some(NotificationKind::Like),
+            "mention" => Some(NotificationKind::Mention),
+            "follow" => Some(NotificationKind::Follow),
+            "reply" => Some(NotificationKind::Reply),
+            "retweet" => Some(NotificationKind::Retweet),
+            "poll_closed" => Some(NotificationKind::PollClosed),
+            _ => None,
+        }
+    }
+
+    /// Whether notifications of this kind about the same tweet are collapsed
+    /// into one entry ("X and 5 others liked your tweet").
+    fn is_grouped(self) -> bool {
+        matches!(self, NotificationKind::Like | NotificationKind::Retweet)
+    }
+}
+
+/// Something a user did that other users should hear about. Recipients are
+/// resolved by the background producer, off the request path.
+#[derive(Debug)]
+pub enum NotificationEvent {
+    Liked {
+        actor_id: Uuid,
+        tweet_id: Uuid,
+        created_at: DateTime<Utc>,
+    },
+    Mentioned {
+        actor_id: Uuid,
+        tweet_id: Uuid,
+        mentioned_user_ids: Vec<Uuid>,
+        created_at: DateTime<Utc>,
+    },
+    Followed {
+        actor_id: Uuid,
+        followed_id: Uuid,
+        created_at: DateTime<Utc>,
+    },
+    /// `tweet_id` is the reply, which the notification points to.
+    Replied {
+        actor_id: Uuid,
+        tweet_id: Uuid,
+        in_reply_to_tweet_id: Uuid,
+        created_at: DateTime<Utc>,
+    },
+    Retweeted {
+        actor_id: Uuid,
+        tweet_id: Uuid,
+        created_at: DateTime<Utc>,
+    },
+    PollClosed {
+        author_id: Uuid,
+        tweet_id: Uuid,
+        closed_at: DateTime<Utc>,
+    },
+}
+
+#[derive(Clone)]
+pub struct NotificationSender {
+    sender: mpsc::Sender<NotificationEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl NotificationSender {
+    pub fn send(&self, event: NotificationEvent) {
+        if let Err(e) = self.sender.try_send(event) {
+            warn!("Dropping notification event: {}", e);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Events waiting to be written.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Events dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Starts the task that turns `NotificationEvent`s into rows of the
+/// `notifications` table and pushes them to the recipients' streams. It
+/// writes whatever is still queued before the server exits.
+pub fn spawn_producer(db_pool: DbPool, broker: Broker, shutdown: &Shutdown) -> NotificationSender {
+    let (sender, mut receiver) = mpsc::channel(QUEUE_CAPACITY);
+
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        while let Some(event) = task_shutdown.recv(&mut receiver).await {
+            // Writing takes several statements per recipient, so it runs
+            // without holding the pool.
+            if let Some(session) = db_pool.session().await {
+                if let Err(e) = write_notifications(&session, &broker, &event).await {
+                    error!("Failed to write notifications for {:?}: {:?}", event, e);
+                }
+            } else {
+                error!("No database session available for notifications");
+            }
+        }
+    });
+
+    NotificationSender {
+        sender,
+        dropped: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+async fn write_notifications(
+    session: &Session,
+    broker: &Broker,
+    event: &NotificationEvent,
+) -> Result<(), QueryError> {
+    match event {
+        NotificationEvent::Liked {
+            actor_id,
+            tweet_id,
+            created_at,
+        } => {
+            if let Some(author_id) = fetch_tweet_author(session, *tweet_id).await? {
+                insert_notification(
+                    session,
+                    broker,
+                    author_id,
+                    NotificationKind::Like,
+                    *actor_id,
+                    Some(*tweet_id),
+                    *created_at,
+                )
+                .await?;
+            }
+        }
+        NotificationEvent::Mentioned {
+            actor_id,
+            tweet_id,
+            mentioned_user_ids,
+            created_at,
+        } => {
+            for &user_id in mentioned_user_ids {
+                insert_notification(
+                    session,
+                    broker,
+                    user_id,
+                    NotificationKind::Mention,
+                    *actor_id,
+                    Some(*tweet_id),
+                    *created_at,
+                )
+                .await?;
+            }
+        }
+        NotificationEvent::Followed {
+            actor_id,
+            followed_id,
+            created_at,
+        } => {
+            insert_notification(
+                session,
+                broker,
+                *followed_id,
+                NotificationKind::Follow,
+                *actor_id,
+                None,
+                *created_at,
+            )
+            .await?;
+        }
+        NotificationEvent::Replied {
+            actor_id,
+            tweet_id,
+            in_reply_to_tweet_id,
+            created_at,
+        } => {
+            if let Some(author_id) = fetch_tweet_author(session, *in_reply_to_tweet_id).await? {
+                insert_notification(
+                    session,
+                    broker,
+                    author_id,
+                    NotificationKind::Reply,
+                    *actor_id,
+                    Some(*tweet_id),
+                    *created_at,
+                )
+                .await?;
+            }
+        }
+        NotificationEvent::Retweeted {
+            actor_id,
+            tweet_id,
+            created_at,
+        } => {
+            if let Some(author_id) = fetch_tweet_author(session, *tweet_id).await? {
+                insert_notification(
+                    session,
+                    broker,
+                    author_id,
+                    NotificationKind::Retweet,
+                    *actor_id,
+                    Some(*tweet_id),
+                    *created_at,
+                )
+                .await?;
+            }
+        }
+        NotificationEvent::PollClosed {
+            author_id,
+            tweet_id,
+            closed_at,
+        } => {
+            insert_notification(
+                session,
+                broker,
+                *author_id,
+                NotificationKind::PollClosed,
+                *author_id,
+                Some(*tweet_id),
+                *closed_at,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn insert_notification(
+    session: &Session,
+    broker: &Broker,
+    user_id: Uuid,
+    kind: NotificationKind,
+    actor_id: Uuid,
+    tweet_id: Option<Uuid>,
+    created_at: DateTime<Utc>,
+) -> Result<(), QueryError> {
+    // Nobody needs to be told about their own actions. A closed poll is the
+    // one notification whose actor is also its recipient.
+    if user_id == actor_id && kind != NotificationKind::PollClosed {
+        return Ok(());
+    }
+
+    let notification_id = Uuid::new_v4();
+    let cql_timestamp = CqlTimestamp(created_at.timestamp_millis());
+    // Indexed by tweet first, so that deleting the tweet finds it.
+    if let Some(tweet_id) = tweet_id {
+        session
+            .query(
+                "INSERT INTO twitter_clone.notifications_by_tweet (tweet_id, user_id, created_at, notification_id) VALUES (?, ?, ?, ?)",
+                (tweet_id, user_id, cql_timestamp, notification_id),
+            )
+            .await?;
+    }
+    session
+        .query(
+            "INSERT INTO twitter_clone.notifications (user_id, created_at, notification_id, kind, actor_id, tweet_id) VALUES (?, ?, ?, ?, ?, ?)",
+            (
+                user_id,
+                cql_timestamp,
+                notification_id,
+                kind.as_str(),
+                actor_id,
+                tweet_id,
+            ),
+        )
+        .await?;
+
+    broker.publish(
+        user_id,
+        "notification",
+        &json!({
+            "kind": kind.as_str(),
+            "actor_id": actor_id,
+            "tweet_id": tweet_id,
+            "created_at": created_at.timestamp_millis(),
+        }),
+    );
+    Ok(())
+}
+
+/// Deletes the notifications about a tweet, for when it is deleted.
+pub async fn delete_tweet_notifications(
+    session: &Session,
+    tweet_id: Uuid,
+) -> Result<(), QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id, created_at, notification_id FROM twitter_clone.notifications_by_tweet WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    for row in result.rows.unwrap_or_default() {
+        let mut columns = row.columns.into_iter();
+        if let (
+            Some(CqlValue::Uuid(user_id)),
+            Some(CqlValue::Timestamp(created_at)),
+            Some(CqlValue::Uuid(notification_id)),
+        ) = (
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+        ) {
+            session
+                .query(
+                    "DELETE FROM twitter_clone.notifications WHERE user_id = ? AND created_at = ? AND notification_id = ?",
+                    (user_id, created_at, notification_id),
+                )
+                .await?;
+        }
+    }
+    session
+        .query(
+            "DELETE FROM twitter_clone.notifications_by_tweet WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn fetch_tweet_author(session: &Session, tweet_id: Uuid) -> Result<Option<Uuid>, QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id FROM twitter_clone.tweets WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|row| match row.columns.first() {
+            Some(Some(CqlValue::Uuid(user_id))) => Some(*user_id),
+            _ => None,
+        }))
+}
+
+/// A row of the `notifications` table.
+pub struct StoredNotification {
+    pub notification_id: Uuid,
+    pub kind: NotificationKind,
+    pub actor_id: Uuid,
+    pub tweet_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Collapses notifications (newest first) into groups, keeping the order of
+/// each group's newest member. `last_read_at` decides which groups are read.
+pub fn group_notifications(
+    notifications: Vec<StoredNotification>,
+    last_read_at: DateTime<Utc>,
+) -> Vec<NotificationGroup> {
+    let mut groups: Vec<NotificationGroup> = Vec::new();
+
+    for notification in notifications {
+        let existing = groups.iter_mut().find(|group| {
+            notification.kind.is_grouped()
+                && group.kind == notification.kind.as_str()
+                && group.tweet_id == notification.tweet_id
+        });
+
+        match existing {
+            Some(group) => {
+                if !group.actor_ids.contains(&notification.actor_id) {
+                    group.actor_ids.push(notification.actor_id);
+                }
+                group.read &= notification.created_at <= last_read_at;
+            }
+            None => groups.push(NotificationGroup {
+                kind: notification.kind.as_str().to_string(),
+                tweet_id: notification.tweet_id,
+                actor_ids: vec![notification.actor_id],
+                message: String::new(),
+                latest_at: notification.created_at,
+                read: notification.created_at <= last_read_at,
+            }),
+        }
+    }
+
+    groups
+}
+
+/// Renders e.g. "alice and 5 others liked your tweet".
+pub fn describe(kind: NotificationKind, first_actor: &str, actor_count: usize) -> String {
+    let actors = match actor_count {
+        0 | 1 => first_actor.to_string(),
+        2 => format!("{} and 1 other", first_actor),
+        n => format!("{} and {} others", first_actor, n - 1),
+    };
+    match kind {
+        NotificationKind::Like => format!("{} liked your tweet", actors),
+        NotificationKind::Mention => format!("{} mentioned you", actors),
+        NotificationKind::Follow => format!("{} followed you", actors),
+        NotificationKind::Reply => format!("{} replied to your tweet", actors),
+        NotificationKind::Retweet => format!("{} retweeted your tweet", actors),
+        NotificationKind::PollClosed => "Your poll has ended".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(second: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(second, 0).unwrap()
+    }
+
+    fn stored(
+        kind: NotificationKind,
+        actor: u128,
+        tweet: Option<u128>,
+        second: i64,
+    ) -> StoredNotification {
+        StoredNotification {
+            notification_id: Uuid::new_v4(),
+            kind,
+            actor_id: Uuid::from_u128(actor),
+            tweet_id: tweet.map(Uuid::from_u128),
+            created_at: at(second),
+        }
+    }
+
+    #[test]
+    fn likes_of_a_tweet_collapse_into_one_group_per_tweet() {
+        use NotificationKind::*;
+        let groups = group_notifications(
+            vec![
+                stored(Like, 1, Some(10), 60),
+                stored(Retweet, 2, Some(10), 50),
+                stored(Like, 2, Some(10), 40),
+                stored(Like, 1, Some(10), 30),
+                stored(Like, 3, Some(11), 20),
+                stored(Like, 3, Some(10), 10),
+            ],
+            at(0),
+        );
+        let summary: Vec<(&str, Option<Uuid>, Vec<Uuid>)> = groups
+            .iter()
+            .map(|group| (group.kind.as_str(), group.tweet_id, group.actor_ids.clone()))
+            .collect();
+        let id = Uuid::from_u128;
+        assert_eq!(
+            summary,
+            [
+                ("like", Some(id(10)), vec![id(1), id(2), id(3)]),
+                ("retweet", Some(id(10)), vec![id(2)]),
+                ("like", Some(id(11)), vec![id(3)]),
+            ]
+        );
+        assert_eq!(groups[0].latest_at, at(60));
+    }
+
+    #[test]
+    fn ungrouped_kinds_stay_separate() {
+        use NotificationKind::*;
+        let groups = group_notifications(
+            vec![
+                stored(Follow, 1, None, 50),
+                stored(Follow, 2, None, 40),
+                stored(Mention, 1, Some(10), 30),
+                stored(Mention, 2, Some(10), 20),
+                stored(Reply, 3, Some(10), 10),
+            ],
+            at(0),
+        );
+        assert_eq!(groups.len(), 5);
+        assert!(groups.iter().all(|group| group.actor_ids.len() == 1));
+    }
+
+    #[test]
+    fn groups_are_read_once_all_their_members_are() {
+        use NotificationKind::*;
+        let groups = group_notifications(
+            vec![
+                stored(Like, 1, Some(10), 30),
+                stored(Like, 2, Some(10), 10),
+                stored(Like, 3, Some(11), 20),
+                stored(Mention, 1, Some(12), 5),
+            ],
+            at(20),
+        );
+        let read: Vec<bool> = groups.iter().map(|group| group.read).collect();
+        assert_eq!(read, [false, true, true]);
+    }
+
+    #[test]
+    fn descriptions_count_the_other_actors() {
+        use NotificationKind::*;
+        assert_eq!(describe(Like, "alice", 1), "alice liked your tweet");
+        assert_eq!(
+            describe(Retweet, "alice", 2),
+            "alice and 1 other retweeted your tweet"
+        );
+        assert_eq!(
+            describe(Like, "alice", 6),
+            "alice and 5 others liked your tweet"
+        );
+        assert_eq!(describe(Follow, "bob", 1), "bob followed you");
+        assert_eq!(describe(Reply, "bob", 1), "bob replied to your tweet");
+        assert_eq!(describe(PollClosed, "bob", 1), "Your poll has ended");
+    }
+}
@@ -0,0 +1,315 @@
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use std::sync::LazyLock;
+use utoipa::openapi::path::{ParameterBuilder, ParameterIn};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, ObjectBuilder, Ref, Required, ResponseBuilder, Type};
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use crate::error::ErrorBody;
+use crate::{handlers, health, logging, metrics};
+
+/// Where the document is served.
+const DOCUMENT_PATH: &str = "/api/openapi.json";
+
+/// The API as served by the routes in `main.rs`, scope by scope.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Twitter Clone API",
+        description = "A Twitter clone backed by ScyllaDB. Requests act as the user given in \
+            their `user_id` query parameter."
+    ),
+    paths(health::healthz, health::readyz, metrics::metrics),
+    nest(
+        (path = "/admin", api = AdminRoutes),
+        (path = "/api", api = ApiRoutes),
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&CommonParts)
+)]
+pub struct ApiDoc;
+
+#[derive(OpenApi)]
+#[openapi(paths(logging::get_log_levels, logging::set_log_levels))]
+struct AdminRoutes;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    openapi_json,
+    handlers::create_user,
+    handlers::create_tweet,
+    handlers::schedule_tweet,
+    handlers::get_scheduled_tweets,
+    handlers::cancel_scheduled_tweet,
+    handlers::create_draft,
+    handlers::get_drafts,
+    handlers::get_draft,
+    handlers::update_draft,
+    handlers::delete_draft,
+    handlers::publish_draft,
+    handlers::like_tweet,
+    handlers::retweet_tweet,
+    handlers::undo_retweet,
+    handlers::reply_to_tweet,
+    handlers::get_replies,
+    handlers::vote_in_poll,
+    handlers::bookmark_tweet,
+    handlers::remove_bookmark,
+    handlers::get_home_feed,
+    handlers::get_user_tweets,
+    handlers::delete_tweet,
+    handlers::search_tweets,
+    handlers::upload_media,
+    handlers::get_media,
+    handlers::get_media_variant,
+    handlers::update_profile,
+    handlers::pin_tweet,
+    handlers::unpin_tweet,
+    handlers::search_users,
+    handlers::get_hashtag_tweets,
+    handlers::get_trends,
+    handlers::get_my_mentions,
+    handlers::get_my_bookmarks,
+    handlers::get_notifications,
+    handlers::get_unread_notification_count,
+    handlers::mark_notifications_read,
+    handlers::stream_events,
+    handlers::follow_user,
+    handlers::unfollow_user,
+    handlers::get_dm_settings,
+    handlers::update_dm_settings,
+    handlers::create_conversation,
+    handlers::get_conversations,
+    handlers::send_message,
+    handlers::get_messages,
+    handlers::mark_conversation_read,
+    handlers::create_list,
+    handlers::get_my_lists,
+    handlers::get_list,
+    handlers::delete_list,
+    handlers::add_list_member,
+    handlers::remove_list_member,
+    handlers::get_list_members,
+    handlers::subscribe_list,
+    handlers::unsubscribe_list,
+    handlers::get_list_tweets,
+))]
+struct ApiRoutes;
+
+/// The form `POST /api/media` takes.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct MediaUpload {
+    /// A JPEG, PNG, GIF or WebP image, or an MP4 video.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+    alt_text: Option<String>,
+}
+
+/// What every operation shares: errors come as an `ErrorBody`, and any
+/// `POST` takes an `Idempotency-Key`. Also declares the admin token.
+struct CommonParts;
+
+impl Modify for CommonParts {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.info.license = None;
+        let error = ResponseBuilder::new()
+            .description("The request failed; `code` says why")
+            .content(
+                "application/json",
+                ContentBuilder::new()
+                    .schema(Some(Ref::from_schema_name("ErrorBody")))
+                    .build(),
+            )
+            .build();
+        let idempotency_key = ParameterBuilder::new()
+            .name("Idempotency-Key")
+            .parameter_in(ParameterIn::Header)
+            .required(Required::False)
+            .description(Some(
+                "Makes the request safe to retry: a retry with the same key gets the first \
+                 response back.",
+            ))
+            .schema(Some(
+                ObjectBuilder::new()
+                    .schema_type(Type::String)
+                    .min_length(Some(1))
+                    .max_length(Some(255)),
+            ))
+            .build();
+
+        for item in openapi.paths.paths.values_mut() {
+            if let Some(post) = &mut item.post {
+                post.parameters
+                    .get_or_insert_with(Vec::new)
+                    .push(idempotency_key.clone());
+            }
+            for operation in [
+                &mut item.get,
+                &mut item.put,
+                &mut item.post,
+                &mut item.delete,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                operation
+                    .responses
+                    .responses
+                    .entry("default".to_string())
+                    .or_insert_with(|| error.clone().into());
+            }
+        }
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
+static DOCUMENT: LazyLock<String> = LazyLock::new(|| {
+    ApiDoc::openapi()
+        .to_json()
+        .expect("OpenAPI document is serializable")
+});
+
+#[utoipa::path(
+    tag = "Docs",
+    summary = "Get this OpenAPI document",
+    responses((status = 200, description = "The OpenAPI 3.1 document", body = Object))
+)]
+#[get("/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(DOCUMENT.as_str())
+}
+
+/// Swagger UI for the document at `/api/docs/`, with its assets built in.
+pub fn swagger_ui() -> impl HttpServiceFactory {
+    (
+        web::redirect("/api/docs", "/api/docs/"),
+        SwaggerUi::new("/api/docs/{_:.*}").config(Config::new([DOCUMENT_PATH])),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use std::collections::BTreeSet;
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+
+    /// Answers requests no route matched, so that they can't be mistaken
+    /// for a handler's own 404.
+    const UNROUTED: StatusCode = StatusCode::IM_A_TEAPOT;
+
+    /// Every documented operation, as method, path and operation id (the
+    /// handler's name).
+    fn operations() -> Vec<(Method, String, String)> {
+        let mut operations = Vec::new();
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            for (method, operation) in [
+                (Method::GET, item.get),
+                (Method::PUT, item.put),
+                (Method::POST, item.post),
+                (Method::DELETE, item.delete),
+                (Method::PATCH, item.patch),
+            ] {
+                if let Some(operation) = operation {
+                    let operation_id = operation.operation_id.unwrap_or_default();
+                    operations.push((method, path.clone(), operation_id));
+                }
+            }
+        }
+        operations
+    }
+
+    /// The handlers `routes` in `main.rs` registers, by name. Services that
+    /// aren't handlers, like scopes and Swagger UI, are skipped.
+    fn registered_handlers() -> BTreeSet<String> {
+        let main = include_str!("main.rs");
+        let routes = &main[main
+            .find("fn routes(")
+            .expect("main.rs has no routes function")..];
+        routes
+            .split(".service(")
+            .skip(1)
+            .filter_map(|service| {
+                let (path, _) = service.split_once(')')?;
+                let is_handler = path
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+                is_handler.then(|| path.rsplit("::").next().unwrap_or(path).to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_registered_handler_is_documented() {
+        let registered = registered_handlers();
+        let documented: BTreeSet<String> = operations().into_iter().map(|(_, _, id)| id).collect();
+        let undocumented: Vec<_> = registered.difference(&documented).collect();
+        let unregistered: Vec<_> = documented.difference(&registered).collect();
+        assert!(
+            undocumented.is_empty() && unregistered.is_empty(),
+            "registered in main.rs but not in the OpenAPI document: {:?}; \
+             in the document but not registered: {:?}",
+            undocumented,
+            unregistered
+        );
+    }
+
+    #[actix_web::test]
+    async fn every_documented_operation_is_routed() {
+        let app = init_service(
+            App::new()
+                .configure(crate::routes)
+                .default_service(web::to(|| async { HttpResponse::new(UNROUTED) })),
+        )
+        .await;
+        for (method, path, operation_id) in operations() {
+            // Any value will do for a path parameter; handlers parse them
+            // themselves.
+            let uri = path
+                .split('/')
+                .map(|segment| match segment.starts_with('{') {
+                    true => "00000000-0000-0000-0000-000000000000",
+                    false => segment,
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let req = TestRequest::default()
+                .method(method.clone())
+                .uri(&uri)
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_ne!(
+                res.status(),
+                UNROUTED,
+                "{} {} ({}) is documented, but no route in main.rs serves it",
+                method,
+                path,
+                operation_id
+            );
+            // The path is served, but not with the documented method.
+            assert_ne!(
+                res.status(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{} {} ({}) is documented, but main.rs serves the path with another method",
+                method,
+                path,
+                operation_id
+            );
+        }
+    }
+}
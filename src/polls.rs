@@ -0,0 +1,280 @@
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::error;
+use scylla::{
+    frame::response::result::CqlValue, frame::value::CqlTimestamp, transport::errors::QueryError,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::db::{lwt_applied, DbPool, Session};
+use crate::error::ApiError;
+use crate::models::{CreatePollRequest, Poll, PollOption, Tweet};
+use crate::notifications::{NotificationEvent, NotificationSender};
+use crate::shutdown::Shutdown;
+
+const MIN_POLL_OPTIONS: usize = 2;
+const MAX_POLL_OPTIONS: usize = 4;
+const MAX_POLL_OPTION_CHARS: usize = 25;
+const MIN_POLL_MINUTES: u32 = 5;
+const MAX_POLL_MINUTES: u32 = 7 * 24 * 60;
+/// `open_polls` partitions are bucketed by day of closing, so closed polls'
+/// tombstones don't pile up in one partition.
+const POLL_BUCKET_MILLIS: i64 = 24 * 60 * 60 * 1000;
+/// Buckets the closer looks back through. Polls that ended while no server
+/// was running for longer than this still close, but their authors aren't
+/// notified.
+const CLOSE_LOOKBACK_BUCKETS: i32 = 2;
+const CLOSE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Checks a poll from a create tweet request and builds it, with no votes
+/// yet.
+pub fn new_poll(request: &CreatePollRequest, now: DateTime<Utc>) -> Result<Poll, ApiError> {
+    if request.options.len() < MIN_POLL_OPTIONS || request.options.len() > MAX_POLL_OPTIONS {
+        return Err(ApiError::Validation(format!(
+            "a poll has {} to {} options",
+            MIN_POLL_OPTIONS, MAX_POLL_OPTIONS
+        )));
+    }
+    if request.duration_minutes < MIN_POLL_MINUTES || request.duration_minutes > MAX_POLL_MINUTES {
+        return Err(ApiError::Validation(format!(
+            "a poll runs for {} to {} minutes",
+            MIN_POLL_MINUTES, MAX_POLL_MINUTES
+        )));
+    }
+    let mut options = Vec::with_capacity(request.options.len());
+    for label in &request.options {
+        let label = label.trim();
+        if label.is_empty() || label.chars().count() > MAX_POLL_OPTION_CHARS {
+            return Err(ApiError::Validation(format!(
+                "poll options are 1 to {} characters",
+                MAX_POLL_OPTION_CHARS
+            )));
+        }
+        options.push(PollOption {
+            label: label.to_string(),
+            votes: None,
+        });
+    }
+    Ok(Poll {
+        options,
+        ends_at: now + ChronoDuration::minutes(request.duration_minutes as i64),
+        closed: false,
+        hide_results: request.hide_results,
+        total_votes: None,
+        voted_option: None,
+    })
+}
+
+pub fn poll_bucket(ends_at: DateTime<Utc>) -> i32 {
+    ends_at.timestamp_millis().div_euclid(POLL_BUCKET_MILLIS) as i32
+}
+
+/// Records a new poll so that the closer finds it once it ends.
+pub async fn schedule_close(
+    session: &Session,
+    tweet_id: Uuid,
+    author_id: Uuid,
+    poll: &Poll,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "INSERT INTO twitter_clone.open_polls (bucket, ends_at, tweet_id, author_id) VALUES (?, ?, ?, ?)",
+            (
+                poll_bucket(poll.ends_at),
+                CqlTimestamp(poll.ends_at.timestamp_millis()),
+                tweet_id,
+                author_id,
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Deletes a poll's votes and tallies, for when its tweet is deleted.
+pub async fn delete_poll_rows(
+    session: &Session,
+    tweet_id: Uuid,
+    poll: &Poll,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "DELETE FROM twitter_clone.open_polls WHERE bucket = ? AND ends_at = ? AND tweet_id = ?",
+            (
+                poll_bucket(poll.ends_at),
+                CqlTimestamp(poll.ends_at.timestamp_millis()),
+                tweet_id,
+            ),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.poll_votes WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.poll_tallies WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Fills in whether each poll is closed and, where the viewer may see
+/// them, its current tallies and the viewer's vote.
+pub async fn hydrate_polls(
+    session: &Session,
+    tweets: &mut [Tweet],
+    viewer_id: Option<Uuid>,
+) -> Result<(), QueryError> {
+    let now = Utc::now();
+    for tweet in tweets {
+        if let Some(poll) = &mut tweet.poll {
+            hydrate_poll(session, tweet.tweet_id, tweet.user_id, poll, viewer_id, now).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn hydrate_poll(
+    session: &Session,
+    tweet_id: Uuid,
+    author_id: Uuid,
+    poll: &mut Poll,
+    viewer_id: Option<Uuid>,
+    now: DateTime<Utc>,
+) -> Result<(), QueryError> {
+    poll.closed = poll.ends_at <= now;
+    poll.voted_option = match viewer_id {
+        Some(viewer_id) => fetch_vote(session, tweet_id, viewer_id).await?,
+        None => None,
+    };
+
+    let visible = !poll.hide_results
+        || poll.closed
+        || viewer_id == Some(author_id)
+        || poll.voted_option.is_some();
+    if !visible {
+        poll.total_votes = None;
+        for option in &mut poll.options {
+            option.votes = None;
+        }
+        return Ok(());
+    }
+
+    let tallies = fetch_tallies(session, tweet_id).await?;
+    for (index, option) in poll.options.iter_mut().enumerate() {
+        option.votes = Some(tallies.get(&index).copied().unwrap_or(0));
+    }
+    poll.total_votes = Some(poll.options.iter().filter_map(|option| option.votes).sum());
+    Ok(())
+}
+
+async fn fetch_vote(
+    session: &Session,
+    tweet_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<usize>, QueryError> {
+    let result = session
+        .query(
+            "SELECT option_index FROM twitter_clone.poll_votes WHERE tweet_id = ? AND user_id = ?",
+            (tweet_id, user_id),
+        )
+        .await?;
+    Ok(result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        match row.columns.into_iter().next().flatten() {
+            Some(CqlValue::Int(index)) => usize::try_from(index).ok(),
+            _ => None,
+        }
+    }))
+}
+
+async fn fetch_tallies(
+    session: &Session,
+    tweet_id: Uuid,
+) -> Result<HashMap<usize, i64>, QueryError> {
+    let result = session
+        .query(
+            "SELECT option_index, votes FROM twitter_clone.poll_tallies WHERE tweet_id = ?",
+            (tweet_id,),
+        )
+        .await?;
+    Ok(result
+        .rows
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|row| {
+            let mut columns = row.columns.into_iter();
+            match (columns.next().flatten(), columns.next().flatten()) {
+                (Some(CqlValue::Int(index)), Some(CqlValue::Counter(votes))) => {
+                    Some((usize::try_from(index).ok()?, votes.0))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Starts the task that closes polls as they end and notifies their authors.
+pub fn spawn_closer(db_pool: DbPool, notifications: NotificationSender, shutdown: &Shutdown) {
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(CLOSE_INTERVAL);
+        while task_shutdown.tick(&mut interval).await {
+            if let Some(session) = db_pool.session().await {
+                if let Err(e) = close_ended_polls(&session, &notifications).await {
+                    error!("Failed to close polls: {:?}", e);
+                }
+            } else {
+                error!("No database session available for closing polls");
+            }
+        }
+    });
+}
+
+async fn close_ended_polls(
+    session: &Session,
+    notifications: &NotificationSender,
+) -> Result<(), QueryError> {
+    let now = Utc::now();
+    let current = poll_bucket(now);
+    for bucket in (current - CLOSE_LOOKBACK_BUCKETS)..=current {
+        let result = session
+            .query(
+                "SELECT ends_at, tweet_id, author_id FROM twitter_clone.open_polls WHERE bucket = ? AND ends_at <= ?",
+                (bucket, CqlTimestamp(now.timestamp_millis())),
+            )
+            .await?;
+        for row in result.rows.unwrap_or_default() {
+            let mut columns = row.columns.into_iter();
+            if let (
+                Some(CqlValue::Timestamp(ends_at)),
+                Some(CqlValue::Uuid(tweet_id)),
+                Some(CqlValue::Uuid(author_id)),
+            ) = (
+                columns.next().flatten(),
+                columns.next().flatten(),
+                columns.next().flatten(),
+            ) {
+                // Conditional, so that with several servers running only
+                // the one that removes the row notifies the author.
+                let deleted = session
+                    .query(
+                        "DELETE FROM twitter_clone.open_polls WHERE bucket = ? AND ends_at = ? AND tweet_id = ? IF EXISTS",
+                        (bucket, ends_at, tweet_id),
+                    )
+                    .await?;
+                if lwt_applied(&deleted) {
+                    notifications.send(NotificationEvent::PollClosed {
+                        author_id,
+                        tweet_id,
+                        closed_at: DateTime::<Utc>::from_timestamp_millis(ends_at.0).unwrap_or(now),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,765 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{web, ResponseError};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use log::{error, info};
+use scylla::{
+    frame::response::result::CqlValue, frame::value::Counter, transport::errors::QueryError,
+};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::error::ApiError;
+use crate::handlers::UserIdQuery;
+use crate::shutdown::Shutdown;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Partitions per budget and window in `rate_limit_counters`, so one busy
+/// window doesn't land on a single replica set.
+const COUNTER_SHARDS: i32 = 16;
+
+const LIMIT_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const REMAINING_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RESET_HEADER: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// A token bucket: `burst` requests at once, then one more every
+/// `refill_every`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub refill_every: Duration,
+}
+
+impl Limit {
+    const fn new(burst: u32, refill_every: Duration) -> Self {
+        Limit {
+            burst,
+            refill_every,
+        }
+    }
+
+    /// How long an empty bucket takes to fill up again.
+    fn period(self) -> Duration {
+        self.refill_every * self.burst
+    }
+}
+
+/// Endpoints that share a budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointClass {
+    Signup,
+    Tweet,
+    Like,
+    Read,
+    /// Every other change.
+    Write,
+}
+
+impl EndpointClass {
+    const ALL: [EndpointClass; 5] = [
+        EndpointClass::Signup,
+        EndpointClass::Tweet,
+        EndpointClass::Like,
+        EndpointClass::Read,
+        EndpointClass::Write,
+    ];
+
+    fn of(req: &ServiceRequest) -> Self {
+        if matches!(*req.method(), Method::GET | Method::HEAD) {
+            return EndpointClass::Read;
+        }
+        match req.match_pattern().as_deref() {
+            Some("/api/users") => EndpointClass::Signup,
+            Some(
+                "/api/tweets"
+                | "/api/tweets/scheduled"
+                | "/api/drafts/{draft_id}/publish"
+                | "/api/tweets/{tweet_id}/replies",
+            ) => EndpointClass::Tweet,
+            Some("/api/tweets/{tweet_id}/like" | "/api/tweets/{tweet_id}/retweet") => {
+                EndpointClass::Like
+            }
+            _ => EndpointClass::Write,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EndpointClass::Signup => "signup",
+            EndpointClass::Tweet => "tweet",
+            EndpointClass::Like => "like",
+            EndpointClass::Read => "read",
+            EndpointClass::Write => "write",
+        }
+    }
+
+    /// Budget of each user. Signups have none, as there is no user yet.
+    fn user_limit(self) -> Option<Limit> {
+        match self {
+            EndpointClass::Signup => None,
+            EndpointClass::Tweet => Some(Limit::new(20, Duration::from_secs(30))),
+            EndpointClass::Like => Some(Limit::new(50, Duration::from_secs(5))),
+            EndpointClass::Read => Some(Limit::new(300, Duration::from_millis(100))),
+            EndpointClass::Write => Some(Limit::new(60, Duration::from_secs(1))),
+        }
+    }
+
+    /// Budget of each client address, shared by everyone behind it.
+    fn ip_limit(self) -> Limit {
+        match self {
+            EndpointClass::Signup => Limit::new(5, Duration::from_secs(12 * 60)),
+            EndpointClass::Tweet => Limit::new(60, Duration::from_secs(10)),
+            EndpointClass::Like => Limit::new(150, Duration::from_secs(1)),
+            EndpointClass::Read => Limit::new(600, Duration::from_millis(50)),
+            EndpointClass::Write => Limit::new(200, Duration::from_millis(300)),
+        }
+    }
+
+    /// Every budget name with its limit.
+    fn budgets() -> Vec<(String, Limit)> {
+        let mut budgets = Vec::new();
+        for class in EndpointClass::ALL {
+            if let Some(limit) = class.user_limit() {
+                budgets.push((format!("{}:user", class.name()), limit));
+            }
+            budgets.push((format!("{}:ip", class.name()), class.ip_limit()));
+        }
+        budgets
+    }
+}
+
+/// The state of a budget after a request was counted against it.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the budget is full again.
+    pub reset_after: Duration,
+    /// Set if the request was refused, to when it would be let through.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    fn allowed(limit: Limit, remaining: f64) -> Self {
+        Decision {
+            limit: limit.burst,
+            remaining: remaining.max(0.0) as u32,
+            reset_after: limit
+                .refill_every
+                .mul_f64((limit.burst as f64 - remaining).max(0.0)),
+            retry_after: None,
+        }
+    }
+
+    fn refused(limit: Limit, remaining: f64, retry_after: Duration) -> Self {
+        Decision {
+            retry_after: Some(retry_after),
+            ..Decision::allowed(limit, remaining)
+        }
+    }
+
+    /// The one closer to refusing requests.
+    fn tighter(self, other: Decision) -> Decision {
+        match (self.retry_after, other.retry_after) {
+            (Some(_), None) => self,
+            (None, Some(_)) => other,
+            _ if other.remaining < self.remaining => other,
+            _ => self,
+        }
+    }
+
+    fn set_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(LIMIT_HEADER, HeaderValue::from(self.limit));
+        headers.insert(REMAINING_HEADER, HeaderValue::from(self.remaining));
+        headers.insert(RESET_HEADER, HeaderValue::from(seconds(self.reset_after)));
+    }
+}
+
+/// Whole seconds, rounded up.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Where budgets are kept.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts one request against `key`'s bucket in `budget`, unless the
+    /// bucket is empty.
+    async fn take(&self, budget: &str, key: &str, limit: Limit) -> Result<Decision, QueryError>;
+
+    /// Forgets buckets that have filled up again. Called every minute.
+    async fn sweep(&self) -> Result<(), QueryError>;
+}
+
+/// Picks the store from `RATE_LIMIT_STORE`: `memory`, the default, keeps
+/// budgets per server, `scylla` shares them across a cluster and `off`
+/// turns rate limiting off.
+pub fn from_env(session: Arc<Session>) -> Result<Option<Arc<dyn RateLimitStore>>, String> {
+    match std::env::var("RATE_LIMIT_STORE").as_deref() {
+        Err(_) | Ok("memory") => Ok(Some(Arc::new(MemoryStore::default()))),
+        Ok("scylla") => Ok(Some(Arc::new(ScyllaStore::new(session)))),
+        Ok("off") => Ok(None),
+        Ok(other) => Err(format!(
+            "unknown RATE_LIMIT_STORE {:?}, expected \"memory\", \"scylla\" or \"off\"",
+            other
+        )),
+    }
+}
+
+pub fn spawn_sweeper(store: Arc<dyn RateLimitStore>, shutdown: &Shutdown) {
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        while task_shutdown.tick(&mut interval).await {
+            if let Err(e) = store.sweep().await {
+                error!("Failed to sweep rate limit buckets: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Exact token buckets, kept in this process.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    limit: Limit,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() / self.limit.refill_every.as_secs_f64())
+            .min(self.limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, budget: &str, key: &str, limit: Limit) -> Result<Decision, QueryError> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry((budget.to_string(), key.to_string()))
+            .or_insert(Bucket {
+                tokens: limit.burst as f64,
+                limit,
+                updated_at: now,
+            });
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(Decision::allowed(limit, bucket.tokens))
+        } else {
+            let retry_after = limit.refill_every.mul_f64(1.0 - bucket.tokens);
+            Ok(Decision::refused(limit, bucket.tokens, retry_after))
+        }
+    }
+
+    async fn sweep(&self) -> Result<(), QueryError> {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limit.burst as f64
+        });
+        Ok(())
+    }
+}
+
+/// Buckets shared by every server, approximated by sliding windows over
+/// the `rate_limit_counters` counters. Each window is as long as the
+/// bucket's `period`, and a request is let through if the hits of the
+/// current window plus the overlapping share of the previous one leave
+/// room. Servers racing for the last token may all get it.
+pub struct ScyllaStore {
+    session: Arc<Session>,
+    /// The newest window start each budget was swept up to.
+    swept: Mutex<HashMap<String, i64>>,
+}
+
+impl ScyllaStore {
+    pub fn new(session: Arc<Session>) -> Self {
+        ScyllaStore {
+            session,
+            swept: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for ScyllaStore {
+    async fn take(&self, budget: &str, key: &str, limit: Limit) -> Result<Decision, QueryError> {
+        let window = limit.period().as_millis() as i64;
+        let now = Utc::now().timestamp_millis();
+        let start = now - now.rem_euclid(window);
+        let previous = start - window;
+        let shard = counter_shard(key);
+
+        let result = self
+            .session
+            .query(
+                "SELECT window_start, hits FROM twitter_clone.rate_limit_counters WHERE budget = ? AND window_start IN (?, ?) AND shard = ? AND key = ?",
+                (budget, previous, start, shard, key),
+            )
+            .await?;
+        let (mut previous_hits, mut hits) = (0.0, 0.0);
+        for row in result.rows.unwrap_or_default() {
+            let mut columns = row.columns.into_iter();
+            if let (
+                Some(Some(CqlValue::BigInt(window_start))),
+                Some(Some(CqlValue::Counter(count))),
+            ) = (columns.next(), columns.next())
+            {
+                if window_start == start {
+                    hits = count.0 as f64;
+                } else {
+                    previous_hits = count.0 as f64;
+                }
+            }
+        }
+
+        let burst = limit.burst as f64;
+        let elapsed = (now - start) as f64 / window as f64;
+        let used = previous_hits * (1.0 - elapsed) + hits;
+        if used + 1.0 > burst {
+            let wait = window_wait(burst, previous_hits, hits, elapsed);
+            let retry_after = Duration::from_millis((wait * window as f64) as u64);
+            return Ok(Decision::refused(limit, burst - used, retry_after));
+        }
+
+        self.session
+            .query(
+                "UPDATE twitter_clone.rate_limit_counters SET hits = hits + 1 WHERE budget = ? AND window_start = ? AND shard = ? AND key = ?",
+                (Counter(1), budget, start, shard, key),
+            )
+            .await?;
+        Ok(Decision::allowed(limit, burst - used - 1.0))
+    }
+
+    /// Counter tables can't expire rows, so windows are deleted once they
+    /// can no longer be read: two windows after they began. Every window
+    /// since the last sweep goes, however many have passed; the first sweep
+    /// scans for windows left behind before this server started.
+    async fn sweep(&self) -> Result<(), QueryError> {
+        let now = Utc::now().timestamp_millis();
+        let budgets = EndpointClass::budgets();
+        if self.swept.lock().unwrap().is_empty() {
+            self.sweep_leftovers(&budgets, now).await?;
+        }
+        for (budget, limit) in budgets {
+            let window = limit.period().as_millis() as i64;
+            let swept = self.swept.lock().unwrap().get(&budget).copied();
+            for window_start in windows_to_sweep(swept, stale_window(now, window), window) {
+                for shard in 0..COUNTER_SHARDS {
+                    self.delete_window(&budget, window_start, shard).await?;
+                }
+                self.swept
+                    .lock()
+                    .unwrap()
+                    .insert(budget.clone(), window_start);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScyllaStore {
+    /// Deletes every stale window in the table, then marks each budget as
+    /// swept up to its newest stale window.
+    async fn sweep_leftovers(
+        &self,
+        budgets: &[(String, Limit)],
+        now: i64,
+    ) -> Result<(), QueryError> {
+        let stale: HashMap<&str, i64> = budgets
+            .iter()
+            .map(|(budget, limit)| {
+                let window = limit.period().as_millis() as i64;
+                (budget.as_str(), stale_window(now, window))
+            })
+            .collect();
+
+        let mut rows = self
+            .session
+            .query_iter(
+                "SELECT DISTINCT budget, window_start, shard FROM twitter_clone.rate_limit_counters",
+                (),
+            )
+            .await?;
+        let mut leftovers = Vec::new();
+        while let Some(row) = rows.next().await {
+            let mut columns = row?.columns.into_iter();
+            if let (
+                Some(CqlValue::Text(budget)),
+                Some(CqlValue::BigInt(window_start)),
+                Some(CqlValue::Int(shard)),
+            ) = (
+                columns.next().flatten(),
+                columns.next().flatten(),
+                columns.next().flatten(),
+            ) {
+                // Budgets that no longer exist are stale whatever their window.
+                if stale
+                    .get(budget.as_str())
+                    .is_none_or(|&stale| window_start <= stale)
+                {
+                    leftovers.push((budget, window_start, shard));
+                }
+            }
+        }
+        for (budget, window_start, shard) in leftovers {
+            self.delete_window(&budget, window_start, shard).await?;
+        }
+
+        let mut swept = self.swept.lock().unwrap();
+        for (budget, stale) in stale {
+            swept.insert(budget.to_string(), stale);
+        }
+        Ok(())
+    }
+
+    async fn delete_window(
+        &self,
+        budget: &str,
+        window_start: i64,
+        shard: i32,
+    ) -> Result<(), QueryError> {
+        self.session
+            .query(
+                "DELETE FROM twitter_clone.rate_limit_counters WHERE budget = ? AND window_start = ? AND shard = ?",
+                (budget, window_start, shard),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// The start of the newest window that can no longer be read as of `now`:
+/// the one before the previous window.
+fn stale_window(now: i64, window: i64) -> i64 {
+    now - now.rem_euclid(window) - 2 * window
+}
+
+/// Starts of the windows to delete, oldest first: every one after `swept`
+/// up to `stale`, or just `stale` if nothing was swept yet.
+fn windows_to_sweep(swept: Option<i64>, stale: i64, window: i64) -> impl Iterator<Item = i64> {
+    let first = swept.map_or(stale, |swept| swept + window);
+    (first..=stale).step_by(window as usize)
+}
+
+/// The share of a window that has to pass before one more hit fits in a
+/// sliding window of `burst`, `elapsed` (a share) into the current window.
+fn window_wait(burst: f64, previous_hits: f64, hits: f64, elapsed: f64) -> f64 {
+    let wait = if hits + 1.0 > burst {
+        // The previous window's share runs out, then some of this one's.
+        (1.0 - elapsed) + (1.0 - (burst - 1.0) / hits)
+    } else {
+        (1.0 - (burst - 1.0 - hits) / previous_hits) - elapsed
+    };
+    wait.max(0.0)
+}
+
+/// A stable hash of `key` into `0..COUNTER_SHARDS`, the same on every
+/// server (FNV-1a).
+fn counter_shard(key: &str) -> i32 {
+    let hash = key.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    (hash % COUNTER_SHARDS as u32) as i32
+}
+
+/// Holds each request to its endpoint class's budgets for the calling user
+/// (the `user_id` query parameter) and for the client address, refusing it
+/// with `429` if either is spent. Every response carries the
+/// `X-RateLimit-*` headers of the tighter budget. If the store fails, the
+/// request is let through.
+#[derive(Clone)]
+pub struct RateLimit {
+    store: Option<Arc<dyn RateLimitStore>>,
+    /// Take the client address from `Forwarded` or `X-Forwarded-For`, for
+    /// servers behind a proxy.
+    trust_proxy: bool,
+}
+
+impl RateLimit {
+    pub fn new(store: Option<Arc<dyn RateLimitStore>>, trust_proxy: bool) -> Self {
+        if store.is_none() {
+            info!("Rate limiting is off");
+        }
+        RateLimit { store, trust_proxy }
+    }
+
+    async fn check(&self, req: &ServiceRequest) -> Option<Decision> {
+        let store = self.store.as_ref()?;
+        let class = EndpointClass::of(req);
+        let mut budgets = Vec::with_capacity(2);
+        if let (Some(limit), Some(user_id)) = (class.user_limit(), user_id(req)) {
+            budgets.push(("user", user_id.to_string(), limit));
+        }
+        if let Some(ip) = client_ip(req, self.trust_proxy) {
+            budgets.push(("ip", ip, class.ip_limit()));
+        }
+
+        let mut tightest: Option<Decision> = None;
+        for (scope, key, limit) in budgets {
+            let budget = format!("{}:{}", class.name(), scope);
+            let decision = match store.take(&budget, &key, limit).await {
+                Ok(decision) => decision,
+                Err(e) => {
+                    error!("Failed to check rate limit {}: {:?}", budget, e);
+                    continue;
+                }
+            };
+            let refused = decision.retry_after.is_some();
+            tightest = Some(tightest.map_or(decision, |tightest| tightest.tighter(decision)));
+            if refused {
+                break;
+            }
+        }
+        tightest
+    }
+}
+
+/// The client's address, taken from `Forwarded` or `X-Forwarded-For` when
+/// `trust_proxy` is set. IPv6 clients are given by their /64, which is
+/// usually all one host or home.
+pub fn client_ip(req: &ServiceRequest, trust_proxy: bool) -> Option<String> {
+    let ip = if trust_proxy {
+        req.connection_info().realip_remote_addr()?.parse().ok()?
+    } else {
+        req.peer_addr()?.ip()
+    };
+    Some(match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    })
+}
+
+/// The calling user, from the `user_id` query parameter.
+pub fn user_id(req: &ServiceRequest) -> Option<Uuid> {
+    let query = web::Query::<UserIdQuery>::from_query(req.query_string()).ok()?;
+    Uuid::parse_str(&query.user_id).ok()
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            rate_limit: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    rate_limit: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let rate_limit = self.rate_limit.clone();
+        Box::pin(async move {
+            let Some(decision) = rate_limit.check(&req).await else {
+                return Ok(service.call(req).await?.map_into_left_body());
+            };
+
+            let mut response = match decision.retry_after {
+                Some(retry_after) => {
+                    let error = ApiError::RateLimited {
+                        retry_after: Some(retry_after),
+                    };
+                    req.into_response(error.error_response())
+                        .map_into_right_body()
+                }
+                None => service.call(req).await?.map_into_left_body(),
+            };
+            decision.set_headers(response.headers_mut());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::RETRY_AFTER;
+
+    const LIMIT: Limit = Limit::new(3, Duration::from_secs(10));
+
+    fn bucket(tokens: f64, updated_at: Instant) -> Bucket {
+        Bucket {
+            tokens,
+            limit: LIMIT,
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_one_token_per_interval_up_to_the_burst() {
+        let start = Instant::now();
+        let mut empty = bucket(0.0, start);
+        empty.refill(start + Duration::from_secs(5));
+        assert_eq!(empty.tokens, 0.5);
+        empty.refill(start + Duration::from_secs(25));
+        assert_eq!(empty.tokens, 2.5);
+        empty.refill(start + Duration::from_secs(3600));
+        assert_eq!(empty.tokens, 3.0);
+
+        // A clock reading from before the last update adds nothing.
+        let mut full = bucket(1.0, start + Duration::from_secs(10));
+        full.refill(start);
+        assert_eq!(full.tokens, 1.0);
+    }
+
+    #[actix_web::test]
+    async fn memory_store_refuses_once_the_burst_is_spent() {
+        let store = MemoryStore::default();
+        for remaining in [2, 1, 0] {
+            let decision = store.take("tweet:user", "alice", LIMIT).await.unwrap();
+            assert!(decision.retry_after.is_none());
+            assert_eq!(decision.limit, 3);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let refused = store.take("tweet:user", "alice", LIMIT).await.unwrap();
+        assert_eq!(refused.remaining, 0);
+        let retry_after = refused.retry_after.unwrap();
+        assert!(retry_after > Duration::from_millis(9900) && retry_after <= LIMIT.refill_every);
+        assert!(refused.reset_after > Duration::from_millis(29900));
+
+        // Other keys and budgets have their own buckets.
+        let other = store.take("tweet:user", "bob", LIMIT).await.unwrap();
+        assert_eq!(other.remaining, 2);
+        let other = store.take("like:user", "alice", LIMIT).await.unwrap();
+        assert_eq!(other.remaining, 2);
+    }
+
+    #[test]
+    fn decisions_report_when_the_bucket_is_full_again() {
+        let decision = Decision::allowed(LIMIT, 0.5);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset_after, Duration::from_secs(25));
+        assert_eq!(seconds(decision.reset_after), 25);
+        assert_eq!(seconds(Duration::from_millis(25_001)), 26);
+        assert_eq!(seconds(Duration::ZERO), 0);
+
+        let refused = Decision::refused(LIMIT, 0.5, Duration::from_secs(5));
+        assert!(Decision::allowed(LIMIT, 2.0)
+            .tighter(refused)
+            .retry_after
+            .is_some());
+        assert_eq!(
+            Decision::allowed(LIMIT, 2.0)
+                .tighter(Decision::allowed(LIMIT, 1.0))
+                .remaining,
+            1
+        );
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let retry_after = |duration| {
+            let error = ApiError::RateLimited {
+                retry_after: Some(duration),
+            };
+            let response = error.error_response();
+            response.headers().get(RETRY_AFTER).cloned()
+        };
+        assert_eq!(retry_after(Duration::from_millis(1500)).unwrap(), "2");
+        assert_eq!(retry_after(Duration::from_secs(3)).unwrap(), "3");
+    }
+
+    #[test]
+    fn sweeps_delete_every_window_since_the_last_one() {
+        // Read windows are 30 s long, shorter than the sweep interval.
+        let window = EndpointClass::Read
+            .user_limit()
+            .unwrap()
+            .period()
+            .as_millis() as i64;
+        assert_eq!(window, 30_000);
+        assert_eq!(stale_window(95_000, window), 30_000);
+        assert_eq!(stale_window(90_000, window), 30_000);
+        assert_eq!(stale_window(-1, window), -90_000);
+
+        let interval = SWEEP_INTERVAL.as_millis() as i64;
+        let mut swept = None;
+        let mut deleted = Vec::new();
+        for sweep in 0..5 {
+            let now = 1_000_000 + sweep * interval;
+            for window_start in windows_to_sweep(swept, stale_window(now, window), window) {
+                deleted.push(window_start);
+                swept = Some(window_start);
+            }
+        }
+        let expected: Vec<i64> = (0..9).map(|n| 930_000 + n * window).collect();
+        assert_eq!(deleted, expected);
+
+        // After downtime, everything in between goes at once.
+        assert_eq!(
+            windows_to_sweep(Some(0), 120_000, window).collect::<Vec<_>>(),
+            [30_000, 60_000, 90_000, 120_000]
+        );
+        assert_eq!(windows_to_sweep(Some(120_000), 120_000, window).count(), 0);
+        assert_eq!(
+            windows_to_sweep(None, 120_000, window).collect::<Vec<_>>(),
+            [120_000]
+        );
+    }
+
+    #[test]
+    fn sliding_windows_wait_for_enough_hits_to_age_out() {
+        // 10 hits last window, a quarter into this one: 7.5 still count, so
+        // with 2 more here a third hit fits once 7 are left, at 30%.
+        let wait = window_wait(10.0, 10.0, 2.0, 0.25);
+        assert!((wait - 0.05).abs() < 1e-9);
+        // A full current window waits for the next one, and then for enough
+        // of its own hits to age out.
+        let wait = window_wait(10.0, 0.0, 10.0, 0.5);
+        assert!((wait - 0.6).abs() < 1e-9);
+        assert_eq!(window_wait(10.0, 10.0, 0.0, 0.99), 0.0);
+    }
+}
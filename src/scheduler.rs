@@ -0,0 +1,265 @@
+use actix_web::ResponseError;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use scylla::{
+    frame::response::result::CqlValue, frame::value::CqlTimestamp, transport::errors::QueryError,
+};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::db::{lwt_applied, uuid_list, DbPool, Session};
+use crate::handlers::{publish_tweet, TweetSinks};
+use crate::models::{CreateTweetRequest, ScheduleStatus, ScheduledTweet};
+use crate::shutdown::Shutdown;
+
+/// `due_tweets` partitions are bucketed by day of publishing, so published
+/// tweets' tombstones don't pile up in one partition.
+const SCHEDULE_BUCKET_MILLIS: i64 = 24 * 60 * 60 * 1000;
+/// Buckets the scheduler looks back through. Tweets that fell due while no
+/// server was running for longer than this stay scheduled until cancelled.
+const LOOKBACK_BUCKETS: i32 = 7;
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a server has to publish a tweet it claimed before another
+/// server may take it over.
+const LEASE_MILLIS: i64 = 60 * 1000;
+
+pub fn schedule_bucket(publish_at: DateTime<Utc>) -> i32 {
+    publish_at
+        .timestamp_millis()
+        .div_euclid(SCHEDULE_BUCKET_MILLIS) as i32
+}
+
+pub async fn fetch_scheduled(
+    session: &Session,
+    scheduled_id: Uuid,
+) -> Result<Option<ScheduledTweet>, QueryError> {
+    let result = session
+        .query(
+            "SELECT user_id, content, media_ids, poll, publish_at, status, created_at FROM twitter_clone.scheduled_tweets WHERE scheduled_id = ?",
+            (scheduled_id,),
+        )
+        .await?;
+    Ok(result.rows.unwrap_or_default().into_iter().find_map(|row| {
+        let mut columns = row.columns.into_iter();
+        match (
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+        ) {
+            (
+                Some(CqlValue::Uuid(user_id)),
+                Some(CqlValue::Text(content)),
+                media_ids,
+                poll,
+                Some(CqlValue::Timestamp(publish_at)),
+                Some(CqlValue::Text(status)),
+                Some(CqlValue::Timestamp(created_at)),
+            ) => Some(ScheduledTweet {
+                scheduled_id,
+                user_id,
+                content,
+                media_ids: uuid_list(media_ids),
+                poll: match poll {
+                    Some(CqlValue::Text(poll)) => serde_json::from_str(&poll).ok(),
+                    _ => None,
+                },
+                publish_at: DateTime::<Utc>::from_timestamp_millis(publish_at.0)?,
+                status: ScheduleStatus::parse(&status)?,
+                created_at: DateTime::<Utc>::from_timestamp_millis(created_at.0)?,
+            }),
+            _ => None,
+        }
+    }))
+}
+
+/// Removes a scheduled tweet from the scheduler's queue and its author's
+/// list, leaving the `scheduled_tweets` row itself to the caller.
+pub async fn delete_schedule_rows(
+    session: &Session,
+    scheduled: &ScheduledTweet,
+) -> Result<(), QueryError> {
+    session
+        .query(
+            "DELETE FROM twitter_clone.due_tweets WHERE bucket = ? AND publish_at = ? AND scheduled_id = ?",
+            (
+                schedule_bucket(scheduled.publish_at),
+                CqlTimestamp(scheduled.publish_at.timestamp_millis()),
+                scheduled.scheduled_id,
+            ),
+        )
+        .await?;
+    session
+        .query(
+            "DELETE FROM twitter_clone.scheduled_by_user WHERE user_id = ? AND scheduled_id = ?",
+            (scheduled.user_id, scheduled.scheduled_id),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Starts the loop that publishes scheduled tweets once they are due.
+/// Every server runs one; leases taken with lightweight transactions make
+/// sure each tweet is published by only one of them.
+pub fn spawn_scheduler(db_pool: DbPool, sinks: TweetSinks, shutdown: &Shutdown) {
+    let instance_id = Uuid::new_v4();
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(SCAN_INTERVAL);
+        while task_shutdown.tick(&mut interval).await {
+            if let Some(session) = db_pool.session().await {
+                if let Err(e) = publish_due_tweets(&session, &sinks, instance_id).await {
+                    error!("Failed to publish scheduled tweets: {:?}", e);
+                }
+            } else {
+                error!("No database session available for scheduled tweets");
+            }
+        }
+    });
+}
+
+async fn publish_due_tweets(
+    session: &Session,
+    sinks: &TweetSinks,
+    instance_id: Uuid,
+) -> Result<(), QueryError> {
+    let now = Utc::now();
+    let current = schedule_bucket(now);
+    for bucket in (current - LOOKBACK_BUCKETS)..=current {
+        let result = session
+            .query(
+                "SELECT scheduled_id FROM twitter_clone.due_tweets WHERE bucket = ? AND publish_at <= ?",
+                (bucket, CqlTimestamp(now.timestamp_millis())),
+            )
+            .await?;
+        for row in result.rows.unwrap_or_default() {
+            if let Some(CqlValue::Uuid(scheduled_id)) = row.columns.into_iter().next().flatten() {
+                if let Err(e) =
+                    publish_scheduled(session, sinks, instance_id, scheduled_id, now).await
+                {
+                    error!(
+                        "Failed to publish scheduled tweet {}: {:?}",
+                        scheduled_id, e
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn publish_scheduled(
+    session: &Session,
+    sinks: &TweetSinks,
+    instance_id: Uuid,
+    scheduled_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<(), QueryError> {
+    if !claim(session, scheduled_id, instance_id, now).await? {
+        return Ok(());
+    }
+    let scheduled = match fetch_scheduled(session, scheduled_id).await? {
+        Some(scheduled) => scheduled,
+        None => return Ok(()),
+    };
+
+    let request = CreateTweetRequest {
+        content: scheduled.content.clone(),
+        media_ids: scheduled.media_ids.clone(),
+        poll: scheduled.poll.clone(),
+    };
+    // The scheduled id and time become the tweet's, so that publishing again
+    // after a server died part-way overwrites the same rows.
+    match publish_tweet(
+        session,
+        sinks,
+        scheduled.user_id,
+        scheduled_id,
+        None,
+        &request,
+        scheduled.publish_at,
+    )
+    .await
+    {
+        Ok(_) => {
+            delete_schedule_rows(session, &scheduled).await?;
+            session
+                .query(
+                    "DELETE FROM twitter_clone.scheduled_tweets WHERE scheduled_id = ? IF lease_owner = ?",
+                    (scheduled_id, instance_id),
+                )
+                .await?;
+            info!("Published scheduled tweet {}", scheduled_id);
+        }
+        Err(e) if e.status_code().is_client_error() => {
+            warn!("Scheduled tweet {} can't be published: {}", scheduled_id, e);
+            session
+                .query(
+                    "UPDATE twitter_clone.scheduled_tweets SET status = ? WHERE scheduled_id = ? IF lease_owner = ?",
+                    (ScheduleStatus::Failed.as_str(), scheduled_id, instance_id),
+                )
+                .await?;
+            session
+                .query(
+                    "DELETE FROM twitter_clone.due_tweets WHERE bucket = ? AND publish_at = ? AND scheduled_id = ?",
+                    (
+                        schedule_bucket(scheduled.publish_at),
+                        CqlTimestamp(scheduled.publish_at.timestamp_millis()),
+                        scheduled_id,
+                    ),
+                )
+                .await?;
+        }
+        // Still claimed by this server, so it's retried once the lease
+        // runs out.
+        Err(e) => error!(
+            "Failed to publish scheduled tweet {}: {:?}",
+            scheduled_id, e
+        ),
+    }
+    Ok(())
+}
+
+/// Takes the lease on a due tweet. `false` if it was cancelled, or another
+/// server holds a lease that hasn't run out.
+async fn claim(
+    session: &Session,
+    scheduled_id: Uuid,
+    instance_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<bool, QueryError> {
+    let lease_expires = CqlTimestamp(now.timestamp_millis() + LEASE_MILLIS);
+    let claimed = session
+        .query(
+            "UPDATE twitter_clone.scheduled_tweets SET status = ?, lease_owner = ?, lease_expires = ? WHERE scheduled_id = ? IF status = ?",
+            (
+                ScheduleStatus::Publishing.as_str(),
+                instance_id,
+                lease_expires,
+                scheduled_id,
+                ScheduleStatus::Scheduled.as_str(),
+            ),
+        )
+        .await?;
+    if lwt_applied(&claimed) {
+        return Ok(true);
+    }
+
+    // Take over from a server that claimed it and then failed or died.
+    let claimed = session
+        .query(
+            "UPDATE twitter_clone.scheduled_tweets SET lease_owner = ?, lease_expires = ? WHERE scheduled_id = ? IF status = ? AND lease_expires < ?",
+            (
+                instance_id,
+                lease_expires,
+                scheduled_id,
+                ScheduleStatus::Publishing.as_str(),
+                CqlTimestamp(now.timestamp_millis()),
+            ),
+        )
+        .await?;
+    Ok(lwt_applied(&claimed))
+}
@@ -0,0 +1,520 @@
+use chrono::{NaiveDate, Utc};
+use futures::StreamExt;
+use log::{error, info};
+use scylla::frame::response::result::CqlValue;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, PhraseQuery, Query, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT,
+};
+use tantivy::{
+    doc, DocAddress, DocId, Index, IndexReader, IndexWriter, Order, ReloadPolicy, Score,
+    SegmentReader, TantivyDocument, Term,
+};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::entities::normalize_hashtag;
+use crate::models::TweetEntities;
+use crate::shutdown::Shutdown;
+
+const WRITER_MEMORY_BYTES: usize = 50_000_000;
+/// New tweets become searchable within this delay.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+/// Relevance ranking halves a tweet's score for every week of age.
+const RECENCY_HALF_LIFE_MILLIS: f32 = 7.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchOrder {
+    /// Text relevance, decayed by age.
+    Relevance,
+    /// Newest first.
+    Recent,
+}
+
+/// A parsed search string. Bare words must all match, `"quoted text"` must
+/// match as a phrase, and `from:username`, `#tag`, `since:YYYY-MM-DD` and
+/// `until:YYYY-MM-DD` (exclusive) narrow the results. A date that can't be
+/// read is an error rather than being ignored.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub words: Vec<String>,
+    pub phrases: Vec<String>,
+    pub from: Option<String>,
+    pub hashtags: Vec<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl SearchQuery {
+    pub fn parse(q: &str) -> Result<Self, String> {
+        let mut query = SearchQuery::default();
+        let mut rest = q.trim();
+
+        while !rest.is_empty() {
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let phrase = quoted[..end].trim();
+                if !phrase.is_empty() {
+                    query.phrases.push(phrase.to_string());
+                }
+                rest = quoted.get(end + 1..).unwrap_or("").trim_start();
+                continue;
+            }
+
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let token = &rest[..end];
+            rest = rest[end..].trim_start();
+
+            if let Some(username) = token.strip_prefix("from:") {
+                query.from = Some(username.trim_start_matches('@').to_string());
+            } else if let Some(date) = token.strip_prefix("since:") {
+                query.since = Some(parse_date_millis("since", date)?);
+            } else if let Some(date) = token.strip_prefix("until:") {
+                query.until = Some(parse_date_millis("until", date)?);
+            } else if let Some(tag) = token
+                .strip_prefix(['#', '\u{FF03}'])
+                .and_then(normalize_hashtag)
+            {
+                query.hashtags.push(tag);
+            } else {
+                query.words.push(token.to_string());
+            }
+        }
+
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == SearchQuery::default()
+    }
+}
+
+/// Midnight UTC at the start of `date`, a `YYYY-MM-DD` date given to the
+/// `operator` operator.
+fn parse_date_millis(operator: &str, date: &str) -> Result<i64, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc().timestamp_millis())
+        .ok_or_else(|| format!("{}: needs a date like 2024-01-31, not {:?}", operator, date))
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    tweet_id: Field,
+    user_id: Field,
+    content: Field,
+    hashtags: Field,
+    created_at: Field,
+}
+
+struct Inner {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+    dirty: AtomicBool,
+}
+
+/// Full-text index of tweet content kept on local disk.
+#[derive(Clone)]
+pub struct SearchIndex(Arc<Inner>);
+
+impl SearchIndex {
+    pub fn open(path: &Path) -> tantivy::Result<Self> {
+        let mut schema_builder = Schema::builder();
+        let tweet_id = schema_builder.add_text_field("tweet_id", STRING | STORED);
+        let user_id = schema_builder.add_text_field("user_id", STRING);
+        let content = schema_builder.add_text_field("content", TEXT);
+        let hashtags = schema_builder.add_text_field("hashtags", STRING);
+        let created_at = schema_builder.add_i64_field("created_at", INDEXED | FAST);
+        let schema = schema_builder.build();
+
+        std::fs::create_dir_all(path)?;
+        let index = Index::open_or_create(MmapDirectory::open(path)?, schema)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let writer = index.writer(WRITER_MEMORY_BYTES)?;
+
+        Ok(SearchIndex(Arc::new(Inner {
+            index,
+            reader,
+            writer: Mutex::new(writer),
+            fields: Fields {
+                tweet_id,
+                user_id,
+                content,
+                hashtags,
+                created_at,
+            },
+            dirty: AtomicBool::new(false),
+        })))
+    }
+
+    /// Indexes a tweet, replacing any earlier version of it.
+    pub fn add_tweet(
+        &self,
+        tweet_id: Uuid,
+        user_id: Uuid,
+        content: &str,
+        created_at: i64,
+    ) -> tantivy::Result<()> {
+        let fields = self.0.fields;
+        let mut document = doc!(
+            fields.tweet_id => tweet_id.to_string(),
+            fields.user_id => user_id.to_string(),
+            fields.content => content,
+            fields.created_at => created_at,
+        );
+        for tag in TweetEntities::parse(content, &HashMap::new()).unique_hashtags() {
+            document.add_text(fields.hashtags, tag);
+        }
+
+        let writer = self.0.writer.lock().unwrap();
+        writer.delete_term(Term::from_field_text(
+            fields.tweet_id,
+            &tweet_id.to_string(),
+        ));
+        writer.add_document(document)?;
+        self.0.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn remove_tweet(&self, tweet_id: Uuid) {
+        let writer = self.0.writer.lock().unwrap();
+        writer.delete_term(Term::from_field_text(
+            self.0.fields.tweet_id,
+            &tweet_id.to_string(),
+        ));
+        self.0.dirty.store(true, Ordering::Release);
+    }
+
+    pub fn clear(&self) -> tantivy::Result<()> {
+        self.0.writer.lock().unwrap().delete_all_documents()?;
+        self.0.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Makes pending changes searchable. Does nothing if there are none.
+    pub fn commit(&self) -> tantivy::Result<()> {
+        if self.0.dirty.swap(false, Ordering::AcqRel) {
+            self.0.writer.lock().unwrap().commit()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of matching tweets, best first. `author_id` is the
+    /// resolved `from:` user, if any.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        author_id: Option<Uuid>,
+        order: SearchOrder,
+        offset: usize,
+        limit: usize,
+    ) -> tantivy::Result<Vec<Uuid>> {
+        let query = self.build_query(query, author_id)?;
+        let searcher = self.0.reader.searcher();
+        let top_docs = TopDocs::with_limit(limit).and_offset(offset);
+
+        let addresses: Vec<DocAddress> = match order {
+            SearchOrder::Recent => searcher
+                .search(
+                    &query,
+                    &top_docs.order_by_fast_field::<i64>("created_at", Order::Desc),
+                )?
+                .into_iter()
+                .map(|(_, address)| address)
+                .collect(),
+            SearchOrder::Relevance => {
+                let now = Utc::now().timestamp_millis();
+                let collector = top_docs.tweak_score(move |segment_reader: &SegmentReader| {
+                    let created_at = segment_reader
+                        .fast_fields()
+                        .i64("created_at")
+                        .map(|column| column.first_or_default_col(0));
+                    move |doc: DocId, score: Score| match &created_at {
+                        Ok(created_at) => {
+                            let age = (now - created_at.get_val(doc)).max(0) as f32;
+                            score * 0.5f32.powf(age / RECENCY_HALF_LIFE_MILLIS)
+                        }
+                        Err(_) => score,
+                    }
+                });
+                searcher
+                    .search(&query, &collector)?
+                    .into_iter()
+                    .map(|(_, address)| address)
+                    .collect()
+            }
+        };
+
+        let mut tweet_ids = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let document: TantivyDocument = searcher.doc(address)?;
+            if let Some(tweet_id) = document
+                .get_first(self.0.fields.tweet_id)
+                .and_then(|value| value.as_str())
+                .and_then(|value| Uuid::parse_str(value).ok())
+            {
+                tweet_ids.push(tweet_id);
+            }
+        }
+        Ok(tweet_ids)
+    }
+
+    fn build_query(
+        &self,
+        query: &SearchQuery,
+        author_id: Option<Uuid>,
+    ) -> tantivy::Result<Box<dyn Query>> {
+        let fields = self.0.fields;
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+        for word in &query.words {
+            for term in self.content_terms(word)? {
+                clauses.push((
+                    Occur::Must,
+                    Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs)),
+                ));
+            }
+        }
+        for phrase in &query.phrases {
+            let mut terms = self.content_terms(phrase)?;
+            match terms.len() {
+                0 => {}
+                1 => clauses.push((
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        terms.remove(0),
+                        IndexRecordOption::WithFreqs,
+                    )),
+                )),
+                _ => clauses.push((Occur::Must, Box::new(PhraseQuery::new(terms)))),
+            }
+        }
+        for tag in &query.hashtags {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(fields.hashtags, tag),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        if let Some(author_id) = author_id {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(fields.user_id, &author_id.to_string()),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+        if query.since.is_some() || query.until.is_some() {
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new_i64_bounds(
+                    "created_at".to_string(),
+                    query.since.map_or(Bound::Unbounded, Bound::Included),
+                    query.until.map_or(Bound::Unbounded, Bound::Excluded),
+                )),
+            ));
+        }
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// Splits text into index terms with the same tokenizer as `content`.
+    fn content_terms(&self, text: &str) -> tantivy::Result<Vec<Term>> {
+        let mut tokenizer = self.0.index.tokenizer_for_field(self.0.fields.content)?;
+        let mut stream = tokenizer.token_stream(text);
+        let mut terms = Vec::new();
+        while stream.advance() {
+            terms.push(Term::from_field_text(
+                self.0.fields.content,
+                &stream.token().text,
+            ));
+        }
+        Ok(terms)
+    }
+}
+
+/// Periodically commits the index so new tweets become searchable without
+/// paying for a commit on every write, and commits once more when the
+/// server exits.
+pub fn spawn_committer(search_index: SearchIndex, shutdown: &Shutdown) {
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(COMMIT_INTERVAL);
+        loop {
+            let draining = tokio::select! {
+                _ = interval.tick() => false,
+                _ = task_shutdown.draining() => true,
+            };
+            let search_index = search_index.clone();
+            match tokio::task::spawn_blocking(move || search_index.commit()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to commit search index: {:?}", e),
+                Err(e) => error!("Search index commit task failed: {:?}", e),
+            }
+            if draining {
+                break;
+            }
+        }
+    });
+}
+
+/// Rebuilds the index from scratch by scanning the `tweets` table. The
+/// server must not be running, since it holds the index's writer lock.
+pub async fn reindex(
+    session: &Session,
+    search_index: &SearchIndex,
+) -> Result<usize, Box<dyn Error>> {
+    search_index.clear()?;
+
+    let mut rows = session
+        .query_iter(
+            "SELECT tweet_id, user_id, content, created_at FROM twitter_clone.tweets",
+            (),
+        )
+        .await?;
+    let mut indexed = 0;
+    while let Some(row) = rows.next().await {
+        let mut columns = row?.columns.into_iter();
+        if let (
+            Some(CqlValue::Uuid(tweet_id)),
+            Some(CqlValue::Uuid(user_id)),
+            Some(CqlValue::Text(content)),
+            Some(CqlValue::Timestamp(created_at)),
+        ) = (
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+            columns.next().flatten(),
+        ) {
+            search_index.add_tweet(tweet_id, user_id, &content, created_at.0)?;
+            indexed += 1;
+            if indexed % 10_000 == 0 {
+                info!("Indexed {} tweets", indexed);
+            }
+        }
+    }
+
+    search_index.commit()?;
+    Ok(indexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(q: &str) -> SearchQuery {
+        SearchQuery::parse(q).unwrap()
+    }
+
+    fn millis(date: &str) -> i64 {
+        parse_date_millis("test", date).unwrap()
+    }
+
+    #[test]
+    fn words_and_phrases() {
+        let query = parse(r#"  rust "async await"  tokio "" "#);
+        assert_eq!(query.words, ["rust", "tokio"]);
+        assert_eq!(query.phrases, ["async await"]);
+        // An unterminated quote runs to the end.
+        let query = parse(r#"rust "async await"#);
+        assert_eq!(query.words, ["rust"]);
+        assert_eq!(query.phrases, ["async await"]);
+        assert!(parse("   ").is_empty());
+    }
+
+    #[test]
+    fn operators() {
+        let query = parse("from:@Alice since:2024-01-01 until:2024-02-01 hi");
+        assert_eq!(query.from.as_deref(), Some("Alice"));
+        assert_eq!(query.since, Some(1_704_067_200_000));
+        assert_eq!(query.until, Some(1_706_745_600_000));
+        assert_eq!(query.words, ["hi"]);
+        assert_eq!(parse("from:bob").from.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn malformed_dates_are_errors() {
+        for q in [
+            "since:2024-13-01",
+            "until:yesterday",
+            "rust since:",
+            "since:2024/01/01",
+        ] {
+            assert!(SearchQuery::parse(q).is_err(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn hashtags_are_normalized() {
+        let query = parse("#Rust ＃Tokio #");
+        assert_eq!(query.hashtags, ["rust", "tokio"]);
+        assert_eq!(query.words, ["#"]);
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let path = std::env::temp_dir().join(format!("search-test-{}", Uuid::new_v4()));
+        let index = SearchIndex::open(&path).unwrap();
+        let search = |q: &str| {
+            index.0.reader.reload().unwrap();
+            index
+                .search(&parse(q), None, SearchOrder::Recent, 0, 10)
+                .unwrap()
+        };
+        let author = Uuid::new_v4();
+        let (first, midnight, later) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let day = 24 * 60 * 60 * 1000;
+        let start = millis("2024-01-01");
+        index
+            .add_tweet(first, author, "learning #rust today", start + day / 2)
+            .unwrap();
+        index
+            .add_tweet(midnight, author, "more rust", start + day)
+            .unwrap();
+        index
+            .add_tweet(later, Uuid::new_v4(), "rust again", start + 2 * day)
+            .unwrap();
+        index.commit().unwrap();
+
+        assert_eq!(search("rust"), [later, midnight, first]);
+        assert_eq!(search("#rust"), [first]);
+        // until: excludes the day it names, since: includes it.
+        assert_eq!(search("rust until:2024-01-02"), [first]);
+        assert_eq!(search("rust since:2024-01-02"), [later, midnight]);
+        assert_eq!(search(r#""more rust""#), [midnight]);
+        let from_author = index
+            .search(&parse("rust"), Some(author), SearchOrder::Recent, 0, 10)
+            .unwrap();
+        assert_eq!(from_author, [midnight, first]);
+
+        index.remove_tweet(later);
+        index
+            .add_tweet(first, author, "learning go today", start + day / 2)
+            .unwrap();
+        index.commit().unwrap();
+        assert_eq!(search("rust"), [midnight]);
+        assert_eq!(search("go"), [first]);
+
+        drop(index);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use futures::future::join_all;
+use log::{info, warn};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Interval;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Phase {
+    Running,
+    /// No new requests are taken; those in flight are finished and the
+    /// periodic jobs wind down.
+    Stopping,
+    /// The fan-out queues are closed and what is left in them handled.
+    Draining,
+}
+
+/// Coordinates stopping the server with its background tasks, which are
+/// started through `spawn` so that `drain` can wait for them.
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: Arc<watch::Sender<Phase>>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (phase, _) = watch::channel(Phase::Running);
+        Shutdown {
+            phase: Arc::new(phase),
+            tasks: Arc::default(),
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.phase.borrow() >= Phase::Stopping
+    }
+
+    /// Resolves once the server starts stopping.
+    pub async fn stopping(&self) {
+        self.reached(Phase::Stopping).await
+    }
+
+    /// Resolves once the fan-out queues should be emptied and closed.
+    pub async fn draining(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    /// Waits for a periodic job's next tick; `false` once the server is
+    /// stopping.
+    pub async fn tick(&self, interval: &mut Interval) -> bool {
+        tokio::select! {
+            biased;
+            _ = self.stopping() => false,
+            _ = interval.tick() => true,
+        }
+    }
+
+    /// Receives the next event of a fan-out queue. Once draining, the queue
+    /// is closed, so what is left in it is returned and then `None`.
+    pub async fn recv<T>(&self, receiver: &mut mpsc::Receiver<T>) -> Option<T> {
+        tokio::select! {
+            biased;
+            event = receiver.recv() => event,
+            _ = self.draining() => {
+                receiver.close();
+                receiver.recv().await
+            }
+        }
+    }
+
+    async fn reached(&self, phase: Phase) {
+        let mut receiver = self.phase.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = receiver.wait_for(|current| *current >= phase).await;
+    }
+
+    /// Runs a background task that `drain` waits for. It should return
+    /// soon after `stopping` or `draining` resolves.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let handle = tokio::spawn(task);
+        self.tasks.lock().unwrap().push(handle);
+    }
+
+    pub fn stop(&self) {
+        self.advance(Phase::Stopping);
+    }
+
+    /// Once no more requests are coming, closes the fan-out queues and
+    /// waits up to `timeout` for every background task to finish.
+    pub async fn drain(&self, timeout: Duration) {
+        self.advance(Phase::Draining);
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        info!("Waiting for {} background tasks", tasks.len());
+        if tokio::time::timeout(timeout, join_all(tasks))
+            .await
+            .is_err()
+        {
+            warn!("Background tasks still running after {:?}", timeout);
+        }
+    }
+
+    fn advance(&self, phase: Phase) {
+        self.phase.send_if_modified(|current| {
+            let advanced = *current < phase;
+            if advanced {
+                *current = phase;
+            }
+            advanced
+        });
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resolves on `SIGTERM` or Ctrl-C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
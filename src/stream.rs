@@ -0,0 +1,260 @@
+use chrono::Utc;
+use log::{error, warn};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+use crate::shutdown::Shutdown;
+
+/// Events buffered per connection. A client that falls this far behind is
+/// disconnected and catches up by reconnecting with `Last-Event-ID`.
+const CONNECTION_BUFFER: usize = 128;
+/// Recent events kept per user for replay on reconnect.
+const REPLAY_BUFFER: usize = 100;
+/// How long an event can be replayed. Clients reconnect within seconds;
+/// older events are dropped so that users who stop getting any don't keep
+/// a buffer forever.
+const REPLAY_TTL: Duration = Duration::from_secs(5 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct StreamEvent {
+    pub id: u64,
+    pub event: &'static str,
+    pub data: String,
+    published_at: Instant,
+}
+
+impl StreamEvent {
+    fn is_expired(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.published_at) > REPLAY_TTL
+    }
+
+    /// Formats the event as a Server-Sent Events message.
+    pub fn to_sse(&self) -> String {
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.event, self.data
+        )
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    next_id: u64,
+    subscribers: HashMap<Uuid, Vec<mpsc::Sender<Arc<StreamEvent>>>>,
+    recent: HashMap<Uuid, VecDeque<Arc<StreamEvent>>>,
+}
+
+/// In-process pub/sub delivering feed items and notifications to the
+/// streaming connections of each user.
+#[derive(Clone)]
+pub struct Broker(Arc<Mutex<BrokerState>>);
+
+impl Broker {
+    pub fn new() -> Self {
+        // Ids start from the current time so they keep increasing across
+        // restarts and a reconnecting client never skips new events.
+        let state = BrokerState {
+            next_id: Utc::now().timestamp_millis() as u64 * 1000,
+            ..Default::default()
+        };
+        Broker(Arc::new(Mutex::new(state)))
+    }
+
+    pub fn publish<T: Serialize>(&self, user_id: Uuid, event: &'static str, payload: &T) {
+        let data = match serde_json::to_string(payload) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to serialize {} event: {:?}", event, e);
+                return;
+            }
+        };
+
+        let mut state = self.0.lock().unwrap();
+        state.next_id += 1;
+        let event = Arc::new(StreamEvent {
+            id: state.next_id,
+            event,
+            data,
+            published_at: Instant::now(),
+        });
+
+        let recent = state.recent.entry(user_id).or_default();
+        if recent.len() == REPLAY_BUFFER {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+
+        if let Some(subscribers) = state.subscribers.get_mut(&user_id) {
+            subscribers.retain(|subscriber| match subscriber.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Disconnecting slow stream consumer for user {}", user_id);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+            if subscribers.is_empty() {
+                state.subscribers.remove(&user_id);
+            }
+        }
+    }
+
+    /// Registers a new connection for `user_id`, first replaying buffered
+    /// events newer than `last_event_id`.
+    pub fn subscribe(
+        &self,
+        user_id: Uuid,
+        last_event_id: Option<u64>,
+    ) -> mpsc::Receiver<Arc<StreamEvent>> {
+        let (sender, receiver) = mpsc::channel(CONNECTION_BUFFER);
+
+        let now = Instant::now();
+        let mut state = self.0.lock().unwrap();
+        if let (Some(last_event_id), Some(recent)) = (last_event_id, state.recent.get(&user_id)) {
+            for event in recent
+                .iter()
+                .filter(|event| event.id > last_event_id && !event.is_expired(now))
+            {
+                // The replay buffer is smaller than the channel, so this
+                // cannot fail on a fresh connection.
+                let _ = sender.try_send(event.clone());
+            }
+        }
+        state.subscribers.entry(user_id).or_default().push(sender);
+
+        receiver
+    }
+
+    /// Drops events too old to replay, the buffers left empty, and closed
+    /// connections of users who haven't had an event since.
+    fn sweep(&self, now: Instant) {
+        let mut state = self.0.lock().unwrap();
+        state.recent.retain(|_, recent| {
+            while recent.front().is_some_and(|event| event.is_expired(now)) {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+        state.subscribers.retain(|_, subscribers| {
+            subscribers.retain(|subscriber| !subscriber.is_closed());
+            !subscribers.is_empty()
+        });
+    }
+}
+
+/// Sweeps the broker's replay buffers every minute.
+pub fn spawn_sweeper(broker: Broker, shutdown: &Shutdown) {
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        while task_shutdown.tick(&mut interval).await {
+            broker.sweep(Instant::now());
+        }
+    });
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(receiver: &mut mpsc::Receiver<Arc<StreamEvent>>) -> Vec<String> {
+        let mut events = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            events.push(event.data.clone());
+        }
+        events
+    }
+
+    fn last_id(broker: &Broker, user_id: Uuid) -> u64 {
+        broker.0.lock().unwrap().recent[&user_id].back().unwrap().id
+    }
+
+    #[test]
+    fn reconnecting_replays_only_later_events() {
+        let broker = Broker::new();
+        let user_id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+        broker.publish(user_id, "tweet", &1);
+        let seen = last_id(&broker, user_id);
+        broker.publish(user_id, "tweet", &2);
+        broker.publish(other_id, "tweet", &99);
+        broker.publish(user_id, "notification", &3);
+
+        let mut receiver = broker.subscribe(user_id, Some(seen));
+        assert_eq!(drain(&mut receiver), ["2", "3"]);
+        // Without a Last-Event-ID only new events arrive.
+        let mut fresh = broker.subscribe(user_id, None);
+        assert!(drain(&mut fresh).is_empty());
+        broker.publish(user_id, "tweet", &4);
+        assert_eq!(drain(&mut receiver), ["4"]);
+        assert_eq!(drain(&mut fresh), ["4"]);
+    }
+
+    #[test]
+    fn replay_keeps_the_last_100_events() {
+        let broker = Broker::new();
+        let user_id = Uuid::new_v4();
+        for n in 0..REPLAY_BUFFER + 5 {
+            broker.publish(user_id, "tweet", &n);
+        }
+        let mut receiver = broker.subscribe(user_id, Some(0));
+        let replayed = drain(&mut receiver);
+        assert_eq!(replayed.len(), REPLAY_BUFFER);
+        assert_eq!(replayed[0], "5");
+        assert_eq!(replayed[REPLAY_BUFFER - 1], (REPLAY_BUFFER + 4).to_string());
+    }
+
+    #[test]
+    fn events_expire_after_five_minutes() {
+        let broker = Broker::new();
+        let user_id = Uuid::new_v4();
+        broker.publish(user_id, "tweet", &1);
+
+        broker.sweep(Instant::now() + REPLAY_TTL - Duration::from_secs(1));
+        assert!(broker.0.lock().unwrap().recent.contains_key(&user_id));
+        broker.sweep(Instant::now() + REPLAY_TTL + Duration::from_secs(1));
+        assert!(broker.0.lock().unwrap().recent.is_empty());
+        let mut receiver = broker.subscribe(user_id, Some(0));
+        assert!(drain(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn sweeping_drops_closed_connections() {
+        let broker = Broker::new();
+        let user_id = Uuid::new_v4();
+        drop(broker.subscribe(user_id, None));
+        let _open = broker.subscribe(user_id, None);
+        broker.sweep(Instant::now());
+        assert_eq!(broker.0.lock().unwrap().subscribers[&user_id].len(), 1);
+    }
+
+    #[test]
+    fn a_full_connection_is_dropped_without_blocking_others() {
+        let broker = Broker::new();
+        let user_id = Uuid::new_v4();
+        let mut slow = broker.subscribe(user_id, None);
+        let mut fast = broker.subscribe(user_id, None);
+
+        for n in 0..=CONNECTION_BUFFER {
+            broker.publish(user_id, "tweet", &n);
+            assert_eq!(drain(&mut fast), [n.to_string()]);
+        }
+        assert_eq!(broker.0.lock().unwrap().subscribers[&user_id].len(), 1);
+        // The slow connection gets what fit, then is closed.
+        assert_eq!(drain(&mut slow).len(), CONNECTION_BUFFER);
+        assert!(slow.is_closed());
+        broker.publish(user_id, "tweet", &"after");
+        assert_eq!(drain(&mut fast), ["\"after\""]);
+    }
+}
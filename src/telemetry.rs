@@ -0,0 +1,182 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use log::error;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Instant;
+use tracing::field::{self, Empty};
+use tracing::level_filters::LevelFilter;
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::logging::{self, LogLevels};
+use crate::metrics;
+use crate::rate_limit;
+
+/// Reported as `service.name` unless `OTEL_SERVICE_NAME` says otherwise.
+const SERVICE_NAME: &str = "twitter_clone";
+/// Keeps the trace pipeline alive; `shutdown` sends what is still buffered.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+    log_levels: LogLevels,
+}
+
+impl Telemetry {
+    pub fn log_levels(&self) -> LogLevels {
+        self.log_levels.clone()
+    }
+
+    pub fn shutdown(self) {
+        if let Err(e) = self.provider.shutdown() {
+            error!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Sets up logging (see `logging::layer`) and tracing. Spans at `info` and
+/// above are exported over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` (or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`) is set; otherwise they only give
+/// requests their trace ids.
+pub fn init() -> Result<Telemetry, Box<dyn Error>> {
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(SERVICE_NAME);
+    }
+    let mut provider = SdkTracerProvider::builder().with_resource(resource.build());
+    let exporting = [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var_os(var).is_some());
+    if exporting {
+        // The endpoint, headers and timeout are read from the standard
+        // OTEL_EXPORTER_OTLP_* variables.
+        let exporter = SpanExporter::builder().with_http().build()?;
+        provider = provider.with_batch_exporter(exporter);
+    }
+    let provider = provider.build();
+
+    let (log_layer, log_levels) = logging::layer()?;
+    let trace_layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME))
+        .with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry()
+        .with(log_layer)
+        .with(trace_layer)
+        .try_init()?;
+    Ok(Telemetry {
+        provider,
+        log_levels,
+    })
+}
+
+/// The span a request is handled in, named by its method and route. It
+/// continues the caller's trace if the request has a valid W3C
+/// `traceparent` header, and starts a new one otherwise.
+pub fn request_span(req: &ServiceRequest, request_id: &str) -> Span {
+    let route = metrics::route(req);
+    let span = tracing::info_span!(
+        "request",
+        otel.name = format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        http.route = route,
+        url.path = req.path(),
+        http.response.status_code = Empty,
+        request_id,
+        user_id = Empty,
+        trace_id = Empty,
+    );
+    if let Some(user_id) = rate_limit::user_id(req) {
+        span.record("user_id", field::display(user_id));
+    }
+    let parent = TraceContextPropagator::new().extract(&RequestHeaders(req.headers()));
+    // Only fails when no tracing layer is installed.
+    let _ = span.set_parent(parent);
+    let trace_id = span.context().span().span_context().trace_id();
+    span.record("trace_id", field::display(trace_id));
+    span
+}
+
+/// Runs a request in its span, records the response status on it, writes
+/// the access log line, and echoes the trace context in a `traceparent`
+/// response header.
+pub async fn traced<B>(
+    span: Span,
+    response: impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>,
+) -> Result<ServiceResponse<B>, actix_web::Error> {
+    let started = Instant::now();
+    let response = response.instrument(span.clone()).await;
+    let status = match &response {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    span.in_scope(|| logging::access(status, started.elapsed()));
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+
+    let mut response = response?;
+    TraceContextPropagator::new().inject_context(
+        &span.context(),
+        &mut ResponseHeaders(response.headers_mut()),
+    );
+    Ok(response)
+}
+
+/// The span a CQL statement runs in, named by its verb and table, e.g.
+/// `SELECT tweets`. The statement text is sent without its values.
+pub fn query_span(summary: &str, cql: &str) -> Span {
+    tracing::info_span!(
+        "query",
+        otel.name = summary,
+        otel.kind = "client",
+        otel.status_code = Empty,
+        otel.status_description = Empty,
+        db.system.name = "scylladb",
+        db.query.summary = summary,
+        db.query.text = cql,
+        db.response.returned_rows = Empty,
+    )
+}
+
+/// Marks a span as failed, with the error as its status message.
+pub fn record_error(span: &Span, e: &impl fmt::Display) {
+    span.record("otel.status_code", "ERROR");
+    span.record("otel.status_description", field::display(e));
+}
+
+struct RequestHeaders<'a>(&'a HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct ResponseHeaders<'a>(&'a mut HeaderMap);
+
+impl Injector for ResponseHeaders<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::HttpResponse;
+use futures::future::Either;
+use log::{error, info};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use std::error::Error;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::shutdown::Shutdown;
+
+/// Where HTTPS is served unless `HTTPS_BIND` says otherwise.
+const DEFAULT_HTTPS_BIND: &str = "0.0.0.0:8443";
+/// How long browsers remember to only use HTTPS unless `HSTS_MAX_AGE`
+/// says otherwise: a year.
+const DEFAULT_HSTS_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+/// How often the certificate and key files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// Still answered over plain HTTP, so that probes and Prometheus inside the
+/// network don't need the certificate.
+const PLAIN_HTTP_PATHS: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+/// Serving HTTPS, set up from the environment.
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// The address HTTPS is served on.
+    pub bind: String,
+    pub https: Https,
+}
+
+/// What the plain HTTP listener needs to send clients to HTTPS.
+#[derive(Clone, Copy)]
+pub struct Https {
+    /// The port clients reach HTTPS on, which differs from the bound one
+    /// behind a port mapping.
+    port: u16,
+    hsts_max_age: u64,
+}
+
+/// HTTPS is served when `TLS_CERT_PATH` and `TLS_KEY_PATH` point to PEM
+/// files with the certificate chain and its private key.
+pub fn from_env() -> Result<Option<TlsConfig>, Box<dyn Error>> {
+    let (cert_path, key_path) = match (
+        std::env::var_os("TLS_CERT_PATH"),
+        std::env::var_os("TLS_KEY_PATH"),
+    ) {
+        (None, None) => return Ok(None),
+        (Some(cert_path), Some(key_path)) => (PathBuf::from(cert_path), PathBuf::from(key_path)),
+        _ => return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".into()),
+    };
+    let bind = std::env::var("HTTPS_BIND").unwrap_or_else(|_| DEFAULT_HTTPS_BIND.to_string());
+    let port = match std::env::var("HTTPS_PUBLIC_PORT") {
+        Ok(port) => port.parse()?,
+        Err(_) => bind
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(|| format!("HTTPS_BIND {:?} has no port", bind))?,
+    };
+    let hsts_max_age = match std::env::var("HSTS_MAX_AGE") {
+        Ok(max_age) => max_age.parse()?,
+        Err(_) => DEFAULT_HSTS_MAX_AGE,
+    };
+    Ok(Some(TlsConfig {
+        cert_path,
+        key_path,
+        bind,
+        https: Https { port, hsts_max_age },
+    }))
+}
+
+impl TlsConfig {
+    /// Loads the certificate, and keeps reloading it while the server runs
+    /// whenever its files change.
+    pub fn server_config(&self, shutdown: &Shutdown) -> Result<ServerConfig, Box<dyn Error>> {
+        let resolver = Arc::new(CertResolver {
+            current: RwLock::new(Arc::new(load_certified_key(
+                &self.cert_path,
+                &self.key_path,
+            )?)),
+            cert_path: self.cert_path.clone(),
+            key_path: self.key_path.clone(),
+        });
+        spawn_reloader(resolver.clone(), shutdown);
+        Ok(ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver))
+    }
+}
+
+/// Hands every handshake the certificate loaded last.
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate in {}", cert_path.display()).into());
+    }
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) => break key,
+            Some(_) => {}
+            None => return Err(format!("No private key in {}", key_path.display()).into()),
+        }
+    };
+    let key = sign::any_supported_type(&PrivateKey(key))
+        .map_err(|_| format!("Unsupported private key in {}", key_path.display()))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+/// Swaps in the new certificate once its files have changed and then been
+/// left alone for a check, so that a renewal written file by file isn't
+/// picked up halfway. A certificate that fails to load is logged and the
+/// old one kept.
+fn spawn_reloader(resolver: Arc<CertResolver>, shutdown: &Shutdown) {
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        let mut loaded = resolver.modified();
+        let mut seen = loaded;
+        while task_shutdown.tick(&mut interval).await {
+            let modified = resolver.modified();
+            if modified != seen {
+                seen = modified;
+                continue;
+            }
+            if modified == loaded || modified.is_none() {
+                continue;
+            }
+            loaded = modified;
+            match resolver.reload() {
+                Ok(()) => info!("Reloaded TLS certificate {}", resolver.cert_path.display()),
+                Err(e) => error!(
+                    "Failed to reload TLS certificate, keeping the old one: {}",
+                    e
+                ),
+            }
+        }
+    });
+}
+
+impl Https {
+    /// A permanent redirect to the same URL over HTTPS, for requests that
+    /// came in over plain HTTP.
+    fn redirect(&self, req: &ServiceRequest) -> Option<HttpResponse> {
+        if req.app_config().secure() || PLAIN_HTTP_PATHS.contains(&req.path()) {
+            return None;
+        }
+        let connection_info = req.connection_info();
+        let host = connection_info.host();
+        // Drop the plain HTTP port, minding IPv6 addresses like `[::1]:8080`.
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.ends_with(']') => name,
+            _ => host,
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        let location = match self.port {
+            443 => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path),
+        };
+        Some(
+            HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, location))
+                .finish(),
+        )
+    }
+}
+
+/// With HTTPS on, redirects requests that came in over plain HTTP to it and
+/// adds a `Strict-Transport-Security` header to responses sent over it.
+/// Used as middleware around the app.
+pub fn enforce<S, B>(
+    https: Option<Https>,
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let hsts_max_age = https
+        .filter(|_| req.app_config().secure())
+        .map(|https| https.hsts_max_age);
+    let response = match https.and_then(|https| https.redirect(&req)) {
+        Some(redirect) => Either::Left(req.into_response(redirect)),
+        None => Either::Right(srv.call(req)),
+    };
+    async move {
+        let mut response = match response {
+            Either::Left(redirect) => redirect.map_into_right_body(),
+            Either::Right(response) => response.await?.map_into_left_body(),
+        };
+        if let Some(max_age) = hsts_max_age {
+            response.headers_mut().insert(
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&format!("max-age={}", max_age)).expect("valid header value"),
+            );
+        }
+        Ok(response)
+    }
+}
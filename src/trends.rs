@@ -0,0 +1,454 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use utoipa::ToSchema;
+
+use crate::shutdown::Shutdown;
+
+/// Events waiting to be counted are dropped once this many are queued.
+const QUEUE_CAPACITY: usize = 10_000;
+const SKETCH_WIDTH: usize = 2048;
+const SKETCH_DEPTH: usize = 4;
+/// Hashtags tracked as possible trends per window. Less frequent tags are
+/// still counted by the sketches, just not reported.
+const MAX_CANDIDATES: usize = 256;
+/// A hashtag is spiking when its last hour is this many times its hourly
+/// rate over the rest of the day...
+const SPIKE_RATIO: f64 = 3.0;
+/// ...and it was used at least this often in the last hour.
+const MIN_SPIKE_COUNT: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum TrendWindow {
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "24h")]
+    Day,
+}
+
+impl TrendWindow {
+    fn length_millis(self) -> i64 {
+        match self {
+            TrendWindow::Hour => 60 * 60 * 1000,
+            TrendWindow::Day => 24 * 60 * 60 * 1000,
+        }
+    }
+
+    fn bucket_millis(self) -> i64 {
+        match self {
+            TrendWindow::Hour => 60 * 1000,
+            TrendWindow::Day => 15 * 60 * 1000,
+        }
+    }
+
+    fn bucket_count(self) -> usize {
+        (self.length_millis() / self.bucket_millis()) as usize
+    }
+
+    /// Counts lose half their weight in the trend score over this long, so
+    /// the ranking favours what's being said now.
+    fn half_life_millis(self) -> f64 {
+        self.length_millis() as f64 / 4.0
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Trend {
+    pub hashtag: String,
+    /// Approximate uses within the window. Never an undercount.
+    pub count: u32,
+    /// `count` with older uses weighted down; trends are ranked by this.
+    pub score: f64,
+    /// Uses in the last hour relative to the average hour before it.
+    pub velocity: f64,
+    pub spiking: bool,
+}
+
+/// Count-min sketch: approximate counts in fixed memory. Estimates can be
+/// too high when hashtags collide, but never too low.
+struct CountMinSketch {
+    counters: Vec<u32>,
+}
+
+impl CountMinSketch {
+    fn new() -> Self {
+        CountMinSketch {
+            counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+        }
+    }
+
+    fn slot(row: usize, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH)
+    }
+
+    fn add(&mut self, key: &str, count: u32) {
+        for row in 0..SKETCH_DEPTH {
+            let slot = Self::slot(row, key);
+            self.counters[slot] = self.counters[slot].saturating_add(count);
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u32 {
+        (0..SKETCH_DEPTH)
+            .map(|row| self.counters[Self::slot(row, key)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn subtract(&mut self, other: &CountMinSketch) {
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter = counter.saturating_sub(*other);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.counters.fill(0);
+    }
+}
+
+struct Bucket {
+    number: i64,
+    sketch: CountMinSketch,
+}
+
+/// Counts over the last `window`, split into buckets that expire one at a
+/// time as the window slides.
+struct SlidingWindow {
+    window: TrendWindow,
+    /// Ring of buckets; bucket `n` lives in slot `n % bucket_count`.
+    buckets: Vec<Bucket>,
+    /// Sum of every bucket, for cheap whole-window estimates.
+    total: CountMinSketch,
+    latest: Option<i64>,
+    /// Heavy-hitter candidates and their count when last seen.
+    candidates: HashMap<String, u32>,
+}
+
+impl SlidingWindow {
+    fn new(window: TrendWindow) -> Self {
+        SlidingWindow {
+            window,
+            buckets: (0..window.bucket_count())
+                .map(|_| Bucket {
+                    number: i64::MIN,
+                    sketch: CountMinSketch::new(),
+                })
+                .collect(),
+            total: CountMinSketch::new(),
+            latest: None,
+            candidates: HashMap::new(),
+        }
+    }
+
+    /// Slides the window forward to end at `now`, expiring old buckets.
+    fn advance(&mut self, now: i64) {
+        let current = now.div_euclid(self.window.bucket_millis());
+        let bucket_count = self.buckets.len() as i64;
+        let first_new = match self.latest {
+            Some(latest) if latest >= current => return,
+            Some(latest) => (latest + 1).max(current - bucket_count + 1),
+            None => current - bucket_count + 1,
+        };
+
+        for number in first_new..=current {
+            let bucket = &mut self.buckets[number.rem_euclid(bucket_count) as usize];
+            self.total.subtract(&bucket.sketch);
+            bucket.sketch.clear();
+            bucket.number = number;
+        }
+        self.latest = Some(current);
+
+        let total = &self.total;
+        self.candidates.retain(|hashtag, count| {
+            *count = total.estimate(hashtag);
+            *count > 0
+        });
+    }
+
+    fn add(&mut self, hashtag: &str, at: i64) {
+        self.advance(at);
+        let number = at.div_euclid(self.window.bucket_millis());
+        let bucket_count = self.buckets.len() as i64;
+        let bucket = &mut self.buckets[number.rem_euclid(bucket_count) as usize];
+        // Too old for the window.
+        if bucket.number != number {
+            return;
+        }
+        bucket.sketch.add(hashtag, 1);
+        self.total.add(hashtag, 1);
+
+        let count = self.total.estimate(hashtag);
+        self.candidates.insert(hashtag.to_string(), count);
+        if self.candidates.len() > MAX_CANDIDATES {
+            if let Some(least) = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(hashtag, _)| hashtag.clone())
+            {
+                self.candidates.remove(&least);
+            }
+        }
+    }
+
+    fn count(&self, hashtag: &str) -> u32 {
+        self.total.estimate(hashtag)
+    }
+
+    fn score(&self, hashtag: &str, now: i64) -> f64 {
+        let bucket_millis = self.window.bucket_millis();
+        let half_life = self.window.half_life_millis();
+        self.buckets
+            .iter()
+            .filter(|bucket| bucket.number != i64::MIN)
+            .map(|bucket| {
+                let age = (now - (bucket.number + 1) * bucket_millis).max(0) as f64;
+                bucket.sketch.estimate(hashtag) as f64 * 0.5f64.powf(age / half_life)
+            })
+            .sum()
+    }
+}
+
+/// Hashtag counts over the last hour and day. Timestamps are passed in, so
+/// it can be driven by synthetic event streams as well as live tweets.
+pub struct TrendTracker {
+    hour: SlidingWindow,
+    day: SlidingWindow,
+}
+
+impl TrendTracker {
+    pub fn new() -> Self {
+        TrendTracker {
+            hour: SlidingWindow::new(TrendWindow::Hour),
+            day: SlidingWindow::new(TrendWindow::Day),
+        }
+    }
+
+    /// Counts one use of each (normalized) hashtag at `at` milliseconds.
+    pub fn record(&mut self, hashtags: &[String], at: i64) {
+        for hashtag in hashtags {
+            self.hour.add(hashtag, at);
+            self.day.add(hashtag, at);
+        }
+    }
+
+    /// The top hashtags in `window` as of `now` milliseconds.
+    pub fn top(&mut self, window: TrendWindow, now: i64, limit: usize) -> Vec<Trend> {
+        self.hour.advance(now);
+        self.day.advance(now);
+
+        let source = match window {
+            TrendWindow::Hour => &self.hour,
+            TrendWindow::Day => &self.day,
+        };
+        let earlier_hours =
+            (TrendWindow::Day.length_millis() / TrendWindow::Hour.length_millis() - 1) as f64;
+        let mut trends: Vec<Trend> = source
+            .candidates
+            .keys()
+            .map(|hashtag| {
+                let last_hour = self.hour.count(hashtag);
+                // Earlier hours of the day, floored at one use an hour so a
+                // brand new hashtag needs real volume to count as a spike.
+                let baseline = (self.day.count(hashtag).saturating_sub(last_hour) as f64
+                    / earlier_hours)
+                    .max(1.0);
+                let velocity = last_hour as f64 / baseline;
+                Trend {
+                    hashtag: hashtag.clone(),
+                    count: source.count(hashtag),
+                    score: source.score(hashtag, now),
+                    velocity,
+                    spiking: last_hour >= MIN_SPIKE_COUNT && velocity >= SPIKE_RATIO,
+                }
+            })
+            .filter(|trend| trend.count > 0)
+            .collect();
+
+        trends.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.hashtag.cmp(&b.hashtag))
+        });
+        trends.truncate(limit);
+        trends
+    }
+}
+
+impl Default for TrendTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hashtags of a newly created tweet.
+#[derive(Debug)]
+pub struct TrendEvent {
+    pub hashtags: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct TrendSender {
+    sender: mpsc::Sender<TrendEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TrendSender {
+    pub fn send(&self, event: TrendEvent) {
+        if let Err(e) = self.sender.try_send(event) {
+            warn!("Dropping trend event: {}", e);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Events waiting to be counted.
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Events dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Shared `TrendTracker` fed by tweet creation events.
+#[derive(Clone, Default)]
+pub struct Trends(Arc<Mutex<TrendTracker>>);
+
+impl Trends {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn top(&self, window: TrendWindow, limit: usize) -> Vec<Trend> {
+        self.0
+            .lock()
+            .unwrap()
+            .top(window, Utc::now().timestamp_millis(), limit)
+    }
+}
+
+/// Starts the task that counts the hashtags of new tweets into `trends`.
+pub fn spawn_consumer(trends: Trends, shutdown: &Shutdown) -> TrendSender {
+    let (sender, mut receiver) = mpsc::channel::<TrendEvent>(QUEUE_CAPACITY);
+
+    let task_shutdown = shutdown.clone();
+    shutdown.spawn(async move {
+        while let Some(event) = task_shutdown.recv(&mut receiver).await {
+            trends
+                .0
+                .lock()
+                .unwrap()
+                .record(&event.hashtags, event.created_at.timestamp_millis());
+        }
+    });
+
+    TrendSender {
+        sender,
+        dropped: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60 * 1000;
+    const HOUR: i64 = 60 * MINUTE;
+    /// A whole hour, so buckets of both windows start on it.
+    const START: i64 = 472_222 * HOUR;
+
+    #[test]
+    fn sketches_never_undercount() {
+        let mut sketch = CountMinSketch::new();
+        for n in 0..10_000 {
+            sketch.add(&format!("tag{}", n), 1);
+        }
+        sketch.add("rust", 5);
+        assert!(sketch.estimate("rust") >= 5);
+        for n in 0..10_000 {
+            assert!(sketch.estimate(&format!("tag{}", n)) >= 1);
+        }
+
+        let mut other = CountMinSketch::new();
+        other.add("rust", 2);
+        let mut total = CountMinSketch::new();
+        total.add("rust", 5);
+        total.subtract(&other);
+        assert_eq!(total.estimate("rust"), 3);
+        total.subtract(&other);
+        total.subtract(&other);
+        assert_eq!(total.estimate("rust"), 0);
+        sketch.clear();
+        assert_eq!(sketch.estimate("rust"), 0);
+    }
+
+    #[test]
+    fn buckets_expire_as_the_window_slides() {
+        let mut window = SlidingWindow::new(TrendWindow::Hour);
+        window.add("rust", START);
+        window.add("rust", START + 30 * MINUTE);
+        assert_eq!(window.count("rust"), 2);
+
+        window.advance(START + 59 * MINUTE);
+        assert_eq!(window.count("rust"), 2);
+        window.advance(START + HOUR);
+        assert_eq!(window.count("rust"), 1);
+        assert!(window.candidates.contains_key("rust"));
+        window.advance(START + HOUR + 30 * MINUTE);
+        assert_eq!(window.count("rust"), 0);
+        assert!(window.candidates.is_empty());
+    }
+
+    #[test]
+    fn windows_ignore_uses_older_than_they_cover() {
+        let mut window = SlidingWindow::new(TrendWindow::Hour);
+        window.add("rust", START + 2 * HOUR);
+        window.add("rust", START);
+        assert_eq!(window.count("rust"), 1);
+
+        // Jumping past a whole window clears every bucket.
+        window.advance(START + 10 * HOUR);
+        assert_eq!(window.count("rust"), 0);
+    }
+
+    #[test]
+    fn recent_uses_score_higher_and_sudden_ones_spike() {
+        let now = START + 24 * HOUR;
+        let mut tracker = TrendTracker::new();
+        for minute in 0..30 {
+            tracker.record(&["old".to_string()], now - 20 * HOUR + minute * MINUTE);
+            tracker.record(&["new".to_string()], now - 30 * MINUTE + minute * MINUTE);
+        }
+
+        let hour = tracker.top(TrendWindow::Hour, now, 10);
+        assert_eq!(hour.len(), 1);
+        assert_eq!(hour[0].hashtag, "new");
+        assert_eq!(hour[0].count, 30);
+        assert!(hour[0].spiking);
+
+        let day = tracker.top(TrendWindow::Day, now, 10);
+        let hashtags: Vec<&str> = day.iter().map(|trend| trend.hashtag.as_str()).collect();
+        assert_eq!(hashtags, ["new", "old"]);
+        assert_eq!(day[1].count, 30);
+        assert!(day[0].score > day[1].score);
+        assert!(!day[1].spiking);
+        assert_eq!(day[1].velocity, 0.0);
+
+        assert_eq!(tracker.top(TrendWindow::Day, now, 1).len(), 1);
+        assert!(tracker
+            .top(TrendWindow::Day, now + 24 * HOUR, 10)
+            .is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+use futures::StreamExt;
+use log::info;
+use scylla::frame::response::result::CqlValue;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::models::UserSummary;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    username: String,
+    display_name: Option<String>,
+    follower_count: i64,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: HashMap<Uuid, Entry>,
+    /// Lowercased username, display name and display name words, each
+    /// mapped to the users they belong to. Sorted, so every key starting
+    /// with a prefix is one contiguous range.
+    prefixes: BTreeMap<String, BTreeSet<Uuid>>,
+}
+
+impl Inner {
+    fn keys(entry: &Entry) -> Vec<String> {
+        let mut keys = vec![entry.username.to_lowercase()];
+        if let Some(display_name) = &entry.display_name {
+            let display_name = display_name.to_lowercase();
+            keys.extend(display_name.split_whitespace().map(str::to_string));
+            keys.push(display_name);
+        }
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    fn insert(&mut self, user_id: Uuid, entry: Entry) {
+        self.remove(user_id);
+        for key in Self::keys(&entry) {
+            self.prefixes.entry(key).or_default().insert(user_id);
+        }
+        self.users.insert(user_id, entry);
+    }
+
+    fn remove(&mut self, user_id: Uuid) -> Option<Entry> {
+        let entry = self.users.remove(&user_id)?;
+        for key in Self::keys(&entry) {
+            if let Some(user_ids) = self.prefixes.get_mut(&key) {
+                user_ids.remove(&user_id);
+                if user_ids.is_empty() {
+                    self.prefixes.remove(&key);
+                }
+            }
+        }
+        Some(entry)
+    }
+}
+
+/// In-memory prefix index over usernames and display names, used for user
+/// search and mention autocompletion. Loaded from the database at startup
+/// and kept current by the handlers that change users or follows.
+#[derive(Clone, Default)]
+pub struct UserIndex(Arc<RwLock<Inner>>);
+
+impl UserIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user, or replaces their names if already present.
+    pub fn upsert(&self, user_id: Uuid, username: &str, display_name: Option<&str>) {
+        let mut inner = self.0.write().unwrap();
+        let follower_count = inner
+            .users
+            .get(&user_id)
+            .map_or(0, |entry| entry.follower_count);
+        inner.insert(
+            user_id,
+            Entry {
+                username: username.to_string(),
+                display_name: display_name.map(str::to_string),
+                follower_count,
+            },
+        );
+    }
+
+    pub fn adjust_follower_count(&self, user_id: Uuid, delta: i64) {
+        if let Some(entry) = self.0.write().unwrap().users.get_mut(&user_id) {
+            entry.follower_count = (entry.follower_count + delta).max(0);
+        }
+    }
+
+    pub fn get(&self, user_id: Uuid) -> Option<UserSummary> {
+        let inner = self.0.read().unwrap();
+        let entry = inner.users.get(&user_id)?;
+        Some(UserSummary {
+            user_id,
+            username: entry.username.clone(),
+            display_name: entry.display_name.clone(),
+            follower_count: entry.follower_count,
+        })
+    }
+
+    /// Users with a username, display name or display name word starting
+    /// with `prefix` (case-insensitive), most followed first.
+    pub fn search(&self, prefix: &str, offset: usize, limit: usize) -> Vec<UserSummary> {
+        let prefix = prefix.trim().to_lowercase();
+        if prefix.is_empty() || limit == 0 {
+            return Vec::new();
+        }
+
+        let inner = self.0.read().unwrap();
+        let mut seen = BTreeSet::new();
+        // Min-heap of the best `offset + limit` matches seen so far, so a
+        // short prefix matching many users doesn't sort all of them.
+        let mut best = BinaryHeap::new();
+        let keep = offset + limit;
+        for (_, user_ids) in inner
+            .prefixes
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            for &user_id in user_ids {
+                if !seen.insert(user_id) {
+                    continue;
+                }
+                let entry = &inner.users[&user_id];
+                best.push(Reverse((
+                    entry.follower_count,
+                    Reverse(entry.username.as_str()),
+                    user_id,
+                )));
+                if best.len() > keep {
+                    best.pop();
+                }
+            }
+        }
+
+        best.into_sorted_vec()
+            .into_iter()
+            .skip(offset)
+            .map(
+                |Reverse((follower_count, Reverse(username), user_id))| UserSummary {
+                    user_id,
+                    username: username.to_string(),
+                    display_name: inner.users[&user_id].display_name.clone(),
+                    follower_count,
+                },
+            )
+            .collect()
+    }
+
+    /// Fills the index from the `users` and `user_stats` tables.
+    pub async fn load(&self, session: &Session) -> Result<usize, Box<dyn Error>> {
+        let mut follower_counts = HashMap::new();
+        let mut rows = session
+            .query_iter(
+                "SELECT user_id, follower_count FROM twitter_clone.user_stats",
+                (),
+            )
+            .await?;
+        while let Some(row) = rows.next().await {
+            let mut columns = row?.columns.into_iter();
+            if let (Some(CqlValue::Uuid(user_id)), Some(CqlValue::Counter(count))) =
+                (columns.next().flatten(), columns.next().flatten())
+            {
+                follower_counts.insert(user_id, count.0);
+            }
+        }
+
+        let mut inner = Inner::default();
+        let mut rows = session
+            .query_iter(
+                "SELECT user_id, username, display_name FROM twitter_clone.users",
+                (),
+            )
+            .await?;
+        while let Some(row) = rows.next().await {
+            let mut columns = row?.columns.into_iter();
+            if let (Some(CqlValue::Uuid(user_id)), Some(CqlValue::Text(username))) =
+                (columns.next().flatten(), columns.next().flatten())
+            {
+                let display_name = match columns.next().flatten() {
+                    Some(CqlValue::Text(display_name)) => Some(display_name),
+                    _ => None,
+                };
+                let follower_count = follower_counts.get(&user_id).copied().unwrap_or(0);
+                inner.insert(
+                    user_id,
+                    Entry {
+                        username,
+                        display_name,
+                        follower_count,
+                    },
+                );
+            }
+        }
+
+        let loaded = inner.users.len();
+        *self.0.write().unwrap() = inner;
+        info!("Loaded {} users into the user index", loaded);
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(users: &[(u128, &str, Option<&str>, i64)]) -> UserIndex {
+        let index = UserIndex::new();
+        for &(id, username, display_name, followers) in users {
+            index.upsert(Uuid::from_u128(id), username, display_name);
+            index.adjust_follower_count(Uuid::from_u128(id), followers);
+        }
+        index
+    }
+
+    fn usernames(results: Vec<UserSummary>) -> Vec<String> {
+        results.into_iter().map(|user| user.username).collect()
+    }
+
+    #[test]
+    fn most_followed_first_then_by_username() {
+        let index = index(&[
+            (1, "alice", None, 10),
+            (2, "albert", None, 50),
+            (3, "alfred", None, 10),
+            (4, "bob", Some("Al Bundy"), 5),
+            (5, "carol", None, 100),
+        ]);
+        assert_eq!(
+            usernames(index.search("AL", 0, 10)),
+            ["albert", "alfred", "alice", "bob"]
+        );
+        assert!(index.search("  ", 0, 10).is_empty());
+        assert!(index.search("al", 0, 0).is_empty());
+        assert!(index.search("zed", 0, 10).is_empty());
+    }
+
+    #[test]
+    fn users_matching_several_keys_are_listed_once() {
+        // Matches by username, display name and each display name word.
+        let index = index(&[(1, "annie", Some("Ann Annabel"), 0), (2, "anton", None, 0)]);
+        let results = index.search("an", 0, 10);
+        assert_eq!(usernames(results), ["annie", "anton"]);
+        assert_eq!(usernames(index.search("annabel", 0, 10)), ["annie"]);
+        assert_eq!(usernames(index.search("ann annab", 0, 10)), ["annie"]);
+    }
+
+    #[test]
+    fn offset_pages_through_the_ranking() {
+        let users: Vec<(u128, String, i64)> = (0..10)
+            .map(|n| (n, format!("user{}", n), n as i64))
+            .collect();
+        let index = UserIndex::new();
+        for (id, username, followers) in &users {
+            index.upsert(Uuid::from_u128(*id), username, None);
+            index.adjust_follower_count(Uuid::from_u128(*id), *followers);
+        }
+        assert_eq!(
+            usernames(index.search("user", 0, 3)),
+            ["user9", "user8", "user7"]
+        );
+        assert_eq!(
+            usernames(index.search("user", 3, 3)),
+            ["user6", "user5", "user4"]
+        );
+        assert_eq!(usernames(index.search("user", 9, 3)), ["user0"]);
+        assert!(index.search("user", 10, 3).is_empty());
+    }
+
+    #[test]
+    fn renames_replace_the_old_names() {
+        let index = index(&[(1, "oldname", Some("Old Display"), 7)]);
+        index.upsert(Uuid::from_u128(1), "newname", Some("Fresh"));
+        assert!(index.search("old", 0, 10).is_empty());
+        let results = index.search("fre", 0, 10);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].username, "newname");
+        assert_eq!(results[0].display_name.as_deref(), Some("Fresh"));
+        // Renaming keeps the follower count.
+        assert_eq!(results[0].follower_count, 7);
+        assert_eq!(index.0.read().unwrap().prefixes.len(), 2);
+
+        index.adjust_follower_count(Uuid::from_u128(1), -10);
+        assert_eq!(index.get(Uuid::from_u128(1)).unwrap().follower_count, 0);
+    }
+}
@@ -0,0 +1,518 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::entities::MAX_USERNAME_CHARS;
+use crate::error::{ApiError, FieldError};
+use crate::models::CreateUserRequest;
+
+/// Weighted length limit of a tweet, as counted by `tweet_weight`.
+pub const MAX_TWEET_WEIGHT: usize = 280;
+/// Every URL counts as this long however long it is, as if shortened.
+const URL_WEIGHT: usize = 23;
+pub const MAX_DISPLAY_NAME_CHARS: usize = 50;
+const MIN_USERNAME_CHARS: usize = 4;
+/// RFC 5321 limits for a whole address and for its local part.
+const MAX_EMAIL_BYTES: usize = 254;
+const MAX_EMAIL_LOCAL_BYTES: usize = 64;
+const MAX_DOMAIN_LABEL_BYTES: usize = 63;
+const MIN_PASSWORD_CHARS: usize = 8;
+/// bcrypt ignores everything after its first 72 bytes.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+/// Usernames that would be mistaken for the service itself or clash with
+/// routes. Compared case-insensitively.
+const RESERVED_USERNAMES: [&str; 24] = [
+    "about",
+    "admin",
+    "administrator",
+    "api",
+    "explore",
+    "help",
+    "home",
+    "login",
+    "logout",
+    "me",
+    "messages",
+    "moderator",
+    "notifications",
+    "null",
+    "official",
+    "root",
+    "search",
+    "security",
+    "settings",
+    "signup",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+];
+
+/// Passwords that are tried first in any guessing attack.
+const COMMON_PASSWORDS: [&str; 20] = [
+    "password",
+    "password1",
+    "password123",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwerty123",
+    "qwertyuiop",
+    "iloveyou",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "welcome1",
+    "letmein1",
+    "trustno1",
+    "abc12345",
+    "11111111",
+    "00000000",
+    "passw0rd",
+];
+
+/// Why a single value was rejected.
+#[derive(Debug)]
+pub struct Invalid {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Invalid {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Invalid {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn on(self, field: &'static str) -> FieldError {
+        FieldError {
+            field,
+            code: self.code,
+            message: self.message,
+        }
+    }
+}
+
+/// A create user request that passed validation, normalized for storage.
+#[derive(Debug)]
+pub struct NewUser {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub email: String,
+    pub password: String,
+}
+
+/// Checks every field of a create user request, reporting all the invalid
+/// ones at once.
+pub fn new_user(request: &CreateUserRequest) -> Result<NewUser, ApiError> {
+    let mut errors = Vec::new();
+    let username = check(&mut errors, "username", username(&request.username));
+    let display_name = check(
+        &mut errors,
+        "display_name",
+        display_name(request.display_name.as_deref()),
+    );
+    let email = check(&mut errors, "email", email(&request.email));
+    let password = check(
+        &mut errors,
+        "password",
+        password(&request.password, &request.username, &request.email),
+    );
+
+    match (username, display_name, email, password) {
+        (Some(username), Some(display_name), Some(email), Some(password)) => Ok(NewUser {
+            username,
+            display_name,
+            email,
+            password,
+        }),
+        _ => Err(ApiError::InvalidFields(errors)),
+    }
+}
+
+fn check<T>(
+    errors: &mut Vec<FieldError>,
+    field: &'static str,
+    result: Result<T, Invalid>,
+) -> Option<T> {
+    result
+        .map_err(|invalid| errors.push(invalid.on(field)))
+        .ok()
+}
+
+/// 4 to 15 ASCII letters, digits or underscores, the characters a mention
+/// can refer to, and not a reserved name.
+pub fn username(username: &str) -> Result<String, Invalid> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_CHARS..=MAX_USERNAME_CHARS).contains(&length) {
+        return Err(Invalid::new(
+            "length",
+            format!(
+                "must be {} to {} characters",
+                MIN_USERNAME_CHARS, MAX_USERNAME_CHARS
+            ),
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Invalid::new(
+            "charset",
+            "may only contain letters, digits and underscores",
+        ));
+    }
+    if RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(username))
+    {
+        return Err(Invalid::new("reserved", "is reserved"));
+    }
+    Ok(username.to_string())
+}
+
+/// Trims the name and normalizes it to NFC; a blank name is no name.
+pub fn display_name(display_name: Option<&str>) -> Result<Option<String>, Invalid> {
+    let name = display_name.map(nfc).unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(None);
+    }
+    if name.graphemes(true).count() > MAX_DISPLAY_NAME_CHARS {
+        return Err(Invalid::new(
+            "length",
+            format!("must be at most {} characters", MAX_DISPLAY_NAME_CHARS),
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(Invalid::new(
+            "charset",
+            "may not contain control characters",
+        ));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// An address in the RFC 5322 dot-atom form, `local@domain`, within the
+/// RFC 5321 length limits and with a domain of DNS labels. Quoted local
+/// parts, address literals and non-ASCII addresses aren't accepted. The
+/// domain is lowercased.
+pub fn email(email: &str) -> Result<String, Invalid> {
+    let email = email.trim();
+    let invalid = || Invalid::new("format", "is not a valid email address");
+    if email.len() > MAX_EMAIL_BYTES {
+        return Err(Invalid::new(
+            "length",
+            format!("must be at most {} characters", MAX_EMAIL_BYTES),
+        ));
+    }
+    let (local, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+
+    let is_atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    if local.is_empty()
+        || local.len() > MAX_EMAIL_LOCAL_BYTES
+        || local
+            .split('.')
+            .any(|atom| atom.is_empty() || !atom.chars().all(is_atext))
+    {
+        return Err(invalid());
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && label.len() <= MAX_DOMAIN_LABEL_BYTES
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    // A top-level domain is never numeric, which also rules out bare IPs.
+    let tld_ok = labels
+        .last()
+        .is_some_and(|tld| !tld.chars().all(|c| c.is_ascii_digit()));
+    if labels.len() < 2 || !labels.iter().all(valid_label) || !tld_ok {
+        return Err(invalid());
+    }
+
+    Ok(format!("{}@{}", local, domain.to_ascii_lowercase()))
+}
+
+/// At least 8 characters and at most 72 bytes after NFC normalization, and
+/// not something guessed first: a common password, a single repeated
+/// character, or the username or email address.
+pub fn password(password: &str, username: &str, email: &str) -> Result<String, Invalid> {
+    let password = nfc(password);
+    if password.chars().count() < MIN_PASSWORD_CHARS {
+        return Err(Invalid::new(
+            "length",
+            format!("must be at least {} characters", MIN_PASSWORD_CHARS),
+        ));
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        return Err(Invalid::new(
+            "length",
+            format!("must be at most {} bytes", MAX_PASSWORD_BYTES),
+        ));
+    }
+
+    let lowercase = password.to_lowercase();
+    let mut chars = password.chars();
+    let first = chars.next();
+    let guessable = COMMON_PASSWORDS.contains(&lowercase.as_str())
+        || chars.all(|c| Some(c) == first)
+        || (username.len() >= MIN_USERNAME_CHARS && lowercase.contains(&username.to_lowercase()))
+        || lowercase == email.trim().to_lowercase();
+    if guessable {
+        return Err(Invalid::new("weak", "is too easy to guess"));
+    }
+    Ok(password)
+}
+
+/// Normalizes tweet text to NFC with `\n` line breaks and checks its
+/// weighted length. Text that is only whitespace counts as empty, which is
+/// only allowed when `allow_empty` is set, e.g. for a tweet with media.
+pub fn tweet_content(content: &str, allow_empty: bool) -> Result<String, Invalid> {
+    // Windows clients and form encoders send `\r\n`.
+    let content = nfc(&content.replace("\r\n", "\n").replace('\r', "\n"));
+    if content.trim().is_empty() {
+        if allow_empty {
+            return Ok(String::new());
+        }
+        return Err(Invalid::new("required", "must not be empty"));
+    }
+    if content
+        .chars()
+        .any(|c| c.is_control() && c != '\n' && c != '\t')
+    {
+        return Err(Invalid::new(
+            "charset",
+            "may not contain control characters",
+        ));
+    }
+    let weight = tweet_weight(&content);
+    if weight > MAX_TWEET_WEIGHT {
+        return Err(Invalid::new(
+            "length",
+            format!(
+                "is {} characters long, more than the {} allowed",
+                weight, MAX_TWEET_WEIGHT
+            ),
+        ));
+    }
+    Ok(content)
+}
+
+/// The length of tweet text as Twitter counts it: each grapheme cluster
+/// weighs 1 if it starts with a Latin, Greek, Cyrillic or other
+/// narrow-script character or common punctuation, and 2 otherwise (CJK,
+/// emoji), and each URL weighs `URL_WEIGHT`.
+pub fn tweet_weight(content: &str) -> usize {
+    let mut weight = 0;
+    let mut rest = content;
+    while let Some((start, end)) = find_url(rest) {
+        weight += graphemes_weight(&rest[..start]) + URL_WEIGHT;
+        rest = &rest[end..];
+    }
+    weight + graphemes_weight(rest)
+}
+
+fn graphemes_weight(text: &str) -> usize {
+    text.graphemes(true)
+        .map(|grapheme| match grapheme.chars().next().map(u32::from) {
+            Some(0..=0x10FF | 0x2000..=0x200D | 0x2010..=0x201F | 0x2032..=0x2037) => 1,
+            _ => 2,
+        })
+        .sum()
+}
+
+/// Byte range of the first `http://` or `https://` URL in `text`: the
+/// scheme at the start of a word, then a host, up to the next whitespace,
+/// less any trailing punctuation.
+fn find_url(text: &str) -> Option<(usize, usize)> {
+    let lowercase = text.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(offset) = lowercase[from..].find("http") {
+        let start = from + offset;
+        from = start + 4;
+        let at_word_start = text[..start]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !c.is_alphanumeric());
+        let scheme_len = if lowercase[start..].starts_with("https://") {
+            8
+        } else if lowercase[start..].starts_with("http://") {
+            7
+        } else {
+            continue;
+        };
+        let tail = &text[start + scheme_len..];
+        let host_len = tail
+            .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '.'))
+            .unwrap_or(tail.len());
+        if !at_word_start || !tail[..host_len].contains('.') {
+            continue;
+        }
+        let word_len = tail.find(char::is_whitespace).unwrap_or(tail.len());
+        let url = tail[..word_len].trim_end_matches(|c: char| ".,;:!?'\")]".contains(c));
+        return Some((start, start + scheme_len + url.len()));
+    }
+    None
+}
+
+fn nfc(text: &str) -> String {
+    text.nfc().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code<T>(result: Result<T, Invalid>) -> &'static str {
+        match result {
+            Ok(_) => "ok",
+            Err(invalid) => invalid.code,
+        }
+    }
+
+    #[test]
+    fn usernames_are_4_to_15_word_characters() {
+        assert_eq!(code(username("abc")), "length");
+        assert_eq!(code(username("abcd")), "ok");
+        assert_eq!(code(username(&"a".repeat(15))), "ok");
+        assert_eq!(code(username(&"a".repeat(16))), "length");
+        assert_eq!(code(username("Alice_99")), "ok");
+        assert_eq!(code(username("alice-99")), "charset");
+        assert_eq!(code(username("alicé")), "charset");
+    }
+
+    #[test]
+    fn reserved_usernames_are_refused_in_any_case() {
+        assert_eq!(code(username("admin")), "reserved");
+        assert_eq!(code(username("Support")), "reserved");
+        assert_eq!(code(username("admins")), "ok");
+    }
+
+    #[test]
+    fn display_names_are_trimmed_and_nfc() {
+        assert_eq!(
+            display_name(Some("  Jose\u{301}  ")).unwrap(),
+            Some("Jos\u{e9}".to_string())
+        );
+        assert_eq!(display_name(Some("   ")).unwrap(), None);
+        assert_eq!(display_name(None).unwrap(), None);
+        // Length counts what the reader sees, not code points.
+        assert_eq!(code(display_name(Some(&"e\u{301}".repeat(50)))), "ok");
+        assert_eq!(code(display_name(Some(&"a".repeat(51)))), "length");
+        assert_eq!(code(display_name(Some("a\u{7}b"))), "charset");
+    }
+
+    #[test]
+    fn emails_are_dot_atoms_with_a_dns_domain() {
+        assert_eq!(
+            email(" first.last+tag@Example.COM ").unwrap(),
+            "first.last+tag@example.com"
+        );
+        for invalid in [
+            "plain",
+            "@example.com",
+            "a@b",
+            "a..b@example.com",
+            ".a@example.com",
+            "a@-example.com",
+            "a@example..com",
+            "a@127.0.0.1",
+            "\"quoted\"@example.com",
+        ] {
+            assert_eq!(code(email(invalid)), "format", "{}", invalid);
+        }
+        let local = "a".repeat(64);
+        assert_eq!(code(email(&format!("{}@example.com", local))), "ok");
+        assert_eq!(code(email(&format!("a{}@example.com", local))), "format");
+        let domain = format!("{}.com", vec!["b".repeat(63); 4].join("."));
+        assert_eq!(code(email(&format!("a@{}", domain))), "length");
+    }
+
+    #[test]
+    fn passwords_are_8_chars_to_72_bytes() {
+        assert_eq!(
+            code(password("Tr0ub4d", "alice", "a@example.com")),
+            "length"
+        );
+        assert_eq!(code(password("Tr0ub4d!", "alice", "a@example.com")), "ok");
+        let longest = format!("Tr0ub4d!{}", "x".repeat(64));
+        assert_eq!(code(password(&longest, "alice", "a@example.com")), "ok");
+        let too_long = format!("{}y", longest);
+        assert_eq!(
+            code(password(&too_long, "alice", "a@example.com")),
+            "length"
+        );
+        // 24 three-byte characters are 72 bytes; one more is too many.
+        assert_eq!(
+            code(password(&"水火".repeat(12), "alice", "a@example.com")),
+            "ok"
+        );
+        assert_eq!(
+            code(password(
+                &("水火".repeat(12) + "土"),
+                "alice",
+                "a@example.com"
+            )),
+            "length"
+        );
+    }
+
+    #[test]
+    fn guessable_passwords_are_refused() {
+        for guessable in ["Password1", "aaaaaaaaaa", "xxalice99xx", "a@example.com"] {
+            assert_eq!(
+                code(password(guessable, "Alice", "A@example.com")),
+                "weak",
+                "{}",
+                guessable
+            );
+        }
+    }
+
+    #[test]
+    fn tweets_weigh_up_to_280() {
+        assert_eq!(code(tweet_content(&"a".repeat(280), false)), "ok");
+        assert_eq!(code(tweet_content(&"a".repeat(281), false)), "length");
+        assert_eq!(tweet_weight(&"日".repeat(140)), 280);
+        assert_eq!(code(tweet_content(&"日".repeat(141), false)), "length");
+        assert_eq!(tweet_weight("👍🏽"), 2);
+        assert_eq!(code(tweet_content("  ", false)), "required");
+        assert_eq!(tweet_content("  ", true).unwrap(), "");
+    }
+
+    #[test]
+    fn line_breaks_are_normalized_to_newlines() {
+        assert_eq!(
+            tweet_content("one\r\ntwo\rthree\nfour", false).unwrap(),
+            "one\ntwo\nthree\nfour"
+        );
+        // A CRLF weighs as one character, like the newline it becomes.
+        let content = format!("{}\r\n{}", "a".repeat(139), "b".repeat(140));
+        assert_eq!(tweet_content(&content, false).unwrap().chars().count(), 280);
+        assert_eq!(code(tweet_content("\r\n\r\n", false)), "required");
+        assert_eq!(code(tweet_content("bell\u{7}", false)), "charset");
+    }
+
+    #[test]
+    fn urls_weigh_23() {
+        let url = format!("https://example.com/{}", "x".repeat(100));
+        assert_eq!(tweet_weight(&url), 23);
+        assert_eq!(
+            tweet_weight("see http://a.io, and HTTPS://b.io."),
+            4 + 23 + 6 + 23 + 1
+        );
+        assert_eq!(tweet_weight("https://nodot"), 13);
+        assert_eq!(tweet_weight("xhttps://example.com"), 20);
+        let content = format!("{} {}", "a".repeat(256), url);
+        assert_eq!(code(tweet_content(&content, false)), "ok");
+        let content = format!("{} {}", "a".repeat(257), url);
+        assert_eq!(code(tweet_content(&content, false)), "length");
+    }
+}